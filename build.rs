@@ -0,0 +1,17 @@
+//! Compiles the GSettings schema, so the editor preferences work when running
+//! from the build directory without installing the schema.
+
+use std::{env, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-changed=schemas");
+    let out_dir = env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    let status = Command::new("glib-compile-schemas")
+        .arg("--strict")
+        .arg("--targetdir")
+        .arg(&out_dir)
+        .arg("schemas")
+        .status()
+        .expect("failed to run glib-compile-schemas, is glib installed?");
+    assert!(status.success(), "glib-compile-schemas failed");
+}
@@ -1,7 +1,34 @@
-use gtk::{glib, prelude::*};
-use samod::DocumentId;
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use adw::prelude::*;
+use automerge::ChangeHash;
+use futures::channel::oneshot;
+use gtk::{gio, glib, prelude::*};
+use samod::{DocHandle, DocumentId};
 use sourceview5::prelude::*;
 
+use crate::automerge_file;
+use crate::error::DocumentError;
+use crate::export::{self, ExportFormat};
+use crate::git;
+use crate::history::HistoryWindow;
+use crate::import::{self, Import};
+use crate::inspector;
+use crate::linked_file::LinkedFile;
+use crate::marks;
+use crate::notifications::{Notification, Notifier};
+use crate::preferences;
+use crate::preview::MarkdownPreview;
+use crate::schema::Metadata;
+use crate::search::FindBar;
+use crate::share::{self, Invite};
+use crate::sync::TextSynchronizer;
+use crate::text_path::TextPath;
+
 pub struct LoadingPageWidgets {
     pub container: gtk::Box,
     pub label: gtk::Label,
@@ -9,33 +36,75 @@ pub struct LoadingPageWidgets {
     pub progress_bar: gtk::ProgressBar,
 }
 
+/// What to do after loading the document failed.
+#[derive(Debug, Clone, Copy)]
+pub enum Recovery {
+    Retry,
+    /// Try again without the relay and the node from the connection string.
+    OpenOffline,
+}
+
+pub struct ErrorPageWidgets {
+    pub page: adw::StatusPage,
+    pub details: gtk::Label,
+    pub open_offline_button: gtk::Button,
+    /// Hands the user's choice to the `show_error` waiting for it.
+    pub recovery: Rc<RefCell<Option<oneshot::Sender<Recovery>>>>,
+    pub diagnostics: Rc<RefCell<String>>,
+}
+
 pub struct AppState {
     pub rt: tokio::runtime::Runtime,
     pub document_id: Option<DocumentId>,
-    pub node_id: Option<iroh::NodeId>,
+    /// The peer to sync with, from the connection string.
+    pub invite: Option<Invite>,
     pub iroh_secret: Option<String>,
-    pub window: gtk::ApplicationWindow,
+    pub text_path: TextPath,
+    /// A file to create the new document from, instead of an empty one.
+    pub import: Option<Import>,
+    /// A markdown file to keep in sync with the text once loaded.
+    pub linked_file: Option<PathBuf>,
+    /// Set when the user chose to open the document without the network.
+    pub offline: bool,
+    pub window: adw::ApplicationWindow,
+    /// Holds the header bar and other bars above the loading and editor pages.
+    pub toolbar_view: adw::ToolbarView,
+    pub toast_overlay: adw::ToastOverlay,
+    pub notifier: Notifier,
     pub main_stack: gtk::Stack,
     #[allow(unused)]
     pub loading_page: gtk::Box,
     pub editor_page: gtk::Box,
-    pub header_bar: gtk::HeaderBar,
+    pub header_bar: adw::HeaderBar,
+    pub banner: adw::Banner,
+    pub title_label: gtk::Label,
+    /// The primary menu section for getting the text in and out of files.
+    pub file_menu: gio::Menu,
     pub doc_id_label: gtk::Label,
-    pub copy_button: gtk::Button,
+    pub share_button: gtk::Button,
+    /// Shows how many iroh peers are connected, opening the peer list.
+    pub peer_button: gtk::Button,
+    pub peer_count: adw::ButtonContent,
     pub loading_label: gtk::Label,
     pub loading_spinner: gtk::Spinner,
     pub progress_bar: gtk::ProgressBar,
+    pub error_page: ErrorPageWidgets,
     pub side_pane: Option<gtk::Box>,
+    pub side_stack: Option<gtk::Stack>,
+    pub editor_view: Option<sourceview5::View>,
 }
 
 impl AppState {
     pub fn new(
         application: &adw::Application,
         doc_id: Option<DocumentId>,
-        node_id: Option<iroh::NodeId>,
+        invite: Option<Invite>,
         iroh_secret: Option<String>,
+        text_path: TextPath,
+        import: Option<Import>,
+        linked_file: Option<PathBuf>,
     ) -> Self {
-        let window = gtk::ApplicationWindow::new(application);
+        let window = adw::ApplicationWindow::new(application);
         window.set_title(Some("Glyphcaster"));
         window.set_default_size(800, 600);
 
@@ -52,44 +121,82 @@ impl AppState {
             progress_bar,
         } = Self::create_loading_page();
 
-        // Create editor page with vertical orientation to include header
         let editor_page = gtk::Box::new(gtk::Orientation::Vertical, 0);
 
-        // Create header bar for document info
-        let header_bar = gtk::HeaderBar::new();
-        header_bar.set_show_title_buttons(false);
-
-        // Create a box to hold the document ID label and copy button
-        let doc_id_box = gtk::Box::new(gtk::Orientation::Horizontal, 6);
-        doc_id_box.set_halign(gtk::Align::Center);
+        // Header bar for document info, shown above the loading page too
+        let header_bar = adw::HeaderBar::new();
 
         // Create label for document ID
         let doc_id_label = gtk::Label::new(Some("Document ID: Loading..."));
         doc_id_label.set_css_classes(&["subtitle"]);
-        doc_id_label.set_selectable(true);
         doc_id_label.set_ellipsize(gtk::pango::EllipsizeMode::Middle);
 
-        // Create copy button
-        let copy_button = gtk::Button::from_icon_name("edit-copy-symbolic");
-        copy_button.set_tooltip_text(Some("Copy Document ID"));
-        copy_button.set_has_frame(false);
-        copy_button.set_sensitive(false); // Disabled until ID is loaded
+        // Document title above the connection info
+        let title_label = gtk::Label::new(Some("Untitled"));
+        title_label.add_css_class("title");
+        title_label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+
+        let title_box = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        title_box.set_valign(gtk::Align::Center);
+        title_box.append(&title_label);
+        title_box.append(&doc_id_label);
+
+        header_bar.set_title_widget(Some(&title_box));
 
-        doc_id_box.append(&doc_id_label);
-        doc_id_box.append(&copy_button);
+        // Features add their file entries as sections, above the application wide ones
+        let file_menu = gio::Menu::new();
+        let primary_menu = gio::Menu::new();
+        primary_menu.append_section(None, &file_menu);
+        let app_section = gio::Menu::new();
+        app_section.append(Some("Preferences"), Some("app.preferences"));
+        primary_menu.append_section(None, &app_section);
 
-        header_bar.set_title_widget(Some(&doc_id_box));
+        let primary_menu_button = gtk::MenuButton::new();
+        primary_menu_button.set_icon_name("open-menu-symbolic");
+        primary_menu_button.set_tooltip_text(Some("Main Menu"));
+        primary_menu_button.set_menu_model(Some(&primary_menu));
+        primary_menu_button.set_primary(true);
+        header_bar.pack_end(&primary_menu_button);
 
-        editor_page.append(&header_bar);
+        let share_button = gtk::Button::from_icon_name("emblem-shared-symbolic");
+        share_button.set_tooltip_text(Some("Share Document"));
+        share_button.set_sensitive(false); // Disabled until ID is loaded
+        header_bar.pack_end(&share_button);
+
+        let peer_count = adw::ButtonContent::builder()
+            .icon_name("network-workgroup-symbolic")
+            .label("0")
+            .build();
+        let peer_button = gtk::Button::builder()
+            .child(&peer_count)
+            .tooltip_text("No Active Iroh Peers")
+            .build();
+        peer_button.add_css_class("flat");
+        header_bar.pack_end(&peer_button);
+
+        // Banner for problems with the document itself, hidden until needed
+        let banner = adw::Banner::new("Document has unexpected structure");
+        banner.set_button_label(Some("Inspect"));
+
+        let toast_overlay = adw::ToastOverlay::new();
+        let notifier = Notifier::new(&toast_overlay);
+        let error_page = Self::create_error_page(&notifier);
 
         // Add pages to stack
         main_stack.add_named(&loading_page, Some("loading"));
+        main_stack.add_named(&error_page.page, Some("error"));
         main_stack.add_named(&editor_page, Some("editor"));
 
         // Show loading page initially
         main_stack.set_visible_child_name("loading");
 
-        window.set_child(Some(&main_stack));
+        toast_overlay.set_child(Some(&main_stack));
+
+        let toolbar_view = adw::ToolbarView::new();
+        toolbar_view.add_top_bar(&header_bar);
+        toolbar_view.add_top_bar(&banner);
+        toolbar_view.set_content(Some(&toast_overlay));
+        window.set_content(Some(&toolbar_view));
 
         let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
@@ -99,19 +206,34 @@ impl AppState {
         Self {
             rt,
             document_id: doc_id,
-            node_id,
+            invite,
             iroh_secret,
+            text_path,
+            import,
+            linked_file,
+            offline: false,
             window,
+            toolbar_view,
+            toast_overlay,
+            notifier,
             main_stack,
             loading_page,
             editor_page,
             header_bar,
+            banner,
+            title_label,
+            file_menu,
             doc_id_label,
-            copy_button,
+            share_button,
+            peer_button,
+            peer_count,
             loading_label,
             loading_spinner,
             progress_bar,
+            error_page,
             side_pane: None,
+            side_stack: None,
+            editor_view: None,
         }
     }
 
@@ -147,6 +269,73 @@ impl AppState {
         }
     }
 
+    fn create_error_page(notifier: &Notifier) -> ErrorPageWidgets {
+        let recovery: Rc<RefCell<Option<oneshot::Sender<Recovery>>>> = Rc::new(RefCell::new(None));
+        let diagnostics = Rc::new(RefCell::new(String::new()));
+
+        let retry_button = gtk::Button::with_label("Retry");
+        retry_button.add_css_class("pill");
+        retry_button.add_css_class("suggested-action");
+        let open_offline_button = gtk::Button::with_label("Open Offline");
+        open_offline_button.add_css_class("pill");
+        for (button, choice) in [
+            (&retry_button, Recovery::Retry),
+            (&open_offline_button, Recovery::OpenOffline),
+        ] {
+            let recovery = recovery.clone();
+            button.connect_clicked(move |_| {
+                if let Some(sender) = recovery.borrow_mut().take() {
+                    let _ = sender.send(choice);
+                }
+            });
+        }
+
+        let copy_button = gtk::Button::with_label("Copy Diagnostics");
+        copy_button.add_css_class("pill");
+        copy_button.connect_clicked({
+            let diagnostics = diagnostics.clone();
+            let notifier = notifier.clone();
+            move |button| {
+                button.clipboard().set_text(&diagnostics.borrow());
+                notifier.notify(Notification::DiagnosticsCopied);
+            }
+        });
+
+        let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+        buttons.set_halign(gtk::Align::Center);
+        buttons.append(&retry_button);
+        buttons.append(&open_offline_button);
+        buttons.append(&copy_button);
+
+        // The whole error chain, with the context of every step that failed
+        let details = gtk::Label::new(None);
+        details.set_selectable(true);
+        details.set_wrap(true);
+        details.set_wrap_mode(gtk::pango::WrapMode::WordChar);
+        details.set_xalign(0.0);
+        details.add_css_class("monospace");
+        let expander = gtk::Expander::new(Some("Details"));
+        expander.set_child(Some(&details));
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 24);
+        content.append(&buttons);
+        content.append(&expander);
+
+        let page = adw::StatusPage::builder()
+            .icon_name("dialog-error-symbolic")
+            .title("Couldn't Open Document")
+            .child(&content)
+            .build();
+
+        ErrorPageWidgets {
+            page,
+            details,
+            open_offline_button,
+            recovery,
+            diagnostics,
+        }
+    }
+
     pub fn update_loading_status(&self, message: &str, progress: Option<f64>) {
         self.loading_label
             .set_markup(&format!("<span size='large'>{}</span>", message));
@@ -183,17 +372,20 @@ impl AppState {
             .build();
 
         let view = sourceview5::View::with_buffer(buffer);
-        view.set_monospace(true);
-        view.set_background_pattern(sourceview5::BackgroundPatternType::Grid);
-        view.set_show_line_numbers(true);
-        view.set_highlight_current_line(true);
-        view.set_tab_width(4);
+        preferences::apply(&view);
         view.set_hexpand(true);
         view.set_auto_indent(true);
-        view.set_insert_spaces_instead_of_tabs(true);
         view.set_smart_backspace(true);
         view.set_smart_home_end(sourceview5::SmartHomeEndType::Before);
 
+        // Read-only links are a courtesy, all they can do is keep this editor from editing
+        if self.read_only() {
+            view.set_editable(false);
+            let read_only_label = gtk::Label::new(Some("Read-Only"));
+            read_only_label.add_css_class("dim-label");
+            self.header_bar.pack_start(&read_only_label);
+        }
+
         scroll.set_child(Some(&view));
         editor_container.append(&scroll);
 
@@ -201,18 +393,63 @@ impl AppState {
         map.set_view(&view);
         editor_container.append(&map);
 
-        // Add editor container to main container
-        main_container.append(&editor_container);
+        // The rendered preview shares the space with the source when shown
+        let preview = MarkdownPreview::new(&view);
+        preview.container.set_visible(false);
+        let editor_paned = gtk::Paned::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .start_child(&editor_container)
+            .end_child(&preview.container)
+            .shrink_start_child(false)
+            .shrink_end_child(false)
+            .hexpand(true)
+            .vexpand(true)
+            .build();
+
+        let show_preview = gio::SimpleAction::new_stateful("show", None, &false.to_variant());
+        show_preview.connect_change_state({
+            let preview = preview.clone();
+            let editor_paned = editor_paned.clone();
+            move |action, state| {
+                let Some(show) = state.and_then(|state| state.get::<bool>()) else {
+                    return;
+                };
+                action.set_state(&show.to_variant());
+                preview.container.set_visible(show);
+                if show {
+                    // Start out with source and preview side by side
+                    editor_paned.set_position(editor_paned.width() / 2);
+                }
+            }
+        });
+        let preview_actions = gio::SimpleActionGroup::new();
+        preview_actions.add_action(&show_preview);
+        self.window
+            .insert_action_group("preview", Some(&preview_actions));
+
+        let preview_button = gtk::ToggleButton::new();
+        preview_button.set_icon_name("view-dual-symbolic");
+        preview_button.set_tooltip_text(Some("Show Preview"));
+        preview_button.set_action_name(Some("preview.show"));
+        self.header_bar.pack_start(&preview_button);
+
+        let shortcuts = gtk::ShortcutController::new();
+        shortcuts.add_shortcut(gtk::Shortcut::new(
+            gtk::ShortcutTrigger::parse_string("<Control><Shift>p"),
+            Some(gtk::NamedAction::new("preview.show")),
+        ));
+        self.window.add_controller(shortcuts);
+
+        // Add editor and preview to main container
+        main_container.append(&editor_paned);
 
         // Create the side pane (right side)
         let side_pane = gtk::Box::new(gtk::Orientation::Vertical, 8);
-        side_pane.set_width_request(250);
         side_pane.set_vexpand(true);
         side_pane.set_margin_top(8);
         side_pane.set_margin_bottom(8);
         side_pane.set_margin_start(8);
         side_pane.set_margin_end(8);
-        side_pane.add_css_class("sidebar");
 
         // Add a placeholder label for iroh peers
         let placeholder_label = gtk::Label::new(Some("No Active Iroh Peers"));
@@ -223,51 +460,722 @@ impl AppState {
 
         side_pane.append(&placeholder_label);
 
+        // The side pane has a page per topic, starting with the peer list
+        let side_stack = gtk::Stack::new();
+        side_stack.set_vexpand(true);
+        side_stack.set_transition_type(gtk::StackTransitionType::Crossfade);
+        side_stack.add_titled(&side_pane, Some("peers"), "Peers");
+
+        let side_switcher = gtk::StackSwitcher::new();
+        side_switcher.set_stack(Some(&side_stack));
+        side_switcher.set_margin_top(8);
+        side_switcher.set_margin_start(8);
+        side_switcher.set_margin_end(8);
+
+        let side_container = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        side_container.set_width_request(250);
+        side_container.add_css_class("sidebar");
+        side_container.append(&side_switcher);
+        side_container.append(&side_stack);
+
         // Add side pane to main container
-        main_container.append(&side_pane);
-
-        // Remove any existing editor content (but keep the header bar)
-        let mut child = self.editor_page.first_child();
-        while let Some(widget) = child {
-            let next = widget.next_sibling();
-            if &widget != &self.header_bar {
-                self.editor_page.remove(&widget);
-            }
-            child = next;
+        main_container.append(&side_container);
+
+        // Remove any existing editor content
+        while let Some(widget) = self.editor_page.first_child() {
+            self.editor_page.remove(&widget);
         }
 
+        // The peer indicator in the header bar opens the peer list
+        self.peer_button.connect_clicked({
+            let side_stack = side_stack.clone();
+            move |_| side_stack.set_visible_child_name("peers")
+        });
+
         // Store reference to side pane for later updates
         self.side_pane = Some(side_pane.clone());
+        self.side_stack = Some(side_stack);
+        self.editor_view = Some(view);
 
         // Add the new main container
         self.editor_page.append(&main_container);
     }
 
-    pub fn update_document_id(&self, doc_id: &DocumentId, node_id: iroh::NodeId) {
+    /// Adds a page to the side pane, next to the peer list.
+    pub fn add_side_page(&self, name: &str, title: &str, page: &impl IsA<gtk::Widget>) {
+        if let Some(ref side_stack) = self.side_stack {
+            side_stack.add_titled(page, Some(name), title);
+        }
+    }
+
+    /// The node the document is synced with, if one was given.
+    pub fn sync_peer(&self) -> Option<iroh::NodeId> {
+        self.invite.as_ref().map(|invite| invite.node_id)
+    }
+
+    /// Whether the document was opened from a read-only link.
+    pub fn read_only(&self) -> bool {
+        self.invite.as_ref().is_some_and(|invite| invite.read_only)
+    }
+
+    pub fn update_document_id(&self, doc_id: &DocumentId, endpoint: &iroh::Endpoint) {
         let doc_id_string = doc_id.to_string();
-        let connection_string = format!("automerge:{doc_id_string} {node_id}");
+        let connection_string = format!("automerge:{doc_id_string} {}", endpoint.node_id());
         self.doc_id_label
             .set_text(&format!("Connect using: {connection_string}"));
         self.doc_id_label.set_tooltip_text(Some(&format!(
             "Full connnection string: {connection_string}"
         )));
 
-        // Enable the copy button and set up its click handler
-        self.copy_button.set_sensitive(true);
+        // Enable the share button and set up its click handler
+        self.share_button.set_sensitive(true);
 
         let window = self.window.clone();
-        self.copy_button.connect_clicked(move |_| {
-            // Copy to clipboard
-            let display = gtk::prelude::WidgetExt::display(&window);
-            let clipboard = display.clipboard();
-            clipboard.set_text(&connection_string);
-
-            // Show a toast or notification (optional)
-            println!("Connection string copied to clipboard: {connection_string}");
+        let doc_id = doc_id.clone();
+        let endpoint = endpoint.clone();
+        // Links shared on from a read-only link start out read-only as well
+        let read_only = self.read_only();
+        let notifier = self.notifier.clone();
+        self.share_button.connect_clicked(move |_| {
+            share::present(&window, &doc_id, &endpoint, read_only, &notifier);
         });
     }
 
+    /// Installs the formatting actions on the editor view, with keyboard
+    /// shortcuts and entries in the context menu.
+    pub fn setup_formatting(&self, sync: &TextSynchronizer) {
+        let Some(ref view) = self.editor_view else {
+            return;
+        };
+        let actions = gio::SimpleActionGroup::new();
+
+        for mark in [marks::BOLD, marks::ITALIC] {
+            let action = gio::SimpleAction::new(mark, None);
+            action.set_enabled(!sync.is_read_only());
+            let sync = sync.clone();
+            action.connect_activate(move |_, _| {
+                if let Err(e) = sync.toggle_mark(mark) {
+                    tracing::warn!(%e, mark, "failed to toggle mark");
+                }
+            });
+            actions.add_action(&action);
+        }
+
+        let link_action = gio::SimpleAction::new(marks::LINK, None);
+        link_action.set_enabled(!sync.is_read_only());
+        link_action.connect_activate({
+            let sync = sync.clone();
+            let view = view.clone();
+            move |_, _| {
+                let buffer = view.buffer();
+                let Some((start, end)) = buffer.selection_bounds() else {
+                    return;
+                };
+                let (start, end) = (start.offset() as usize, end.offset() as usize);
+
+                // Selecting an existing link removes it
+                if marks::link_at(&buffer, start as i32).is_some() {
+                    if let Err(e) = sync.set_mark(marks::LINK, None, start, end) {
+                        tracing::warn!(%e, "failed to remove link");
+                    }
+                    return;
+                }
+
+                let entry = gtk::Entry::new();
+                entry.set_placeholder_text(Some("https://"));
+                entry.set_activates_default(true);
+
+                let dialog = adw::AlertDialog::new(Some("Add Link"), None);
+                dialog.add_responses(&[("cancel", "Cancel"), ("add", "Add Link")]);
+                dialog.set_response_appearance("add", adw::ResponseAppearance::Suggested);
+                dialog.set_default_response(Some("add"));
+                dialog.set_close_response("cancel");
+                dialog.set_extra_child(Some(&entry));
+
+                let sync = sync.clone();
+                dialog.connect_response(None, move |_, response| {
+                    let url = entry.text();
+                    if response != "add" || url.trim().is_empty() {
+                        return;
+                    }
+                    let value = automerge::ScalarValue::Str(url.trim().into());
+                    if let Err(e) = sync.set_mark(marks::LINK, Some(value), start, end) {
+                        tracing::warn!(%e, "failed to add link");
+                    }
+                });
+                dialog.present(Some(&view));
+            }
+        });
+        actions.add_action(&link_action);
+
+        view.insert_action_group("format", Some(&actions));
+
+        let menu = gio::Menu::new();
+        menu.append(Some("Bold"), Some("format.bold"));
+        menu.append(Some("Italic"), Some("format.italic"));
+        menu.append(Some("Link…"), Some("format.link"));
+        view.set_extra_menu(Some(&menu));
+
+        let shortcuts = gtk::ShortcutController::new();
+        for (trigger, action) in [
+            ("<Control>b", "format.bold"),
+            ("<Control>i", "format.italic"),
+            ("<Control>k", "format.link"),
+        ] {
+            shortcuts.add_shortcut(gtk::Shortcut::new(
+                gtk::ShortcutTrigger::parse_string(trigger),
+                Some(gtk::NamedAction::new(action)),
+            ));
+        }
+        view.add_controller(shortcuts);
+    }
+
+    /// Adds the find and replace bar above the editor, with a header bar button
+    /// and the usual shortcuts to open it.
+    pub fn setup_find(&self, sync: &TextSynchronizer) {
+        let Some(ref view) = self.editor_view else {
+            return;
+        };
+        let find = FindBar::new(sync, view);
+        self.toolbar_view.add_top_bar(&find.bar);
+
+        let find_button = gtk::ToggleButton::new();
+        find_button.set_icon_name("edit-find-symbolic");
+        find_button.set_tooltip_text(Some("Find and Replace"));
+        find_button
+            .bind_property("active", &find.bar, "search-mode-enabled")
+            .bidirectional()
+            .build();
+        self.header_bar.pack_end(&find_button);
+    }
+
+    /// Adds a header bar button that opens the history of the edited text.
+    pub fn setup_history(&self, sync: &TextSynchronizer) {
+        let history_button = gtk::Button::from_icon_name("document-open-recent-symbolic");
+        history_button.set_tooltip_text(Some("Browse History"));
+
+        let window = self.window.clone();
+        let sync = sync.clone();
+        history_button.connect_clicked(move |_| {
+            // Show the latest edits in the history as well
+            sync.flush();
+            HistoryWindow::present(&window, &sync);
+        });
+
+        self.header_bar.pack_end(&history_button);
+    }
+
+    /// Adds the export entries to the file menu.
+    pub fn setup_export(&self, sync: &TextSynchronizer) {
+        let actions = gio::SimpleActionGroup::new();
+        for (name, format) in [
+            ("markdown", ExportFormat::Markdown),
+            ("html", ExportFormat::Html),
+            ("pdf", ExportFormat::Pdf),
+        ] {
+            let action = gio::SimpleAction::new(name, None);
+            let window = self.window.clone();
+            let sync = sync.clone();
+            let notifier = self.notifier.clone();
+            action.connect_activate(move |_, _| {
+                let path = sync.path();
+                let (title, markdown) = sync.handle().with_document(|doc| {
+                    let markdown = path.read(doc).unwrap_or_default();
+                    (Metadata::read(doc).display_title(&markdown), markdown)
+                });
+
+                let dialog = gtk::FileDialog::builder()
+                    .title("Export")
+                    .initial_name(format!("{title}.{}", format.extension()))
+                    .build();
+                let parent = window.clone();
+                let notifier = notifier.clone();
+                dialog.save(Some(&window), gio::Cancellable::NONE, move |result| {
+                    let Some(path) = result.ok().and_then(|file| file.path()) else {
+                        return;
+                    };
+                    let result = match format {
+                        ExportFormat::Pdf => export::export_pdf(&parent, &markdown, &path),
+                        _ => export::export_text(&path, format, &title, &markdown),
+                    };
+                    if let Err(ref e) = result {
+                        tracing::error!(e = format!("{e:#}"), "failed to export");
+                    }
+                    notifier.notify(Notification::file_saved(&path, result.is_ok()));
+                });
+            });
+            actions.add_action(&action);
+        }
+        self.window.insert_action_group("export", Some(&actions));
+
+        let section = gio::Menu::new();
+        section.append(Some("Export as Markdown…"), Some("export.markdown"));
+        section.append(Some("Export as HTML…"), Some("export.html"));
+        section.append(Some("Export as PDF…"), Some("export.pdf"));
+        self.file_menu.append_section(None, &section);
+    }
+
+    /// Adds linking the text to a markdown file to the file menu, and links the
+    /// file given on the command line.
+    pub fn setup_linked_file(&self, sync: &TextSynchronizer) {
+        let linked: Rc<RefCell<Option<LinkedFile>>> = Rc::new(RefCell::new(None));
+        let actions = gio::SimpleActionGroup::new();
+
+        let unlink_action = gio::SimpleAction::new("unlink", None);
+        unlink_action.set_enabled(false);
+        unlink_action.connect_activate({
+            let linked = linked.clone();
+            move |action, _| {
+                if let Some(file) = linked.borrow_mut().take() {
+                    file.unlink();
+                }
+                action.set_enabled(false);
+            }
+        });
+
+        let link = {
+            let linked = linked.clone();
+            let sync = sync.clone();
+            let unlink_action = unlink_action.clone();
+            move |path: PathBuf| {
+                if let Some(file) = linked.borrow_mut().take() {
+                    file.unlink();
+                }
+                match LinkedFile::link(&sync, path) {
+                    Ok(file) => {
+                        *linked.borrow_mut() = Some(file);
+                        unlink_action.set_enabled(true);
+                    }
+                    Err(e) => {
+                        tracing::error!(e = format!("{e:#}"), "failed to link file");
+                        unlink_action.set_enabled(false);
+                    }
+                }
+            }
+        };
+
+        // Reading the file into the document would change it
+        let choose_action = gio::SimpleAction::new("choose", None);
+        choose_action.set_enabled(!sync.is_read_only());
+        let window = self.window.clone();
+        let sync_for_chooser = sync.clone();
+        choose_action.connect_activate({
+            let link = link.clone();
+            let linked = linked.clone();
+            move |_, _| {
+                let initial_name = match linked.borrow().as_ref() {
+                    Some(file) => file
+                        .path()
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned()),
+                    None => {
+                        let path = sync_for_chooser.path();
+                        let title = sync_for_chooser.handle().with_document(|doc| {
+                            let markdown = path.read(doc).unwrap_or_default();
+                            Metadata::read(doc).display_title(&markdown)
+                        });
+                        Some(format!("{title}.md"))
+                    }
+                };
+                let dialog = gtk::FileDialog::builder()
+                    .title("Link to File")
+                    .accept_label("Link")
+                    .build();
+                dialog.set_initial_name(initial_name.as_deref());
+                let link = link.clone();
+                dialog.save(Some(&window), gio::Cancellable::NONE, move |result| {
+                    if let Some(path) = result.ok().and_then(|file| file.path()) {
+                        link(path);
+                    }
+                });
+            }
+        });
+
+        actions.add_action(&choose_action);
+        actions.add_action(&unlink_action);
+        self.window.insert_action_group("link", Some(&actions));
+
+        let section = gio::Menu::new();
+        section.append(Some("Link to File…"), Some("link.choose"));
+        section.append(Some("Unlink File"), Some("link.unlink"));
+        self.file_menu.append_section(None, &section);
+
+        match self.linked_file.clone() {
+            Some(_) if sync.is_read_only() => {
+                tracing::warn!("not linking a file to a document opened read-only");
+            }
+            Some(path) => link(path),
+            None => {}
+        }
+    }
+
+    /// Adds committing the text into a git repository to the file menu.
+    pub fn setup_git(&self, sync: &TextSynchronizer) {
+        // Remember the file, so repeated commits only need a confirmation
+        let last_file: Rc<RefCell<Option<gio::File>>> = Rc::new(RefCell::new(None));
+        let action = gio::SimpleAction::new("commit", None);
+        let window = self.window.clone();
+        let sync = sync.clone();
+        let notifier = self.notifier.clone();
+        action.connect_activate(move |_, _| {
+            let dialog = gtk::FileDialog::builder()
+                .title("Commit to Git Repository")
+                .accept_label("Commit")
+                .build();
+            match last_file.borrow().as_ref() {
+                Some(file) => dialog.set_initial_file(Some(file)),
+                None => dialog.set_initial_name(Some("README.md")),
+            }
+            let parent = window.clone();
+            let sync = sync.clone();
+            let notifier = notifier.clone();
+            let last_file = last_file.clone();
+            dialog.save(Some(&window), gio::Cancellable::NONE, move |result| {
+                let Some(file) = result.ok() else {
+                    return;
+                };
+                let Some(path) = file.path() else {
+                    return;
+                };
+                *last_file.borrow_mut() = Some(file);
+
+                glib::spawn_future_local(async move {
+                    match Self::commit_to_git(&sync, &path).await {
+                        Ok(Some(commit)) => notifier.notify(Notification::Committed(commit)),
+                        Ok(None) => notifier.notify(Notification::AlreadyCommitted(
+                            path.file_name()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .into_owned(),
+                        )),
+                        Err(e) => {
+                            // Git's explanation doesn't fit into a toast
+                            tracing::error!(e = format!("{e:#}"), "failed to commit to git");
+                            let dialog = adw::AlertDialog::new(
+                                Some("Failed to Commit"),
+                                Some(&format!("{e:#}")),
+                            );
+                            dialog.set_body_use_markup(false);
+                            dialog.add_response("close", "Close");
+                            dialog.present(Some(&parent));
+                        }
+                    }
+                });
+            });
+        });
+        let actions = gio::SimpleActionGroup::new();
+        actions.add_action(&action);
+        self.window.insert_action_group("git", Some(&actions));
+
+        let section = gio::Menu::new();
+        section.append(Some("Commit to Git Repository…"), Some("git.commit"));
+        self.file_menu.append_section(None, &section);
+    }
+
+    /// Commits the text to `path`. Git may take a while with hooks or signing,
+    /// so it runs on another thread and only reading the text holds the document.
+    async fn commit_to_git(sync: &TextSynchronizer, path: &Path) -> anyhow::Result<Option<String>> {
+        let doc_id = sync.handle().document_id();
+        let since = {
+            let path = path.to_path_buf();
+            let doc_id = doc_id.clone();
+            gio::spawn_blocking(move || git::committed_heads(&path, &doc_id))
+                .await
+                .map_err(|_| anyhow::anyhow!("git thread panicked"))??
+        };
+
+        // Include the latest edits in the commit
+        sync.flush();
+        let text_path = sync.path();
+        let pending = sync
+            .handle()
+            .with_document(|doc| git::PendingCommit::read(doc, &doc_id, &text_path, &since))?;
+
+        let path = path.to_path_buf();
+        gio::spawn_blocking(move || git::commit(&path, &pending))
+            .await
+            .map_err(|_| anyhow::anyhow!("git thread panicked"))?
+    }
+
+    /// Adds saving the whole document or just the latest changes as `.automerge`
+    /// files to the file menu, and applying such files to the open document.
+    pub fn setup_automerge_files(&self, sync: &TextSynchronizer) {
+        // Heads of the last save, so the next bundle only has what changed since
+        let saved_heads: Rc<RefCell<Vec<ChangeHash>>> = Rc::new(RefCell::new(Vec::new()));
+        let actions = gio::SimpleActionGroup::new();
+
+        let save_changes_action = gio::SimpleAction::new("save-changes", None);
+        save_changes_action.set_enabled(false);
+        for (action, incremental) in [
+            (gio::SimpleAction::new("save", None), false),
+            (save_changes_action.clone(), true),
+        ] {
+            let window = self.window.clone();
+            let sync = sync.clone();
+            let notifier = self.notifier.clone();
+            let saved_heads = saved_heads.clone();
+            let save_changes_action = save_changes_action.clone();
+            action.connect_activate(move |_, _| {
+                sync.flush();
+                let document_id = sync.handle().document_id();
+                let (title, suffix) = if incremental {
+                    ("Save Changes", "-changes")
+                } else {
+                    ("Save Automerge File", "")
+                };
+                let dialog = gtk::FileDialog::builder()
+                    .title(title)
+                    .initial_name(format!(
+                        "{document_id}{suffix}.{}",
+                        automerge_file::EXTENSION
+                    ))
+                    .build();
+                let sync = sync.clone();
+                let notifier = notifier.clone();
+                let saved_heads = saved_heads.clone();
+                let save_changes_action = save_changes_action.clone();
+                dialog.save(Some(&window), gio::Cancellable::NONE, move |result| {
+                    let Some(path) = result.ok().and_then(|file| file.path()) else {
+                        return;
+                    };
+                    let since = if incremental {
+                        saved_heads.borrow().clone()
+                    } else {
+                        Vec::new()
+                    };
+                    let result = sync.handle().with_document(|doc| {
+                        automerge_file::save(doc, &since, &path)?;
+                        anyhow::Ok(doc.get_heads())
+                    });
+                    match &result {
+                        Ok(heads) => {
+                            *saved_heads.borrow_mut() = heads.clone();
+                            save_changes_action.set_enabled(true);
+                        }
+                        Err(e) => {
+                            tracing::error!(e = format!("{e:#}"), "failed to save automerge file");
+                        }
+                    }
+                    notifier.notify(Notification::file_saved(&path, result.is_ok()));
+                });
+            });
+            actions.add_action(&action);
+        }
+
+        let apply_action = gio::SimpleAction::new("apply", None);
+        apply_action.set_enabled(!sync.is_read_only());
+        let window = self.window.clone();
+        let sync = sync.clone();
+        apply_action.connect_activate(move |_, _| {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some("Automerge Document"));
+            filter.add_suffix(automerge_file::EXTENSION);
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&filter);
+            let dialog = gtk::FileDialog::builder()
+                .title("Apply Changes from File")
+                .accept_label("Apply")
+                .filters(&filters)
+                .build();
+            let sync = sync.clone();
+            dialog.open(Some(&window), gio::Cancellable::NONE, move |result| {
+                let Some(path) = result.ok().and_then(|file| file.path()) else {
+                    return;
+                };
+                // Let the synchronizer pick the new changes up from the document
+                sync.flush();
+                match sync
+                    .handle()
+                    .with_document(|doc| automerge_file::apply(doc, &path))
+                {
+                    Ok(ops) => tracing::info!(path = %path.display(), ops, "applied changes"),
+                    Err(e) => tracing::error!(e = format!("{e:#}"), "failed to apply changes"),
+                }
+            });
+        });
+        actions.add_action(&apply_action);
+        self.window.insert_action_group("automerge", Some(&actions));
+
+        let section = gio::Menu::new();
+        section.append(Some("Save as Automerge File…"), Some("automerge.save"));
+        section.append(
+            Some("Save Changes Since Last Save…"),
+            Some("automerge.save-changes"),
+        );
+        section.append(Some("Apply Changes from File…"), Some("automerge.apply"));
+        self.file_menu.append_section(None, &section);
+    }
+
+    /// Lets markdown and automerge files be imported as new documents, through a
+    /// file chooser in the header bar or by dropping them onto the editor.
+    pub fn setup_import(&self, samod: &samod::Samod, node_id: iroh::NodeId) {
+        let import_button = gtk::Button::from_icon_name("document-open-symbolic");
+        import_button.set_tooltip_text(Some("Import File…"));
+
+        let window = self.window.clone();
+        let samod_for_chooser = samod.clone();
+        import_button.connect_clicked(move |_| {
+            let markdown_filter = gtk::FileFilter::new();
+            markdown_filter.set_name(Some("Markdown"));
+            for extension in import::MARKDOWN_EXTENSIONS {
+                markdown_filter.add_suffix(extension);
+            }
+            let automerge_filter = gtk::FileFilter::new();
+            automerge_filter.set_name(Some("Automerge Document"));
+            automerge_filter.add_suffix(automerge_file::EXTENSION);
+            let filters = gio::ListStore::new::<gtk::FileFilter>();
+            filters.append(&markdown_filter);
+            filters.append(&automerge_filter);
+
+            let dialog = gtk::FileDialog::builder()
+                .title("Import File")
+                .filters(&filters)
+                .build();
+            dialog.open(Some(&window), gio::Cancellable::NONE, {
+                let window = window.clone();
+                let samod = samod_for_chooser.clone();
+                move |result| {
+                    let Some(path) = result.ok().and_then(|file| file.path()) else {
+                        return;
+                    };
+                    glib::spawn_future_local(async move {
+                        import::import_and_show(&window, &samod, node_id, &path).await;
+                    });
+                }
+            });
+        });
+        self.header_bar.pack_end(&import_button);
+
+        let drop_target =
+            gtk::DropTarget::new(gio::File::static_type(), gtk::gdk::DragAction::COPY);
+        let window = self.window.clone();
+        let samod = samod.clone();
+        drop_target.connect_drop(move |_, value, _, _| {
+            let Some(path) = value.get::<gio::File>().ok().and_then(|file| file.path()) else {
+                return false;
+            };
+            if !import::is_markdown(&path) && !import::is_automerge(&path) {
+                return false;
+            }
+            let window = window.clone();
+            let samod = samod.clone();
+            glib::spawn_future_local(async move {
+                import::import_and_show(&window, &samod, node_id, &path).await;
+            });
+            true
+        });
+        self.editor_page.add_controller(drop_target);
+    }
+
+    /// Returns a callback that shows the document title and metadata in the header bar.
+    pub fn title_updater(&self) -> impl Fn(&Metadata, &str) + 'static {
+        let title_label = self.title_label.clone();
+        let window = self.window.clone();
+        move |metadata, content| {
+            let title = metadata.display_title(content);
+            title_label.set_text(&title);
+            title_label.set_tooltip_text(Some(&metadata.summary()));
+            window.set_title(Some(&format!("{title} - Glyphcaster")));
+        }
+    }
+
+    /// Returns a callback that switches the editor to read-only and reveals the
+    /// banner offering the raw inspector when the document has an unexpected shape.
+    pub fn document_error_handler(&self, handle: &DocHandle) -> impl Fn(&DocumentError) + 'static {
+        let window = self.window.clone();
+        let banner = self.banner.clone();
+        let view = self.editor_view.clone();
+        let notifier = self.notifier.clone();
+        let handle = handle.clone();
+
+        banner.connect_button_clicked(move |_| {
+            inspector::show_inspector(&window, &handle);
+        });
+
+        move |error| {
+            if let Some(ref view) = view {
+                view.set_editable(false);
+            }
+            banner.set_title(&format!(
+                "Document has unexpected structure, opened read-only: {}",
+                glib::markup_escape_text(&error.to_string())
+            ));
+            banner.set_revealed(true);
+            notifier.notify(Notification::SyncError(error.to_string()));
+        }
+    }
+
+    /// Adds a picker to the header bar that lists all text objects in the
+    /// document and switches the editor to the chosen one.
+    pub fn setup_text_picker(&self, sync: &TextSynchronizer, handle: &DocHandle) {
+        let picker_button = gtk::MenuButton::new();
+        picker_button.set_label(&sync.path().to_string());
+        picker_button.set_tooltip_text(Some("Choose the text field to edit"));
+
+        let list = gtk::ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::None);
+        list.add_css_class("navigation-sidebar");
+
+        let popover = gtk::Popover::new();
+        popover.set_child(Some(&list));
+        picker_button.set_popover(Some(&popover));
+
+        let paths = std::rc::Rc::new(std::cell::RefCell::new(Vec::<TextPath>::new()));
+
+        // Refresh the list every time it's opened, peers may have added text objects
+        popover.connect_show({
+            let list = list.clone();
+            let paths = paths.clone();
+            let handle = handle.clone();
+            move |_| {
+                list.remove_all();
+                let found = handle.with_document(|doc| TextPath::find_all(doc));
+                if found.is_empty() {
+                    let label = gtk::Label::new(Some("No text objects in this document"));
+                    label.add_css_class("dim-label");
+                    list.append(&label);
+                }
+                for path in &found {
+                    let label = gtk::Label::new(Some(&path.to_string()));
+                    label.set_halign(gtk::Align::Start);
+                    list.append(&label);
+                }
+                *paths.borrow_mut() = found;
+            }
+        });
+
+        list.connect_row_activated({
+            let sync = sync.clone();
+            let picker_button = picker_button.clone();
+            let popover = popover.clone();
+            let banner = self.banner.clone();
+            let view = self.editor_view.clone();
+            let read_only = self.read_only();
+            move |_, row| {
+                let Some(path) = paths.borrow().get(row.index() as usize).cloned() else {
+                    return;
+                };
+                popover.popdown();
+                match sync.switch_to(path.clone()) {
+                    Ok(()) => {
+                        picker_button.set_label(&path.to_string());
+                        banner.set_revealed(false);
+                        if let Some(ref view) = view {
+                            view.set_editable(!read_only);
+                        }
+                    }
+                    Err(e) => sync.fail(e),
+                }
+            }
+        });
+
+        self.header_bar.pack_start(&picker_button);
+    }
+
     pub fn update_remote_peers(&self, peer_infos: Vec<iroh::endpoint::RemoteInfo>) {
+        self.update_peer_indicator(peer_infos.len());
+        self.notifier
+            .update_peers(peer_infos.iter().map(|info| info.node_id), self.sync_peer());
         if let Some(ref side_pane) = self.side_pane {
             // Clear existing content except the first child (placeholder)
             let mut child = side_pane.first_child();
@@ -375,13 +1283,62 @@ impl AppState {
         }
     }
 
-    pub fn show_error(&self, error_message: &str) {
+    fn update_peer_indicator(&self, count: usize) {
+        self.peer_count.set_label(&count.to_string());
+        let tooltip = match count {
+            0 => "No Active Iroh Peers".to_string(),
+            1 => "1 Connected Iroh Peer".to_string(),
+            count => format!("{count} Connected Iroh Peers"),
+        };
+        self.peer_button.set_tooltip_text(Some(&tooltip));
+    }
+
+    /// Shows why loading failed and waits for the user to choose how to go on.
+    pub async fn show_error(&self, error: &anyhow::Error) -> Recovery {
         self.loading_spinner.stop();
-        self.loading_label.set_markup(&format!(
-            "<span size='large' color='red'>Error: {}</span>",
-            glib::markup_escape_text(error_message)
-        ));
+        let page = &self.error_page;
+
+        let summary = error.to_string();
+        let mut chars = summary.chars();
+        let summary: String = chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default();
+        page.page
+            .set_description(Some(&glib::markup_escape_text(&summary)));
+        page.details.set_text(&format!("{error:?}"));
+        *page.diagnostics.borrow_mut() = self.diagnostics(error);
+        // Offline is all there is left to try then
+        page.open_offline_button.set_visible(!self.offline);
+        self.main_stack.set_visible_child_name("error");
+
+        let (sender, receiver) = oneshot::channel();
+        *page.recovery.borrow_mut() = Some(sender);
+        receiver.await.unwrap_or(Recovery::Retry)
+    }
+
+    /// Describes the failure for bug reports.
+    fn diagnostics(&self, error: &anyhow::Error) -> String {
+        let document = self.document_id.as_ref().map_or_else(
+            || "new document".to_string(),
+            |id| format!("automerge:{id}"),
+        );
+        let peer = self
+            .sync_peer()
+            .map_or_else(|| "none".to_string(), |node| node.to_string());
+        format!(
+            "Glyphcaster {}\nDocument: {document}\nText path: {}\nPeer: {peer}\nOffline: {}\n\n{error:?}\n",
+            env!("CARGO_PKG_VERSION"),
+            self.text_path,
+            self.offline,
+        )
+    }
+
+    /// Goes back to the loading page, e.g. to retry after an error.
+    pub fn show_loading(&self) {
+        self.loading_spinner.start();
         self.progress_bar.set_fraction(0.0);
-        self.progress_bar.set_text(Some("Failed"));
+        self.progress_bar.set_text(None);
+        self.main_stack.set_visible_child_name("loading");
     }
 }
@@ -0,0 +1,101 @@
+//! Maps automerge actor IDs to the iroh node IDs of the peers using them.
+//!
+//! Every glyphcaster instance records its actor under `authors` when it opens
+//! a document, so history and authorship can be shown per peer:
+//!
+//! ```typescript
+//! type Authors = { [actorId: string]: string } // actor ID (hex) -> iroh node ID
+//! ```
+
+use std::collections::HashMap;
+
+use automerge::{
+    ActorId, Automerge, AutomergeError, ObjType, ROOT, ReadDoc, Value, transaction::Transactable,
+};
+
+use crate::schema::{commit_options, read_string};
+
+pub(crate) const AUTHORS_KEY: &str = "authors";
+
+/// Records that the document's current actor belongs to `node_id`.
+///
+/// The `authors` map is created with the document, see [`crate::schema`].
+/// Documents without one are left alone, so the history shows actor IDs.
+pub(crate) fn register(doc: &mut Automerge, node_id: iroh::NodeId) -> Result<(), AutomergeError> {
+    let actor = doc.get_actor().to_hex_string();
+    let node_id = node_id.to_string();
+    let authors_id = match doc.get(ROOT, AUTHORS_KEY)? {
+        Some((Value::Object(ObjType::Map), authors_id)) => authors_id,
+        _ => {
+            tracing::warn!("document has no authors map, not registering");
+            return Ok(());
+        }
+    };
+    if read_string(doc, &authors_id, &actor).as_deref() == Some(node_id.as_str()) {
+        return Ok(());
+    }
+
+    doc.transact_with::<_, _, AutomergeError, _>(
+        |_| commit_options("Register author"),
+        |tx| tx.put(&authors_id, actor, node_id),
+    )
+    .map_err(|failure| failure.error)?;
+    Ok(())
+}
+
+/// All known actors, as hex actor ID to node ID.
+pub(crate) fn read_authors<R: ReadDoc>(doc: &R) -> HashMap<String, String> {
+    let Ok(Some((Value::Object(ObjType::Map), authors_id))) = doc.get(ROOT, AUTHORS_KEY) else {
+        return HashMap::new();
+    };
+    doc.keys(&authors_id)
+        .filter_map(|actor| {
+            let node_id = read_string(doc, &authors_id, &actor)?;
+            Some((actor, node_id))
+        })
+        .collect()
+}
+
+/// A short name for an actor: the beginning of its node ID if known, or of the
+/// actor ID for peers that aren't glyphcaster instances.
+pub(crate) fn display_name(authors: &HashMap<String, String>, actor: &ActorId) -> String {
+    let actor = actor.to_hex_string();
+    match authors.get(&actor) {
+        Some(node_id) => format!("{}...", node_id.chars().take(12).collect::<String>()),
+        None => format!("actor {}...", actor.chars().take(8).collect::<String>()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema;
+
+    fn node_id() -> iroh::NodeId {
+        iroh::SecretKey::from_bytes(&[1; 32]).public()
+    }
+
+    #[test]
+    fn registers_actors_once() {
+        let mut doc = Automerge::new();
+        doc.transact(|tx| schema::initialize(tx, "Notes", "# Notes", node_id()))
+            .unwrap();
+        register(&mut doc, node_id()).unwrap();
+        let heads = doc.get_heads();
+        assert_eq!(
+            read_authors(&doc).get(&doc.get_actor().to_hex_string()),
+            Some(&node_id().to_string())
+        );
+
+        register(&mut doc, node_id()).unwrap();
+        assert_eq!(doc.get_heads(), heads);
+    }
+
+    #[test]
+    fn leaves_documents_without_authors_alone() {
+        let mut doc = Automerge::new();
+        register(&mut doc, node_id()).unwrap();
+        assert!(doc.get_heads().is_empty());
+        assert!(read_authors(&doc).is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+//! Saving and loading raw `.automerge` files, independently of samod's storage.
+//!
+//! A file either holds the whole document as written by [`Automerge::save`], or
+//! a bundle of just the changes made after some heads. Both can be handed to
+//! other automerge tooling, carried over to machines that aren't connected, or
+//! kept as backups.
+
+use std::{path::Path, str::FromStr};
+
+use anyhow::Context as _;
+use automerge::{Automerge, ChangeHash};
+use glib::ExitCode;
+use samod::DocumentId;
+
+use crate::authors;
+use crate::export;
+use crate::fs::write_atomically;
+use crate::schema;
+
+pub(crate) const EXTENSION: &str = "automerge";
+
+/// Parses a comma separated list of change hashes, as printed after saving.
+pub(crate) fn parse_heads(heads: &str) -> anyhow::Result<Vec<ChangeHash>> {
+    heads
+        .split(',')
+        .map(str::trim)
+        .filter(|hash| !hash.is_empty())
+        .map(|hash| ChangeHash::from_str(hash).with_context(|| format!("invalid head {hash}")))
+        .collect()
+}
+
+pub(crate) fn format_heads(heads: &[ChangeHash]) -> String {
+    heads
+        .iter()
+        .map(ChangeHash::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Writes the whole document, or only the changes after `since` if it's not empty.
+pub(crate) fn save(doc: &Automerge, since: &[ChangeHash], path: &Path) -> anyhow::Result<()> {
+    let bytes = if since.is_empty() {
+        doc.save()
+    } else {
+        doc.save_after(since)
+    };
+    write_atomically(path, bytes)
+}
+
+/// Reads a whole document as a new document.
+///
+/// Change bundles only make sense on top of the document they were saved
+/// from, so they're rejected here and have to be applied with [`apply`].
+pub(crate) fn load(path: &Path) -> anyhow::Result<Automerge> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut doc = Automerge::load(&bytes)
+        .with_context(|| format!("{} isn't an automerge file", path.display()))?;
+    anyhow::ensure!(
+        doc.get_missing_deps(&[]).is_empty(),
+        "{} only holds changes to another document, apply it to that document instead",
+        path.display()
+    );
+    if let Err(e) = schema::migrate(&mut doc) {
+        tracing::warn!(%e, "failed to migrate loaded document");
+    }
+    Ok(doc)
+}
+
+/// Applies a whole document or a change bundle to `doc`. Returns the number of
+/// operations that were new.
+pub(crate) fn apply(doc: &mut Automerge, path: &Path) -> anyhow::Result<usize> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    doc.load_incremental(&bytes)
+        .with_context(|| format!("{} isn't an automerge file", path.display()))
+}
+
+/// Creates a new document in samod from the file at `path`.
+pub(crate) async fn load_new(
+    samod: &samod::Samod,
+    node_id: iroh::NodeId,
+    path: &Path,
+) -> anyhow::Result<DocumentId> {
+    let mut doc = load(path)?;
+    authors::register(&mut doc, node_id)?;
+    let handle = samod.create(doc).await?;
+    tracing::info!(path = %path.display(), id = %handle.document_id(), "loaded automerge file");
+    Ok(handle.document_id())
+}
+
+/// Runs `glyphcaster export <automerge URL> <file>.automerge [--since <heads>]`.
+pub(crate) fn run_save_cli(doc_id: DocumentId, since: &[ChangeHash], output: &Path) -> ExitCode {
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start tokio runtime: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = rt.block_on(export::with_stored_document(doc_id, |doc| {
+        save(doc, since, output)?;
+        Ok(doc.get_heads())
+    }));
+    match result {
+        Ok(heads) => {
+            println!("Saved to {}", output.display());
+            // Passing these as --since next time saves only what changed in between
+            println!("Heads: {}", format_heads(&heads));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to save: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{AutomergeError, ReadDoc, transaction::Transactable};
+
+    use super::*;
+    use crate::text_path::TextPath;
+
+    fn document() -> Automerge {
+        let node_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        schema::new_document("Notes", "Hello", node_id).unwrap()
+    }
+
+    fn append(doc: &mut Automerge, text: &str) {
+        let text_id = TextPath::default().resolve(doc).unwrap();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let length = tx.length(&text_id);
+            tx.splice_text(&text_id, length, 0, text)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn round_trips_heads() {
+        let heads = vec![ChangeHash([1; 32]), ChangeHash([0xab; 32])];
+        assert_eq!(parse_heads(&format_heads(&heads)).unwrap(), heads);
+        assert_eq!(format_heads(&[]), "");
+        assert!(parse_heads("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_whitespace_and_empty_heads() {
+        let head = ChangeHash([7; 32]);
+        let parsed = parse_heads(&format!(" {head} ,, {head},")).unwrap();
+        assert_eq!(parsed, [head, head]);
+    }
+
+    #[test]
+    fn rejects_invalid_heads() {
+        let error = parse_heads(&format!("{},abc", ChangeHash([7; 32]))).unwrap_err();
+        assert_eq!(error.to_string(), "invalid head abc");
+        assert!(parse_heads(&"0".repeat(63)).is_err());
+    }
+
+    #[test]
+    fn saves_and_loads_whole_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.automerge");
+        let doc = document();
+        save(&doc, &[], &path).unwrap();
+
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+        assert_eq!(TextPath::default().read(&loaded).unwrap(), "Hello");
+    }
+
+    #[test]
+    fn applies_changes_saved_after_heads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.automerge");
+        let mut doc = document();
+        let mut copy = doc.clone();
+        let since = doc.get_heads();
+        append(&mut doc, " world");
+        append(&mut doc, "!");
+        save(&doc, &since, &path).unwrap();
+
+        // The bundle only makes sense on top of the document it came from
+        let error = load(&path).unwrap_err();
+        assert!(format!("{error:#}").contains("only holds changes to another document"));
+
+        assert!(apply(&mut copy, &path).unwrap() > 0);
+        assert_eq!(copy.get_heads(), doc.get_heads());
+        assert_eq!(TextPath::default().read(&copy).unwrap(), "Hello world!");
+        // Applying the same changes again does nothing
+        assert_eq!(apply(&mut copy, &path).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_files_that_arent_automerge() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.automerge");
+        std::fs::write(&path, "# Notes").unwrap();
+        assert!(load(&path).is_err());
+        assert!(apply(&mut document(), &path).is_err());
+    }
+}
@@ -0,0 +1,368 @@
+//! Authorship colouring: which peer wrote each span of the edited text.
+//!
+//! Characters in an automerge text can't be overwritten, only deleted and
+//! inserted again, so the actor that inserted a character is also the last one
+//! who wrote it. Actors are mapped to peers through the `authors` registry.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use automerge::{ActorId, AutomergeError, ObjId, ReadDoc, Value, iter::ListRangeItem};
+use gtk::{gdk, gio, prelude::*};
+
+use crate::authors;
+use crate::sync::TextSynchronizer;
+
+const TAG_PREFIX: &str = "blame:";
+
+/// Distinguishable colours, picked per author by hashing their node ID so that
+/// every peer shows the same author in the same colour.
+const PALETTE: &[&str] = &[
+    "#3584e4", "#2ec27e", "#f5c211", "#e66100", "#c061cb", "#986a44", "#e01b24", "#1c9fa8",
+];
+
+/// A run of characters inserted by the same actor, in buffer offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BlameSpan {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) actor: ActorId,
+}
+
+/// Finds out which actor inserted each character of the text object.
+pub(crate) fn read_blame<R: ReadDoc>(
+    doc: &R,
+    text_obj_id: &ObjId,
+) -> Result<Vec<BlameSpan>, AutomergeError> {
+    let mut spans: Vec<BlameSpan> = Vec::new();
+    let mut offset = 0;
+    for ListRangeItem { value, id, .. } in doc.list_range(text_obj_id, ..) {
+        // Depending on the text encoding an element can hold more than one character
+        let len = match value {
+            Value::Scalar(scalar) => scalar.to_str().map_or(1, |s| s.chars().count()),
+            Value::Object(_) => 1,
+        };
+        let ObjId::Id(_, ref actor, _) = id else {
+            return Err(AutomergeError::InvalidObjId(id.to_string()));
+        };
+        match spans.last_mut() {
+            Some(span) if &span.actor == actor => span.end += len,
+            _ => spans.push(BlameSpan {
+                start: offset,
+                end: offset + len,
+                actor: actor.clone(),
+            }),
+        }
+        offset += len;
+    }
+    Ok(spans)
+}
+
+/// Identifies an author: their node ID, or the actor ID for peers that aren't
+/// glyphcaster instances.
+fn author_key(authors: &HashMap<String, String>, actor: &ActorId) -> String {
+    let actor = actor.to_hex_string();
+    authors.get(&actor).cloned().unwrap_or(actor)
+}
+
+pub(crate) fn author_color(key: &str) -> &'static str {
+    // FNV-1a, std's hasher isn't stable across builds
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+fn ensure_tag(buffer: &impl IsA<gtk::TextBuffer>, key: &str) -> gtk::TextTag {
+    let tag_name = format!("{TAG_PREFIX}{key}");
+    let tag_table = buffer.as_ref().tag_table();
+    if let Some(tag) = tag_table.lookup(&tag_name) {
+        return tag;
+    }
+    let color = gdk::RGBA::parse(author_color(key)).unwrap_or(gdk::RGBA::BLACK);
+    let tag = gtk::TextTag::builder()
+        .name(tag_name.as_str())
+        .background_rgba(&gdk::RGBA::new(
+            color.red(),
+            color.green(),
+            color.blue(),
+            0.25,
+        ))
+        .build();
+    tag_table.add(&tag);
+    tag
+}
+
+fn remove_tags(buffer: &impl IsA<gtk::TextBuffer>) {
+    let buffer = buffer.as_ref();
+    let (start, end) = buffer.bounds();
+    let mut blame_tags = Vec::new();
+    buffer.tag_table().foreach(|tag| {
+        if tag.name().is_some_and(|name| name.starts_with(TAG_PREFIX)) {
+            blame_tags.push(tag.clone());
+        }
+    });
+    for tag in &blame_tags {
+        buffer.remove_tag(tag, &start, &end);
+    }
+}
+
+/// One line of the legend: an author and how many characters of the text
+/// they wrote.
+#[derive(Debug, Clone, PartialEq)]
+struct LegendEntry {
+    key: String,
+    name: String,
+    characters: usize,
+}
+
+/// The authors page of the side pane, and the colouring in the editor.
+#[derive(Clone)]
+pub(crate) struct BlamePanel {
+    pub(crate) container: gtk::Box,
+    legend: gtk::Box,
+    sync: TextSynchronizer,
+    /// The node ID of this peer, marked in the legend.
+    local_node: String,
+    enabled: Rc<Cell<bool>>,
+    entries: Rc<RefCell<Option<Vec<LegendEntry>>>>,
+}
+
+impl BlamePanel {
+    pub(crate) fn new(
+        sync: &TextSynchronizer,
+        view: &sourceview5::View,
+        local_node: String,
+    ) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        container.set_margin_top(8);
+        container.set_margin_bottom(8);
+        container.set_margin_start(8);
+        container.set_margin_end(8);
+
+        let toggle_row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        let toggle_label = gtk::Label::new(Some("Colour by Author"));
+        toggle_label.set_hexpand(true);
+        toggle_label.set_halign(gtk::Align::Start);
+        let toggle = gtk::Switch::new();
+        toggle.set_action_name(Some("blame.show"));
+        toggle_row.append(&toggle_label);
+        toggle_row.append(&toggle);
+        container.append(&toggle_row);
+
+        let legend = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        let scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&legend)
+            .build();
+        container.append(&scroll);
+
+        let panel = Self {
+            container,
+            legend,
+            sync: sync.clone(),
+            local_node,
+            enabled: Rc::new(Cell::new(false)),
+            entries: Rc::new(RefCell::new(None)),
+        };
+        panel.install_action(view);
+
+        sync.connect_reconciled({
+            let panel = panel.clone();
+            move || panel.refresh()
+        });
+        panel.refresh();
+        panel
+    }
+
+    fn install_action(&self, view: &sourceview5::View) {
+        let action = gio::SimpleAction::new_stateful("show", None, &false.to_variant());
+        action.connect_change_state({
+            let panel = self.clone();
+            move |action, state| {
+                let Some(enabled) = state.and_then(|state| state.get::<bool>()) else {
+                    return;
+                };
+                action.set_state(&enabled.to_variant());
+                panel.enabled.set(enabled);
+                panel.refresh();
+            }
+        });
+        let actions = gio::SimpleActionGroup::new();
+        actions.add_action(&action);
+        // The switch in the side pane and the context menu share the action
+        view.insert_action_group("blame", Some(&actions));
+        self.container.insert_action_group("blame", Some(&actions));
+
+        if let Some(menu) = view
+            .extra_menu()
+            .and_then(|menu| menu.downcast::<gio::Menu>().ok())
+        {
+            menu.append(Some("Colour by Author"), Some("blame.show"));
+        }
+    }
+
+    pub(crate) fn refresh(&self) {
+        let buffer = self.sync.buffer();
+        if !self.enabled.get() {
+            remove_tags(buffer);
+            self.show_legend(None);
+            return;
+        }
+
+        let path = self.sync.path();
+        let result = self.sync.handle().with_document(|doc| {
+            let text_obj_id = path.resolve(doc).ok()?;
+            let spans = read_blame(doc, &text_obj_id);
+            Some((spans, authors::read_authors(doc)))
+        });
+        let Some((Ok(spans), authors)) = result else {
+            remove_tags(buffer);
+            self.show_legend(Some(Vec::new()));
+            return;
+        };
+
+        remove_tags(buffer);
+        let mut entries: Vec<LegendEntry> = Vec::new();
+        for span in &spans {
+            let key = author_key(&authors, &span.actor);
+            let tag = ensure_tag(buffer, &key);
+            let start = buffer.iter_at_offset(span.start as i32);
+            let end = buffer.iter_at_offset(span.end as i32);
+            buffer.apply_tag(&tag, &start, &end);
+
+            match entries.iter_mut().find(|entry| entry.key == key) {
+                Some(entry) => entry.characters += span.end - span.start,
+                None => entries.push(LegendEntry {
+                    name: authors::display_name(&authors, &span.actor),
+                    key,
+                    characters: span.end - span.start,
+                }),
+            }
+        }
+        entries.sort_by(|a, b| b.characters.cmp(&a.characters));
+        self.show_legend(Some(entries));
+    }
+
+    /// Rebuilds the legend, `None` meaning colouring is turned off.
+    fn show_legend(&self, entries: Option<Vec<LegendEntry>>) {
+        if *self.entries.borrow() == entries {
+            return;
+        }
+
+        while let Some(child) = self.legend.first_child() {
+            self.legend.remove(&child);
+        }
+        match entries {
+            None => {
+                let hint =
+                    gtk::Label::new(Some("Turn on to colour the text by the peer who wrote it"));
+                hint.set_wrap(true);
+                hint.set_margin_top(16);
+                hint.add_css_class("dim-label");
+                self.legend.append(&hint);
+            }
+            Some(ref entries) if entries.is_empty() => {
+                let placeholder = gtk::Label::new(Some("No Text"));
+                placeholder.set_margin_top(16);
+                placeholder.add_css_class("dim-label");
+                self.legend.append(&placeholder);
+            }
+            Some(ref entries) => {
+                let total: usize = entries.iter().map(|entry| entry.characters).sum();
+                for entry in entries {
+                    self.legend.append(&self.legend_row(entry, total));
+                }
+            }
+        }
+        *self.entries.borrow_mut() = entries;
+    }
+
+    fn legend_row(&self, entry: &LegendEntry, total: usize) -> gtk::Box {
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        row.set_tooltip_text(Some(&entry.key));
+
+        let swatch = gtk::Label::new(None);
+        swatch.set_markup(&format!(
+            "<span foreground='{}'>●</span>",
+            author_color(&entry.key)
+        ));
+        row.append(&swatch);
+
+        let mut name = entry.name.clone();
+        if entry.key == self.local_node {
+            name.push_str(" (you)");
+        }
+        let name_label = gtk::Label::new(Some(&name));
+        name_label.set_halign(gtk::Align::Start);
+        name_label.set_hexpand(true);
+        name_label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+        row.append(&name_label);
+
+        let share = gtk::Label::new(Some(&format!("{}%", entry.characters * 100 / total.max(1))));
+        share.add_css_class("caption");
+        share.add_css_class("dim-label");
+        row.append(&share);
+
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{Automerge, ObjType, ROOT, transaction::Transactable};
+
+    use super::*;
+
+    fn actor(n: u8) -> ActorId {
+        ActorId::from(vec![n; 16])
+    }
+
+    fn splice(doc: &mut Automerge, n: u8, index: usize, delete: isize, text: &str) -> ObjId {
+        doc.set_actor(actor(n));
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let text_id = match tx.get(ROOT, "content")? {
+                Some((_, text_id)) => text_id,
+                None => tx.put_object(ROOT, "content", ObjType::Text)?,
+            };
+            tx.splice_text(&text_id, index, delete, text)?;
+            Ok(text_id)
+        })
+        .unwrap()
+        .result
+    }
+
+    fn span(start: usize, end: usize, n: u8) -> BlameSpan {
+        BlameSpan {
+            start,
+            end,
+            actor: actor(n),
+        }
+    }
+
+    #[test]
+    fn attributes_characters_to_the_actor_that_inserted_them() {
+        let mut doc = Automerge::new();
+        splice(&mut doc, 1, 0, 0, "Grüße world");
+        splice(&mut doc, 2, 6, 0, "big ");
+        // Deleting doesn't change who wrote the remaining characters
+        let text_id = splice(&mut doc, 2, 0, 1, "");
+
+        assert_eq!(doc.text(&text_id).unwrap(), "rüße big world");
+        assert_eq!(
+            read_blame(&doc, &text_id).unwrap(),
+            [span(0, 5, 1), span(5, 9, 2), span(9, 14, 1)]
+        );
+    }
+
+    #[test]
+    fn reads_empty_texts() {
+        let mut doc = Automerge::new();
+        let text_id = splice(&mut doc, 1, 0, 0, "");
+        assert!(read_blame(&doc, &text_id).unwrap().is_empty());
+    }
+}
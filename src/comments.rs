@@ -0,0 +1,585 @@
+//! Comment threads anchored to ranges of the edited text.
+//!
+//! Threads live in the document under the `comments` map, keyed by a random
+//! ID. Their ranges are stored as automerge cursors, so they follow the text
+//! as peers edit around them:
+//!
+//! ```typescript
+//! type Comments = {
+//!   [id: string]: {
+//!     target: string // The text object the thread belongs to, e.g. "content"
+//!     start: string // Cursor of the first commented character
+//!     end: string // Cursor of the character after the commented range
+//!     resolved: boolean
+//!     messages: { author: string, createdAt: Date, text: string }[]
+//!   }
+//! }
+//! ```
+
+use std::{cell::RefCell, rc::Rc};
+
+use adw::prelude::*;
+use automerge::{
+    AutomergeError, Cursor, CursorPosition, ObjId, ObjType, ROOT, ReadDoc, ScalarValue, Value,
+    transaction::Transactable,
+};
+use gtk::{gio, glib};
+
+use crate::schema::{now_millis, read_string};
+use crate::sync::TextSynchronizer;
+use crate::text_path::TextPath;
+
+pub(crate) const COMMENTS_KEY: &str = "comments";
+
+const HIGHLIGHT_TAG: &str = "comment-thread";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Message {
+    pub(crate) author: String,
+    pub(crate) created_at: i64,
+    pub(crate) text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CommentThread {
+    pub(crate) id: String,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) resolved: bool,
+    pub(crate) messages: Vec<Message>,
+}
+
+/// Reads all comment threads on the text object at `target`, ordered by position.
+///
+/// Threads whose anchors can't be resolved anymore are skipped.
+pub(crate) fn read_threads<R: ReadDoc>(doc: &R, target: &TextPath) -> Vec<CommentThread> {
+    let Ok(text_obj_id) = target.resolve(doc) else {
+        return Vec::new();
+    };
+    let Ok(Some((Value::Object(ObjType::Map), comments_id))) = doc.get(ROOT, COMMENTS_KEY) else {
+        return Vec::new();
+    };
+    let target = target.to_string();
+
+    let mut threads: Vec<_> = doc
+        .keys(&comments_id)
+        .filter_map(|id| {
+            let Ok(Some((Value::Object(ObjType::Map), thread_id))) = doc.get(&comments_id, &id)
+            else {
+                return None;
+            };
+            if read_string(doc, &thread_id, "target")? != target {
+                return None;
+            }
+            let position = |key: &str| {
+                let cursor = Cursor::try_from(read_string(doc, &thread_id, key)?).ok()?;
+                doc.get_cursor_position(&text_obj_id, &cursor, None).ok()
+            };
+            let (start, end) = (position("start")?, position("end")?);
+            let resolved = match doc.get(&thread_id, "resolved") {
+                Ok(Some((value, _))) => value.to_bool().unwrap_or(false),
+                _ => false,
+            };
+            Some(CommentThread {
+                id,
+                start: start.min(end),
+                end: start.max(end),
+                resolved,
+                messages: read_messages(doc, &thread_id),
+            })
+        })
+        .collect();
+    threads.sort_by_key(|thread| (thread.start, thread.end));
+    threads
+}
+
+fn read_messages<R: ReadDoc>(doc: &R, thread_id: &ObjId) -> Vec<Message> {
+    let Ok(Some((Value::Object(ObjType::List), messages_id))) = doc.get(thread_id, "messages")
+    else {
+        return Vec::new();
+    };
+    (0..doc.length(&messages_id))
+        .filter_map(|i| {
+            let Ok(Some((Value::Object(ObjType::Map), message_id))) = doc.get(&messages_id, i)
+            else {
+                return None;
+            };
+            let created_at = match doc.get(&message_id, "createdAt") {
+                Ok(Some((value, _))) => value.to_i64().unwrap_or_default(),
+                _ => 0,
+            };
+            Some(Message {
+                author: read_string(doc, &message_id, "author").unwrap_or_default(),
+                created_at,
+                text: read_string(doc, &message_id, "text")?,
+            })
+        })
+        .collect()
+}
+
+fn push_message<T: Transactable>(
+    tx: &mut T,
+    messages_id: &ObjId,
+    author: &str,
+    text: &str,
+) -> Result<(), AutomergeError> {
+    let index = tx.length(messages_id);
+    let message_id = tx.insert_object(messages_id, index, ObjType::Map)?;
+    tx.put(&message_id, "author", author)?;
+    tx.put(
+        &message_id,
+        "createdAt",
+        ScalarValue::Timestamp(now_millis()),
+    )?;
+    tx.put(&message_id, "text", text)?;
+    Ok(())
+}
+
+/// Starts a new thread on the characters between `start` and `end` of `text_obj_id`.
+pub(crate) fn create_thread<T: Transactable>(
+    tx: &mut T,
+    text_obj_id: &ObjId,
+    target: &TextPath,
+    (start, end): (usize, usize),
+    author: &str,
+    text: &str,
+) -> Result<String, AutomergeError> {
+    let start_cursor = tx.get_cursor(text_obj_id, start, None)?;
+    let end_cursor = if end >= tx.length(text_obj_id) {
+        tx.get_cursor(text_obj_id, CursorPosition::End, None)?
+    } else {
+        tx.get_cursor(text_obj_id, end, None)?
+    };
+
+    let comments_id = comments_obj(tx)?;
+    let id = format!("{:016x}", rand::random::<u64>());
+    let thread_id = tx.put_object(&comments_id, &id, ObjType::Map)?;
+    tx.put(&thread_id, "target", target.to_string())?;
+    tx.put(&thread_id, "start", start_cursor.to_string())?;
+    tx.put(&thread_id, "end", end_cursor.to_string())?;
+    tx.put(&thread_id, "resolved", false)?;
+    let messages_id = tx.put_object(&thread_id, "messages", ObjType::List)?;
+    push_message(tx, &messages_id, author, text)?;
+    Ok(id)
+}
+
+/// The `comments` map. It's created with the document, see [`crate::schema`].
+fn comments_obj<R: ReadDoc>(doc: &R) -> Result<ObjId, AutomergeError> {
+    match doc.get(ROOT, COMMENTS_KEY)? {
+        Some((Value::Object(ObjType::Map), comments_id)) => Ok(comments_id),
+        _ => Err(AutomergeError::InvalidObjId(COMMENTS_KEY.to_string())),
+    }
+}
+
+fn thread_obj<R: ReadDoc>(doc: &R, id: &str) -> Result<ObjId, AutomergeError> {
+    let comments_id = comments_obj(doc)?;
+    match doc.get(&comments_id, id)? {
+        Some((Value::Object(ObjType::Map), thread_id)) => Ok(thread_id),
+        _ => Err(AutomergeError::InvalidObjId(id.to_string())),
+    }
+}
+
+pub(crate) fn reply<T: Transactable>(
+    tx: &mut T,
+    id: &str,
+    author: &str,
+    text: &str,
+) -> Result<(), AutomergeError> {
+    let thread_id = thread_obj(tx, id)?;
+    let messages_id = match tx.get(&thread_id, "messages")? {
+        Some((Value::Object(ObjType::List), messages_id)) => messages_id,
+        _ => tx.put_object(&thread_id, "messages", ObjType::List)?,
+    };
+    push_message(tx, &messages_id, author, text)
+}
+
+pub(crate) fn set_resolved<T: Transactable>(
+    tx: &mut T,
+    id: &str,
+    resolved: bool,
+) -> Result<(), AutomergeError> {
+    let thread_id = thread_obj(tx, id)?;
+    tx.put(&thread_id, "resolved", resolved)
+}
+
+/// The comment threads page of the side pane, plus the highlights in the editor.
+#[derive(Clone)]
+pub(crate) struct CommentsPanel {
+    pub(crate) container: gtk::Box,
+    list: gtk::Box,
+    sync: TextSynchronizer,
+    view: sourceview5::View,
+    author: String,
+    threads: Rc<RefCell<Vec<CommentThread>>>,
+}
+
+impl CommentsPanel {
+    pub(crate) fn new(sync: &TextSynchronizer, view: &sourceview5::View, author: String) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        container.set_margin_top(8);
+        container.set_margin_bottom(8);
+        container.set_margin_start(8);
+        container.set_margin_end(8);
+
+        let hint = gtk::Label::new(Some("Select text and press Ctrl+Alt+M to comment"));
+        hint.add_css_class("dim-label");
+        hint.add_css_class("caption");
+        hint.set_wrap(true);
+        container.append(&hint);
+
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        let scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&list)
+            .build();
+        container.append(&scroll);
+
+        let buffer = sync.buffer();
+        if buffer.tag_table().lookup(HIGHLIGHT_TAG).is_none() {
+            let tag = gtk::TextTag::builder()
+                .name(HIGHLIGHT_TAG)
+                .background("rgba(246, 211, 45, 0.35)")
+                .underline(gtk::pango::Underline::Error)
+                .build();
+            buffer.tag_table().add(&tag);
+        }
+
+        let panel = Self {
+            container,
+            list,
+            sync: sync.clone(),
+            view: view.clone(),
+            author,
+            threads: Rc::new(RefCell::new(Vec::new())),
+        };
+        panel.install_action();
+
+        sync.connect_reconciled({
+            let panel = panel.clone();
+            move || panel.refresh()
+        });
+        panel.refresh();
+        panel
+    }
+
+    fn install_action(&self) {
+        let action = gio::SimpleAction::new("add", None);
+        action.set_enabled(!self.sync.is_read_only());
+        action.connect_activate({
+            let panel = self.clone();
+            move |_, _| panel.prompt_new_thread()
+        });
+        let actions = gio::SimpleActionGroup::new();
+        actions.add_action(&action);
+        self.view.insert_action_group("comments", Some(&actions));
+
+        let shortcuts = gtk::ShortcutController::new();
+        shortcuts.add_shortcut(gtk::Shortcut::new(
+            gtk::ShortcutTrigger::parse_string("<Control><Alt>m"),
+            Some(gtk::NamedAction::new("comments.add")),
+        ));
+        self.view.add_controller(shortcuts);
+
+        if let Some(menu) = self
+            .view
+            .extra_menu()
+            .and_then(|menu| menu.downcast::<gio::Menu>().ok())
+        {
+            menu.append(Some("Add Comment…"), Some("comments.add"));
+        }
+    }
+
+    fn prompt_new_thread(&self) {
+        let Some((start, end)) = self.sync.buffer().selection_bounds() else {
+            return;
+        };
+        let range = (start.offset() as usize, end.offset() as usize);
+
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text(Some("Comment"));
+        entry.set_activates_default(true);
+
+        let dialog = adw::AlertDialog::new(Some("Add Comment"), None);
+        dialog.add_responses(&[("cancel", "Cancel"), ("add", "Comment")]);
+        dialog.set_response_appearance("add", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("add"));
+        dialog.set_close_response("cancel");
+        dialog.set_extra_child(Some(&entry));
+
+        let panel = self.clone();
+        dialog.connect_response(None, move |_, response| {
+            let text = entry.text();
+            if response != "add" || text.trim().is_empty() {
+                return;
+            }
+            let target = panel.sync.path();
+            let result = panel.sync.change_text("Add comment", |tx, text_obj_id| {
+                create_thread(tx, text_obj_id, &target, range, &panel.author, text.trim())
+                    .map(|_| ())
+            });
+            if let Err(e) = result {
+                tracing::warn!(%e, "failed to add comment");
+            }
+        });
+        dialog.present(Some(&self.view));
+    }
+
+    /// Makes a change to a thread that doesn't touch the text itself.
+    fn change_thread(
+        &self,
+        message: &str,
+        f: impl FnOnce(&mut automerge::transaction::Transaction<'_>) -> Result<(), AutomergeError>,
+    ) {
+        if let Err(e) = self.sync.change_document(message, f) {
+            tracing::warn!(%e, "failed to update comment thread");
+        }
+    }
+
+    pub(crate) fn refresh(&self) {
+        let target = self.sync.path();
+        let threads = self
+            .sync
+            .handle()
+            .with_document(|doc| read_threads(doc, &target));
+
+        // Highlight open threads in the editor
+        let buffer = self.sync.buffer();
+        let (start, end) = buffer.bounds();
+        buffer.remove_tag_by_name(HIGHLIGHT_TAG, &start, &end);
+        for thread in threads.iter().filter(|thread| !thread.resolved) {
+            let start = buffer.iter_at_offset(thread.start as i32);
+            let end = buffer.iter_at_offset(thread.end as i32);
+            buffer.apply_tag_by_name(HIGHLIGHT_TAG, &start, &end);
+        }
+
+        // Don't rebuild the list while someone types a reply
+        if *self.threads.borrow() == threads {
+            return;
+        }
+
+        while let Some(child) = self.list.first_child() {
+            self.list.remove(&child);
+        }
+        if threads.is_empty() {
+            let placeholder = gtk::Label::new(Some("No Comments"));
+            placeholder.set_margin_top(16);
+            placeholder.add_css_class("dim-label");
+            self.list.append(&placeholder);
+        }
+        for thread in &threads {
+            self.list.append(&self.thread_card(thread));
+        }
+        *self.threads.borrow_mut() = threads;
+    }
+
+    fn thread_card(&self, thread: &CommentThread) -> gtk::Box {
+        let card = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        card.add_css_class("card");
+        if thread.resolved {
+            card.add_css_class("dim-label");
+        }
+
+        // The commented text, click to jump there
+        let quote: String = {
+            let buffer = self.sync.buffer();
+            let start = buffer.iter_at_offset(thread.start as i32);
+            let end = buffer.iter_at_offset(thread.end as i32);
+            buffer.text(&start, &end, false).into()
+        };
+        let quote_button = gtk::Button::with_label(&format!("“{}”", quote.trim()));
+        quote_button.set_has_frame(false);
+        if let Some(label) = quote_button
+            .child()
+            .and_then(|child| child.downcast::<gtk::Label>().ok())
+        {
+            label.set_ellipsize(gtk::pango::EllipsizeMode::End);
+            label.set_xalign(0.0);
+            label.add_css_class("caption");
+        }
+        quote_button.connect_clicked({
+            let panel = self.clone();
+            let (start, end) = (thread.start as i32, thread.end as i32);
+            move |_| {
+                let buffer = panel.sync.buffer();
+                let mut start = buffer.iter_at_offset(start);
+                let end = buffer.iter_at_offset(end);
+                buffer.select_range(&start, &end);
+                panel.view.scroll_to_iter(&mut start, 0.1, false, 0.0, 0.0);
+                panel.view.grab_focus();
+            }
+        });
+        card.append(&quote_button);
+
+        for message in &thread.messages {
+            let created_at = glib::DateTime::from_unix_local(message.created_at / 1000)
+                .and_then(|date| date.format("%x %H:%M"))
+                .map(|date| date.to_string())
+                .unwrap_or_default();
+            let header = gtk::Label::new(Some(&format!(
+                "{}... · {created_at}",
+                message.author.chars().take(8).collect::<String>()
+            )));
+            header.set_halign(gtk::Align::Start);
+            header.add_css_class("heading");
+            card.append(&header);
+
+            let text = gtk::Label::new(Some(&message.text));
+            text.set_halign(gtk::Align::Start);
+            text.set_wrap(true);
+            text.set_xalign(0.0);
+            text.set_selectable(true);
+            card.append(&text);
+        }
+
+        let actions = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let reply_entry = gtk::Entry::new();
+        reply_entry.set_placeholder_text(Some("Reply…"));
+        reply_entry.set_hexpand(true);
+        reply_entry.connect_activate({
+            let panel = self.clone();
+            let id = thread.id.clone();
+            move |entry| {
+                let text = entry.text();
+                if text.trim().is_empty() {
+                    return;
+                }
+                panel.change_thread("Reply to comment", |tx| {
+                    reply(tx, &id, &panel.author, text.trim())
+                });
+                entry.set_text("");
+            }
+        });
+        actions.append(&reply_entry);
+
+        let resolve_button =
+            gtk::Button::with_label(if thread.resolved { "Reopen" } else { "Resolve" });
+        resolve_button.connect_clicked({
+            let panel = self.clone();
+            let id = thread.id.clone();
+            let resolved = !thread.resolved;
+            move |_| {
+                let message = if resolved {
+                    "Resolve comment"
+                } else {
+                    "Reopen comment"
+                };
+                panel.change_thread(message, |tx| set_resolved(tx, &id, resolved));
+            }
+        });
+        actions.append(&resolve_button);
+        actions.set_sensitive(!self.sync.is_read_only());
+        card.append(&actions);
+
+        card
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{Automerge, transaction::Transaction};
+
+    use super::*;
+    use crate::schema;
+
+    fn document(text: &str) -> (Automerge, TextPath) {
+        let node_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        let mut doc = Automerge::new();
+        doc.transact(|tx| schema::initialize(tx, "Notes", text, node_id))
+            .unwrap();
+        (doc, "content".parse().unwrap())
+    }
+
+    fn change<T>(
+        doc: &mut Automerge,
+        path: &TextPath,
+        f: impl FnOnce(&mut Transaction<'_>, &ObjId) -> Result<T, AutomergeError>,
+    ) -> T {
+        let text_id = path.resolve(doc).unwrap();
+        doc.transact(|tx| f(tx, &text_id)).unwrap().result
+    }
+
+    #[test]
+    fn creates_and_reads_threads() {
+        let (mut doc, path) = document("Hello brave new world");
+        let later = change(&mut doc, &path, |tx, text_id| {
+            create_thread(tx, text_id, &path, (16, 21), "bob", "Too big?")
+        });
+        let first = change(&mut doc, &path, |tx, text_id| {
+            create_thread(tx, text_id, &path, (6, 11), "alice", "Really?")
+        });
+
+        let threads = read_threads(&doc, &path);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].id, first);
+        assert_eq!((threads[0].start, threads[0].end), (6, 11));
+        assert!(!threads[0].resolved);
+        assert_eq!(threads[0].messages.len(), 1);
+        assert_eq!(threads[0].messages[0].author, "alice");
+        assert_eq!(threads[0].messages[0].text, "Really?");
+        assert_eq!(threads[1].id, later);
+        assert_eq!((threads[1].start, threads[1].end), (16, 21));
+
+        // Threads on other text objects aren't listed
+        let other: TextPath = "notes".parse().unwrap();
+        assert!(read_threads(&doc, &other).is_empty());
+    }
+
+    #[test]
+    fn doesnt_create_the_comments_map() {
+        let mut doc = Automerge::new();
+        let path = TextPath::default();
+        let result = doc.transact::<_, _, AutomergeError>(|tx| {
+            let text_id = tx.put_object(ROOT, "content", ObjType::Text)?;
+            tx.splice_text(&text_id, 0, 0, "Hello")?;
+            create_thread(tx, &text_id, &path, (0, 5), "alice", "Hi?")
+        });
+        assert!(result.is_err());
+        assert!(doc.get(ROOT, COMMENTS_KEY).unwrap().is_none());
+    }
+
+    #[test]
+    fn replies_and_resolves() {
+        let (mut doc, path) = document("Hello world");
+        let id = change(&mut doc, &path, |tx, text_id| {
+            create_thread(tx, text_id, &path, (0, 5), "alice", "Hi?")
+        });
+        doc.transact(|tx| reply(tx, &id, "bob", "Hello!")).unwrap();
+        doc.transact(|tx| set_resolved(tx, &id, true)).unwrap();
+
+        let threads = read_threads(&doc, &path);
+        let texts: Vec<&str> = threads[0]
+            .messages
+            .iter()
+            .map(|message| message.text.as_str())
+            .collect();
+        assert_eq!(texts, ["Hi?", "Hello!"]);
+        assert!(threads[0].resolved);
+
+        assert!(doc.transact(|tx| reply(tx, "missing", "bob", "?")).is_err());
+    }
+
+    #[test]
+    fn anchors_follow_edits() {
+        let (mut doc, path) = document("Hello world");
+        change(&mut doc, &path, |tx, text_id| {
+            create_thread(tx, text_id, &path, (6, 11), "alice", "Which one?")
+        });
+
+        // Text inserted before the range moves it, text inside grows it
+        change(&mut doc, &path, |tx, text_id| {
+            tx.splice_text(text_id, 0, 0, "Oh, ")?;
+            tx.splice_text(text_id, 12, 0, "-")
+        });
+        assert_eq!(path.read(&doc).unwrap(), "Oh, Hello wo-rld");
+        let thread = &read_threads(&doc, &path)[0];
+        assert_eq!((thread.start, thread.end), (10, 16));
+
+        // A range up to the end of the text keeps up with text added there
+        change(&mut doc, &path, |tx, text_id| {
+            tx.splice_text(text_id, 16, 0, "!")
+        });
+        let thread = &read_threads(&doc, &path)[0];
+        assert_eq!((thread.start, thread.end), (10, 17));
+    }
+}
@@ -1,36 +1,80 @@
-use std::str::FromStr;
-
-use crate::app_state::AppState;
+use std::{pin::pin, str::FromStr, time::Duration};
+
+use crate::app_state::{AppState, Recovery};
+use crate::authors;
+use crate::blame::BlamePanel;
+use crate::comments::CommentsPanel;
+use crate::drafts::{self, DraftsPanel};
+use crate::error::DocumentError;
+use crate::import::Import;
+use crate::notifications::Notification;
+use crate::outline::OutlinePanel;
+use crate::preferences;
+use crate::schema::{self, Metadata};
+use crate::snapshots::SnapshotsPanel;
 use crate::sync::TextSynchronizer;
 use anyhow::Context as _;
-use automerge::transaction::Transactable;
-use automerge::{Automerge, AutomergeError, ObjType, ROOT, ReadDoc};
+use futures::{
+    StreamExt,
+    future::{self, Either},
+};
 use gtk::glib;
 use iroh::Watcher;
 use iroh_automerge_repo::IrohRepo;
 use samod::{DocHandle, PeerId};
 use sourceview5::prelude::*;
 
+/// Where samod keeps its documents, and glyphcaster its local state.
+pub(crate) const DATA_DIR: &str = "./data";
+
+/// How long to wait for a relay server before assuming there's no network.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait for the node from the connection string.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct DocumentLoader {
     app_state: AppState,
+    /// The document repo and the drafts repo, kept open across retries so that only one
+    /// samod ever uses the storage.
+    repos: Option<(samod::Samod, samod::Samod)>,
+    /// What the current attempt started, shut down again if it fails.
+    router: Option<iroh::protocol::Router>,
+    sync_task: Option<tokio::task::AbortHandle>,
+}
+
+/// Everything `load_document` sets up that the editor needs afterwards.
+pub struct LoadedDocument {
+    pub buffer: sourceview5::Buffer,
+    pub handle: DocHandle,
+    pub router: iroh::protocol::Router,
+    pub samod: samod::Samod,
+    /// The private repo for drafts.
+    pub drafts: samod::Samod,
+    /// Set if the document doesn't have the expected structure.
+    pub structure_error: Option<DocumentError>,
 }
 
 impl DocumentLoader {
     pub fn new(app_state: AppState) -> Self {
-        Self { app_state }
+        Self {
+            app_state,
+            repos: None,
+            router: None,
+            sync_task: None,
+        }
     }
 
-    pub async fn load_document(
-        &mut self,
-    ) -> Result<(sourceview5::Buffer, DocHandle, iroh::protocol::Router), Box<dyn std::error::Error>>
-    {
+    pub async fn load_document(&mut self) -> anyhow::Result<LoadedDocument> {
         let rt = &self.app_state.rt;
         let iroh_secret = self.app_state.iroh_secret.clone();
+        let offline = self.app_state.offline;
+        // Read-only links leave the document as it is
+        let read_only = self.app_state.read_only();
 
         self.update_progress("Initializing iroh", 0.1).await;
 
         let endpoint = rt
-            .spawn(async {
+            .spawn(async move {
                 let secret_key =
                     iroh_secret.and_then(|key_hex| match iroh::SecretKey::from_str(&key_hex) {
                         Ok(key) => Some(key),
@@ -57,32 +101,54 @@ impl DocumentLoader {
                     }
                 };
 
-                let endpoint = iroh::Endpoint::builder()
-                    .discovery_n0()
-                    .secret_key(secret_key)
+                // Offline, peers on the local network can still connect directly
+                let builder = iroh::Endpoint::builder().secret_key(secret_key);
+                let builder = if offline {
+                    builder.relay_mode(iroh::RelayMode::Disabled)
+                } else {
+                    builder.discovery_n0()
+                };
+                let endpoint = builder
                     .bind()
-                    .await?;
-
-                endpoint.home_relay().initialized().await;
+                    .await
+                    .context("failed to start the iroh endpoint")?;
+
+                if !offline {
+                    let _ =
+                        tokio::time::timeout(RELAY_TIMEOUT, endpoint.home_relay().initialized())
+                            .await
+                            .context("couldn't reach an iroh relay server, is the network down?")?;
+                }
 
                 anyhow::Ok(endpoint)
             })
             .await??;
+        // Retries reuse the key, the repos are already open under its node ID
+        self.app_state.iroh_secret =
+            Some(data_encoding::HEXLOWER.encode(&endpoint.secret_key().to_bytes()));
 
         self.update_progress("Initializing samod", 0.2).await;
 
-        let samod = rt
-            .spawn({
-                let endpoint = endpoint.clone();
-                async move {
-                    samod::Samod::build_tokio()
-                        .with_peer_id(PeerId::from_string(endpoint.node_id().to_string()))
-                        .with_storage(samod::storage::TokioFilesystemStorage::new("./data"))
-                        .load()
-                        .await
-                }
-            })
-            .await?;
+        let (samod, drafts) = match self.repos.clone() {
+            Some(repos) => repos,
+            None => {
+                let samod = rt
+                    .spawn({
+                        let endpoint = endpoint.clone();
+                        async move {
+                            samod::Samod::build_tokio()
+                                .with_peer_id(PeerId::from_string(endpoint.node_id().to_string()))
+                                .with_storage(samod::storage::TokioFilesystemStorage::new(DATA_DIR))
+                                .load()
+                                .await
+                        }
+                    })
+                    .await?;
+                let drafts = rt.spawn(drafts::open_repo()).await?;
+                self.repos = Some((samod.clone(), drafts.clone()));
+                (samod, drafts)
+            }
+        };
 
         self.update_progress("Starting to serve over iroh", 0.3)
             .await;
@@ -100,16 +166,33 @@ impl DocumentLoader {
                 }
             })
             .await?;
+        self.router = Some(router.clone());
 
-        if let Some(node_id) = self.app_state.node_id.clone() {
+        if let Some(invite) = self.app_state.invite.clone().filter(|_| !offline) {
+            let node_id = invite.node_id;
             self.update_progress("Connecting to remote node", 0.4).await;
 
-            tracing::info!(%node_id, "Starting continuous sync");
-            rt.spawn(async move { proto.sync_with(node_id).await });
+            // Addresses from the connection string save the discovery round trip
+            if !invite.direct_addresses.is_empty() {
+                endpoint.add_node_addr(iroh::NodeAddr::from_parts(
+                    node_id,
+                    None,
+                    invite.direct_addresses,
+                ))?;
+            }
 
-            samod
-                .when_connected(PeerId::from_string(node_id.to_string()))
-                .await?;
+            tracing::info!(%node_id, "Starting continuous sync");
+            let sync_task = rt.spawn(async move { proto.sync_with(node_id).await });
+            self.sync_task = Some(sync_task.abort_handle());
+
+            let connected = pin!(samod.when_connected(PeerId::from_string(node_id.to_string())));
+            match future::select(connected, glib::timeout_future(CONNECT_TIMEOUT)).await {
+                Either::Left((connected, _)) => connected?,
+                Either::Right(_) => anyhow::bail!(
+                    "couldn't connect to {node_id} within {} seconds, is it running?",
+                    CONNECT_TIMEOUT.as_secs()
+                ),
+            }
 
             tracing::info!(%node_id, "Connected");
         }
@@ -117,31 +200,53 @@ impl DocumentLoader {
         self.update_progress("Loading document...", 0.5).await;
 
         let handle = if let Some(doc_id) = self.app_state.document_id.clone() {
-            samod
+            let handle = samod
                 .find(doc_id.clone())
                 .await?
-                .context(format!("couldn't find document with document ID {doc_id}"))?
+                .context(format!("couldn't find document with document ID {doc_id}"))?;
+            let migrated = if read_only {
+                Ok(false)
+            } else {
+                handle.with_document(|doc| schema::migrate(doc))
+            };
+            if let Err(e) = migrated {
+                tracing::warn!(%e, "failed to migrate document");
+            }
+            handle
         } else {
-            let mut doc = Automerge::new();
-            doc.transact::<_, _, AutomergeError>(|tx| {
-                let text_id = tx.put_object(ROOT, "content", ObjType::Text)?;
-                tx.splice_text(&text_id, 0, 0, "# Untitled")?;
-                Ok(())
-            })
-            .unwrap();
-            samod.create(doc).await?
+            // Keep the import around until the document exists, in case of a retry
+            let doc = match &self.app_state.import {
+                Some(Import::Markdown(file)) => {
+                    schema::new_document(&file.title, &file.content, endpoint.node_id())?
+                }
+                Some(Import::Automerge(doc)) => doc.clone(),
+                None => schema::new_document("Untitled", "# Untitled", endpoint.node_id())?,
+            };
+            let handle = samod.create(doc).await?;
+            self.app_state.import = None;
+            handle
         };
 
-        let content = handle.with_document(|doc| {
-            let (value, id) = doc.get(automerge::ROOT, "content").unwrap().unwrap();
-            match value {
-                automerge::Value::Object(automerge::ObjType::Text) => {
-                    let text = doc.text(id).unwrap();
-                    text.to_string()
+        // Documents with an unexpected shape are still opened, just read-only
+        let text_path = self.app_state.text_path.clone();
+        let (content, structure_error) = match handle.with_document(|doc| text_path.read(doc)) {
+            Ok(content) => {
+                // Let peers attribute our changes to this node in the history
+                let registered = if read_only {
+                    Ok(())
+                } else {
+                    handle.with_document(|doc| authors::register(doc, endpoint.node_id()))
+                };
+                if let Err(e) = registered {
+                    tracing::warn!(%e, "failed to register author");
                 }
-                _ => panic!("content should be a text object"),
+                (content, None)
             }
-        });
+            Err(e) => {
+                tracing::warn!(%e, "document has unexpected structure");
+                (String::new(), Some(e))
+            }
+        };
 
         println!(
             "Connect using automerge:{} {}",
@@ -157,7 +262,29 @@ impl DocumentLoader {
         self.update_progress("Ready!", 1.0).await;
         glib::timeout_future(std::time::Duration::from_millis(200)).await;
 
-        Ok((buffer, handle, router))
+        // The editor takes over from here
+        self.router = None;
+        self.sync_task = None;
+
+        Ok(LoadedDocument {
+            buffer,
+            handle,
+            router,
+            samod,
+            drafts,
+            structure_error,
+        })
+    }
+
+    /// Shuts down the router, and with it the endpoint, and the sync task of a
+    /// failed attempt, so that the next one doesn't run next to them.
+    async fn shut_down_attempt(&mut self) {
+        if let Some(sync_task) = self.sync_task.take() {
+            sync_task.abort();
+        }
+        if let Some(router) = self.router.take() {
+            let _ = router.shutdown().await;
+        }
     }
 
     async fn update_progress(&self, message: &str, progress: f64) {
@@ -171,7 +298,7 @@ impl DocumentLoader {
     async fn create_markdown_buffer(
         &self,
         initial_content: String,
-    ) -> Result<sourceview5::Buffer, Box<dyn std::error::Error>> {
+    ) -> anyhow::Result<sourceview5::Buffer> {
         let buffer = sourceview5::Buffer::new(None);
         buffer.set_highlight_syntax(true);
 
@@ -182,15 +309,8 @@ impl DocumentLoader {
             eprintln!("Warning: Markdown language definition not found");
         }
 
-        // Set up syntax highlighting theme
-        if let Some(ref scheme) = sourceview5::StyleSchemeManager::new().scheme("solarized-light") {
-            buffer.set_style_scheme(Some(scheme));
-        } else {
-            // Fallback to default theme
-            if let Some(ref scheme) = sourceview5::StyleSchemeManager::new().scheme("classic") {
-                buffer.set_style_scheme(Some(scheme));
-            }
-        }
+        // Set up syntax highlighting theme, falling back to the default one
+        buffer.set_style_scheme(preferences::style_scheme().as_ref());
 
         buffer.set_text(&initial_content);
 
@@ -201,28 +321,131 @@ impl DocumentLoader {
         let mut loader = DocumentLoader::new(app_state);
 
         glib::MainContext::default().spawn_local(async move {
-            let (buffer, doc_handle, router) = match loader.load_document().await {
-                Err(e) => {
-                    loader.app_state.show_error(&e.to_string());
-                    return;
+            // Failing to load leaves it to the user whether to try again
+            let LoadedDocument {
+                buffer,
+                handle: doc_handle,
+                router,
+                samod,
+                drafts,
+                structure_error,
+            } = loop {
+                match loader.load_document().await {
+                    Ok(loaded) => break loaded,
+                    Err(e) => {
+                        tracing::error!(e = format!("{e:#}"), "failed to load document");
+                        loader.shut_down_attempt().await;
+                        match loader.app_state.show_error(&e).await {
+                            Recovery::Retry => {}
+                            Recovery::OpenOffline => loader.app_state.offline = true,
+                        }
+                        loader.app_state.show_loading();
+                    }
                 }
-                Ok((buffer, doc_handle, router)) => (buffer, doc_handle, router),
             };
 
             let doc_id = doc_handle.document_id();
 
             loader
                 .app_state
-                .update_document_id(&doc_id, router.endpoint().node_id());
+                .update_document_id(&doc_id, router.endpoint());
             loader.app_state.setup_editor(&buffer);
             loader.app_state.show_editor();
+            if loader.app_state.offline {
+                loader
+                    .app_state
+                    .notifier
+                    .notify(Notification::WorkingOffline);
+            }
 
             // Set up bidirectional synchronization
-            let sync = TextSynchronizer::new(doc_handle, buffer);
+            let on_document_error = loader.app_state.document_error_handler(&doc_handle);
+            let sync = TextSynchronizer::new(
+                doc_handle.clone(),
+                buffer,
+                loader.app_state.text_path.clone(),
+            );
+            sync.set_read_only(loader.app_state.read_only());
+            sync.connect_error(on_document_error);
+            if let Some(e) = structure_error {
+                sync.fail(e);
+            }
             sync.start();
+            loader.app_state.setup_text_picker(&sync, &doc_handle);
+            loader.app_state.setup_formatting(&sync);
+            loader.app_state.setup_find(&sync);
+            loader.app_state.setup_history(&sync);
+            loader.app_state.setup_export(&sync);
+            loader.app_state.setup_linked_file(&sync);
+            loader.app_state.setup_git(&sync);
+            loader.app_state.setup_automerge_files(&sync);
+            let local_node = router.endpoint().node_id().to_string();
+            let outline = if let Some(view) = loader.app_state.editor_view.clone() {
+                let outline = OutlinePanel::new(&sync, &view, local_node.clone());
+                loader
+                    .app_state
+                    .add_side_page("outline", "Outline", &outline.container);
+
+                let comments = CommentsPanel::new(&sync, &view, local_node.clone());
+                loader
+                    .app_state
+                    .add_side_page("comments", "Comments", &comments.container);
+
+                let blame = BlamePanel::new(&sync, &view, local_node.clone());
+                loader
+                    .app_state
+                    .add_side_page("authors", "Authors", &blame.container);
+                Some(outline)
+            } else {
+                None
+            };
+            let snapshots = SnapshotsPanel::new(&sync, local_node);
+            loader
+                .app_state
+                .add_side_page("snapshots", "Snapshots", &snapshots.container);
+            let drafts_panel = DraftsPanel::new(&sync, &drafts, router.endpoint().node_id());
+            loader
+                .app_state
+                .add_side_page("drafts", "Drafts", &drafts_panel.container);
+            loader
+                .app_state
+                .setup_import(&samod, router.endpoint().node_id());
+
+            // Keep the title in the header bar up to date as peers edit the metadata
+            {
+                let update_title = loader.app_state.title_updater();
+                let handle = doc_handle.clone();
+                let sync = sync.clone();
+                glib::spawn_future_local(async move {
+                    let mut changes = handle.changes();
+                    loop {
+                        let path = sync.path();
+                        let (metadata, content) = handle.with_document(|doc| {
+                            (Metadata::read(doc), path.read(doc).unwrap_or_default())
+                        });
+                        update_title(&metadata, &content);
+                        if changes.next().await.is_none() {
+                            break;
+                        }
+                    }
+                });
+            }
 
-            // Keep polling remote infos until window is closed
+            // Don't lose edits that are still waiting to be committed
             let window = loader.app_state.window.clone();
+            window.connect_close_request({
+                let sync = sync.clone();
+                move |_| {
+                    sync.flush();
+                    // Other peers shouldn't show us in the outline anymore
+                    if let Some(ref outline) = outline {
+                        outline.clear_presence();
+                    }
+                    glib::Propagation::Proceed
+                }
+            });
+
+            // Keep polling remote infos until window is closed
             loop {
                 // Check if window is still visible/mapped
                 if !window.is_visible() {
@@ -0,0 +1,558 @@
+//! Drafts: private forks of a document that get merged back when they're done.
+//!
+//! A draft is a fork of the document with its own document ID, kept in a
+//! separate samod repo that isn't connected to any peers. Its ID is recorded
+//! locally in `drafts.json` next to the drafts. Peers don't see any of the
+//! draft's edits until it's merged back into the original.
+
+use std::{path::PathBuf, rc::Rc, str::FromStr};
+
+use adw::prelude::*;
+use automerge::{Automerge, AutomergeError, ChangeHash};
+use gtk::glib;
+use samod::{DocHandle, DocumentId};
+use serde_json::{Map, Value, json};
+use sourceview5::prelude::*;
+
+use crate::authors;
+use crate::error::DocumentError;
+use crate::fs::write_atomically;
+use crate::history::{format_time, show_diff};
+use crate::preferences;
+use crate::schema;
+use crate::sync::TextSynchronizer;
+use crate::text_path::TextPath;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Draft {
+    pub(crate) id: DocumentId,
+    pub(crate) original: DocumentId,
+    pub(crate) name: String,
+    /// Milliseconds since the unix epoch.
+    pub(crate) created_at: i64,
+}
+
+/// Where the repo for drafts keeps them, apart from the synced documents.
+pub(crate) const DRAFTS_DIR: &str = "./drafts";
+
+/// Opens the repo for drafts. It's never connected to peers, so they can't
+/// request drafts from it.
+pub(crate) async fn open_repo() -> samod::Samod {
+    samod::Samod::build_tokio()
+        .with_storage(samod::storage::TokioFilesystemStorage::new(DRAFTS_DIR))
+        .load()
+        .await
+}
+
+fn registry_path() -> PathBuf {
+    PathBuf::from(DRAFTS_DIR).join("drafts.json")
+}
+
+/// The local list of drafts, keyed by the draft's document ID.
+fn read_registry() -> Map<String, Value> {
+    std::fs::read_to_string(registry_path())
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn write_registry(registry: &Map<String, Value>) -> anyhow::Result<()> {
+    std::fs::create_dir_all(DRAFTS_DIR)
+        .with_context(|| format!("failed to create {DRAFTS_DIR}"))?;
+    write_atomically(&registry_path(), serde_json::to_string_pretty(registry)?)
+}
+
+/// Lists the drafts forked from `original` on this machine, oldest first.
+pub(crate) fn read_drafts(original: &DocumentId) -> Vec<Draft> {
+    let original_id = original.to_string();
+    let mut drafts: Vec<Draft> = read_registry()
+        .into_iter()
+        .filter_map(|(id, entry)| {
+            if entry.get("original")?.as_str()? != original_id {
+                return None;
+            }
+            Some(Draft {
+                id: DocumentId::from_str(&id).ok()?,
+                original: original.clone(),
+                name: entry.get("name")?.as_str()?.to_string(),
+                created_at: entry.get("createdAt")?.as_i64().unwrap_or_default(),
+            })
+        })
+        .collect();
+    drafts.sort_by_key(|draft| draft.created_at);
+    drafts
+}
+
+pub(crate) fn add_draft(draft: &Draft) -> anyhow::Result<()> {
+    let mut registry = read_registry();
+    registry.insert(
+        draft.id.to_string(),
+        json!({
+            "original": draft.original.to_string(),
+            "name": draft.name,
+            "createdAt": draft.created_at,
+        }),
+    );
+    write_registry(&registry)
+}
+
+/// Forgets about a draft. Its document stays in the drafts repo's storage.
+pub(crate) fn remove_draft(id: &DocumentId) -> anyhow::Result<()> {
+    let mut registry = read_registry();
+    registry.remove(&id.to_string());
+    write_registry(&registry)
+}
+
+/// Forks the document as it was at `heads`, with changes attributed to `node_id`.
+pub(crate) fn fork(
+    doc: &Automerge,
+    heads: &[ChangeHash],
+    node_id: iroh::NodeId,
+) -> Result<Automerge, AutomergeError> {
+    let mut draft = doc.fork_at(heads)?;
+    authors::register(&mut draft, node_id)?;
+    Ok(draft)
+}
+
+/// Returns the text at `path` in the original before and after merging the draft,
+/// without changing the original.
+pub(crate) fn preview_merge(
+    original: &Automerge,
+    draft: &Automerge,
+    path: &TextPath,
+) -> Result<(String, String), DocumentError> {
+    let before = path.read(original)?;
+    let mut merged = original.clone();
+    merged.merge(&mut draft.clone())?;
+    Ok((before, path.read(&merged)?))
+}
+
+/// Merges all changes of the draft into the original document.
+pub(crate) fn merge(original: &DocHandle, draft: &DocHandle) -> Result<(), AutomergeError> {
+    let mut draft_doc = draft.with_document(|doc| doc.clone());
+    original.with_document(|doc| doc.merge(&mut draft_doc))?;
+    Ok(())
+}
+
+/// The drafts page of the side pane.
+#[derive(Clone)]
+pub(crate) struct DraftsPanel {
+    pub(crate) container: gtk::Box,
+    list: gtk::Box,
+    sync: TextSynchronizer,
+    /// The private repo drafts are created in.
+    drafts: samod::Samod,
+    node_id: iroh::NodeId,
+}
+
+impl DraftsPanel {
+    pub(crate) fn new(
+        sync: &TextSynchronizer,
+        drafts: &samod::Samod,
+        node_id: iroh::NodeId,
+    ) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        container.set_margin_top(8);
+        container.set_margin_bottom(8);
+        container.set_margin_start(8);
+        container.set_margin_end(8);
+
+        let new_button = gtk::Button::with_label("Start Draft…");
+        new_button.set_tooltip_text(Some(
+            "Fork the document to edit it privately and merge it back later",
+        ));
+        container.append(&new_button);
+
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        let scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&list)
+            .build();
+        container.append(&scroll);
+
+        let panel = Self {
+            container,
+            list,
+            sync: sync.clone(),
+            drafts: drafts.clone(),
+            node_id,
+        };
+        new_button.connect_clicked({
+            let panel = panel.clone();
+            move |_| panel.prompt_new_draft()
+        });
+        panel.refresh();
+        panel
+    }
+
+    fn window(&self) -> Option<gtk::Window> {
+        self.container.root().and_downcast::<gtk::Window>()
+    }
+
+    /// Drafts are only recorded locally, so the list only changes through this panel.
+    pub(crate) fn refresh(&self) {
+        while let Some(child) = self.list.first_child() {
+            self.list.remove(&child);
+        }
+        let drafts = read_drafts(&self.sync.handle().document_id());
+        if drafts.is_empty() {
+            let placeholder = gtk::Label::new(Some("No Drafts"));
+            placeholder.set_margin_top(16);
+            placeholder.add_css_class("dim-label");
+            self.list.append(&placeholder);
+        }
+        for draft in drafts.iter().rev() {
+            self.list.append(&self.draft_card(draft));
+        }
+    }
+
+    fn draft_card(&self, draft: &Draft) -> gtk::Box {
+        let card = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        card.add_css_class("card");
+
+        let name = gtk::Label::new(Some(&draft.name));
+        name.set_halign(gtk::Align::Start);
+        name.set_wrap(true);
+        name.set_xalign(0.0);
+        name.add_css_class("heading");
+        card.append(&name);
+
+        let details = gtk::Label::new(Some(&format!(
+            "Started {}",
+            format_time(draft.created_at / 1000)
+        )));
+        details.set_halign(gtk::Align::Start);
+        details.add_css_class("caption");
+        details.add_css_class("dim-label");
+        card.append(&details);
+
+        let actions = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+        let open_button = gtk::Button::with_label("Open");
+        open_button.connect_clicked({
+            let panel = self.clone();
+            let draft = draft.clone();
+            move |_| {
+                let panel = panel.clone();
+                let draft = draft.clone();
+                glib::spawn_future_local(async move { panel.open(draft).await });
+            }
+        });
+        actions.append(&open_button);
+
+        let discard_button = gtk::Button::from_icon_name("user-trash-symbolic");
+        discard_button.set_tooltip_text(Some("Discard Draft"));
+        discard_button.set_hexpand(true);
+        discard_button.set_halign(gtk::Align::End);
+        discard_button.connect_clicked({
+            let panel = self.clone();
+            let id = draft.id.clone();
+            move |_| {
+                if let Err(e) = remove_draft(&id) {
+                    tracing::warn!(%e, "failed to discard draft");
+                }
+                panel.refresh();
+            }
+        });
+        actions.append(&discard_button);
+
+        card.append(&actions);
+        card
+    }
+
+    fn prompt_new_draft(&self) {
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text(Some("Rewrite of the introduction"));
+        entry.set_activates_default(true);
+
+        let dialog = adw::AlertDialog::new(
+            Some("Start Draft"),
+            Some(
+                "The draft starts from the current version. Its edits stay on this machine \
+                 until you merge it back.",
+            ),
+        );
+        dialog.add_responses(&[("cancel", "Cancel"), ("start", "Start Draft")]);
+        dialog.set_response_appearance("start", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("start"));
+        dialog.set_close_response("cancel");
+        dialog.set_extra_child(Some(&entry));
+
+        let panel = self.clone();
+        dialog.connect_response(None, move |_, response| {
+            let name = entry.text().trim().to_string();
+            if response != "start" || name.is_empty() {
+                return;
+            }
+            let panel = panel.clone();
+            glib::spawn_future_local(async move {
+                if let Err(e) = panel.create_draft(name).await {
+                    tracing::error!(%e, "failed to start draft");
+                }
+            });
+        });
+        dialog.present(Some(&self.container));
+    }
+
+    async fn create_draft(&self, name: String) -> anyhow::Result<()> {
+        // The draft includes everything that was typed so far
+        self.sync.flush();
+        let heads = self.sync.view_heads();
+        let forked = self
+            .sync
+            .handle()
+            .with_document(|doc| fork(doc, &heads, self.node_id))?;
+        let handle = self.drafts.create(forked).await?;
+
+        let draft = Draft {
+            id: handle.document_id(),
+            original: self.sync.handle().document_id(),
+            name,
+            created_at: schema::now_millis(),
+        };
+        add_draft(&draft)?;
+        self.refresh();
+        DraftWindow::present(self.window().as_ref(), &self.sync, draft, handle);
+        Ok(())
+    }
+
+    async fn open(&self, draft: Draft) {
+        let handle = match self.drafts.find(draft.id.clone()).await {
+            Ok(Some(handle)) => handle,
+            Ok(None) => {
+                tracing::warn!(id = %draft.id, "draft document is missing from storage");
+                return;
+            }
+            Err(e) => {
+                tracing::error!(%e, "failed to load draft");
+                return;
+            }
+        };
+        DraftWindow::present(self.window().as_ref(), &self.sync, draft, handle);
+    }
+}
+
+/// A window for editing a draft, with a way to merge it back.
+struct DraftWindow {
+    window: gtk::Window,
+    status: gtk::Label,
+    original: TextSynchronizer,
+    draft: TextSynchronizer,
+}
+
+impl DraftWindow {
+    fn present(
+        parent: Option<&gtk::Window>,
+        original: &TextSynchronizer,
+        draft: Draft,
+        handle: DocHandle,
+    ) {
+        let window = gtk::Window::builder()
+            .title(format!("Draft: {}", draft.name))
+            .default_width(800)
+            .default_height(600)
+            .build();
+        window.set_transient_for(parent);
+
+        let merge_button = gtk::Button::with_label("Merge into Original…");
+        merge_button.add_css_class("suggested-action");
+        merge_button.set_sensitive(!original.is_read_only());
+
+        let status = gtk::Label::new(Some(&format!(
+            "Private draft of automerge:{}, edits aren't shared",
+            draft.original
+        )));
+        status.set_hexpand(true);
+        status.set_halign(gtk::Align::Start);
+        status.set_ellipsize(gtk::pango::EllipsizeMode::End);
+        status.add_css_class("dim-label");
+
+        let toolbar = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        toolbar.set_margin_top(8);
+        toolbar.set_margin_bottom(8);
+        toolbar.set_margin_start(8);
+        toolbar.set_margin_end(8);
+        toolbar.append(&status);
+        toolbar.append(&merge_button);
+
+        // The synchronizer expects the buffer to start out with the document's text
+        let path = original.path();
+        let text = handle.with_document(|doc| path.read(doc));
+        let buffer = sourceview5::Buffer::new(None);
+        buffer.set_language(original.buffer().language().as_ref());
+        buffer.set_style_scheme(preferences::style_scheme().as_ref());
+        buffer.set_text(text.as_deref().unwrap_or_default());
+
+        let view = sourceview5::View::with_buffer(&buffer);
+        preferences::apply(&view);
+        view.set_auto_indent(true);
+        let scroll = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&view)
+            .build();
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        content.append(&toolbar);
+        content.append(&scroll);
+        window.set_child(Some(&content));
+
+        let sync = TextSynchronizer::new(handle, buffer, path);
+        sync.connect_error({
+            let status = status.clone();
+            let view = view.clone();
+            move |error| {
+                view.set_editable(false);
+                status.set_text(&format!("Draft opened read-only: {error}"));
+            }
+        });
+        if let Err(e) = text {
+            sync.fail(e);
+        }
+        sync.start();
+
+        let draft_window = Rc::new(Self {
+            window,
+            status,
+            original: original.clone(),
+            draft: sync,
+        });
+        merge_button.connect_clicked({
+            let draft_window = draft_window.clone();
+            move |_| draft_window.confirm_merge()
+        });
+        draft_window.window.connect_close_request({
+            let sync = draft_window.draft.clone();
+            move |_| {
+                sync.flush();
+                glib::Propagation::Proceed
+            }
+        });
+        draft_window.window.present();
+    }
+
+    /// Shows what merging would change in the original, then merges.
+    fn confirm_merge(self: &Rc<Self>) {
+        self.draft.flush();
+        self.original.flush();
+
+        let draft = self.draft.handle().with_document(|doc| doc.clone());
+        let path = self.original.path();
+        let texts = self
+            .original
+            .handle()
+            .with_document(|doc| preview_merge(doc, &draft, &path));
+        let (before, after) = match texts {
+            Ok(texts) => texts,
+            Err(e) => {
+                self.status.set_text(&format!("Can't merge: {e}"));
+                return;
+            }
+        };
+
+        let preview = gtk::TextView::new();
+        preview.set_editable(false);
+        preview.set_monospace(true);
+        preview.set_wrap_mode(gtk::WrapMode::WordChar);
+        let (added, removed) = show_diff(&preview.buffer(), &before, &after);
+        let preview_scroll = gtk::ScrolledWindow::builder()
+            .min_content_width(500)
+            .min_content_height(300)
+            .child(&preview)
+            .build();
+
+        let dialog = adw::AlertDialog::new(
+            Some("Merge Draft?"),
+            Some(&format!(
+                "+{added} −{removed} lines. Edits made to the original in the meantime are kept."
+            )),
+        );
+        dialog.add_responses(&[("cancel", "Cancel"), ("merge", "Merge")]);
+        dialog.set_response_appearance("merge", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("merge"));
+        dialog.set_close_response("cancel");
+        dialog.set_extra_child(Some(&preview_scroll));
+
+        let draft_window = self.clone();
+        dialog.connect_response(None, move |_, response| {
+            if response != "merge" {
+                return;
+            }
+            match merge(draft_window.original.handle(), draft_window.draft.handle()) {
+                Ok(()) => draft_window
+                    .status
+                    .set_text("Merged into the original, later edits can be merged again"),
+                Err(e) => draft_window
+                    .status
+                    .set_text(&format!("Failed to merge: {e}")),
+            }
+        });
+        dialog.present(Some(&self.window));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::transaction::Transactable;
+
+    use super::*;
+
+    fn node_id() -> iroh::NodeId {
+        iroh::SecretKey::from_bytes(&[1; 32]).public()
+    }
+
+    fn document(text: &str) -> Automerge {
+        let mut doc = Automerge::new();
+        doc.transact(|tx| schema::initialize(tx, "Notes", text, node_id()))
+            .unwrap();
+        doc
+    }
+
+    fn insert(doc: &mut Automerge, index: usize, text: &str) {
+        let text_id = TextPath::default().resolve(doc).unwrap();
+        doc.transact::<_, _, AutomergeError>(|tx| tx.splice_text(&text_id, index, 0, text))
+            .unwrap();
+    }
+
+    #[test]
+    fn forks_at_the_given_heads() {
+        let path = TextPath::default();
+        let mut original = document("Hello");
+        let heads = original.get_heads();
+        insert(&mut original, 5, " world");
+
+        let draft = fork(&original, &heads, node_id()).unwrap();
+        assert_eq!(path.read(&draft).unwrap(), "Hello");
+        assert_ne!(draft.get_actor(), original.get_actor());
+        let authors = authors::read_authors(&draft);
+        assert_eq!(
+            authors.get(&draft.get_actor().to_hex_string()),
+            Some(&node_id().to_string())
+        );
+    }
+
+    #[test]
+    fn previews_merges_with_edits_to_both() {
+        let path = TextPath::default();
+        let mut original = document("Hello");
+        let mut draft = fork(&original, &original.get_heads(), node_id()).unwrap();
+        insert(&mut draft, 5, " world");
+        insert(&mut original, 0, "Oh, ");
+
+        let heads = original.get_heads();
+        let (before, after) = preview_merge(&original, &draft, &path).unwrap();
+        assert_eq!(before, "Oh, Hello");
+        assert_eq!(after, "Oh, Hello world");
+        // Previewing leaves the original alone
+        assert_eq!(original.get_heads(), heads);
+
+        // Once merged, only what changed in the draft since gets merged again
+        original.merge(&mut draft).unwrap();
+        let (before, after) = preview_merge(&original, &draft, &path).unwrap();
+        assert_eq!(before, after);
+        insert(&mut draft, 11, " again");
+        let (_, after) = preview_merge(&original, &draft, &path).unwrap();
+        assert_eq!(after, "Oh, Hello world again");
+    }
+}
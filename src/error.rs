@@ -0,0 +1,32 @@
+use automerge::{AutomergeError, ObjType, Value};
+
+/// Errors caused by a document that doesn't have the shape glyphcaster expects.
+///
+/// These can come from any peer, so they must never take down the app.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DocumentError {
+    #[error("document has no `{path}` field")]
+    MissingText { path: String },
+    #[error("document field `{path}` is {found}, not a text object")]
+    NotText { path: String, found: String },
+    #[error("the document was opened from a read-only link")]
+    ReadOnly,
+    #[error("automerge error: {0}")]
+    Automerge(#[from] AutomergeError),
+}
+
+impl DocumentError {
+    pub(crate) fn not_text(path: impl Into<String>, value: &Value<'_>) -> Self {
+        let found = match value {
+            Value::Object(ObjType::Map) => "a map".to_string(),
+            Value::Object(ObjType::Table) => "a table".to_string(),
+            Value::Object(ObjType::List) => "a list".to_string(),
+            Value::Object(ObjType::Text) => "text".to_string(),
+            Value::Scalar(scalar) => format!("the scalar value {scalar}"),
+        };
+        Self::NotText {
+            path: path.into(),
+            found,
+        }
+    }
+}
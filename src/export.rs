@@ -0,0 +1,211 @@
+//! Writing the edited text out as markdown, HTML or PDF.
+
+use std::path::Path;
+
+use anyhow::Context as _;
+use automerge::Automerge;
+use glib::ExitCode;
+use gtk::prelude::*;
+use pulldown_cmark::{Options, Parser};
+use samod::DocumentId;
+use sourceview5::prelude::*;
+
+use crate::document_loader::DATA_DIR;
+use crate::fs::write_atomically;
+use crate::preview;
+use crate::schema::Metadata;
+use crate::text_path::TextPath;
+
+/// The stylesheet embedded into exported HTML, so the file stands on its own.
+const HTML_THEME: &str = r#"
+body {
+  max-width: 46em;
+  margin: 2em auto;
+  padding: 0 1em;
+  font-family: system-ui, -apple-system, "Cantarell", sans-serif;
+  line-height: 1.6;
+  color: #241f31;
+  background: #ffffff;
+}
+h1, h2, h3, h4, h5, h6 { line-height: 1.25; margin-top: 1.5em; }
+h1, h2 { border-bottom: 1px solid #deddda; padding-bottom: 0.3em; }
+a { color: #1c71d8; }
+code, pre { font-family: "Source Code Pro", monospace; font-size: 0.9em; }
+code { background: #f6f5f4; padding: 0.1em 0.3em; border-radius: 4px; }
+pre { background: #f6f5f4; padding: 1em; overflow-x: auto; border-radius: 6px; }
+pre code { background: none; padding: 0; }
+blockquote { margin: 0; padding-left: 1em; border-left: 4px solid #deddda; color: #5e5c64; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #deddda; padding: 0.3em 0.6em; }
+img { max-width: 100%; }
+@media (prefers-color-scheme: dark) {
+  body { color: #deddda; background: #1e1e1e; }
+  h1, h2 { border-color: #3d3846; }
+  a { color: #78aeed; }
+  code, pre { background: #303030; }
+  blockquote { border-color: #3d3846; color: #9a9996; }
+  th, td { border-color: #3d3846; }
+}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    Markdown,
+    Html,
+    Pdf,
+}
+
+impl ExportFormat {
+    /// Picks the format from the file extension of `path`.
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" | "txt" => Some(Self::Markdown),
+            "html" | "htm" => Some(Self::Html),
+            "pdf" => Some(Self::Pdf),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+            Self::Pdf => "pdf",
+        }
+    }
+}
+
+/// The markdown parser options used wherever glyphcaster renders markdown.
+pub(crate) fn markdown_options() -> Options {
+    Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES
+}
+
+/// Renders markdown to a standalone HTML page with the embedded theme.
+pub(crate) fn render_html(title: &str, markdown: &str) -> String {
+    let mut body = String::new();
+    pulldown_cmark::html::push_html(&mut body, Parser::new_ext(markdown, markdown_options()));
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>{HTML_THEME}</style>\n</head>\n<body>\n{body}</body>\n</html>\n",
+        glib::markup_escape_text(title)
+    )
+}
+
+/// Exports to the formats that don't need GTK, markdown and HTML.
+pub(crate) fn export_text(
+    path: &Path,
+    format: ExportFormat,
+    title: &str,
+    markdown: &str,
+) -> anyhow::Result<()> {
+    match format {
+        ExportFormat::Markdown => write_atomically(path, markdown),
+        ExportFormat::Html => write_atomically(path, &render_html(title, markdown)),
+        ExportFormat::Pdf => anyhow::bail!("PDF export is only available in the editor"),
+    }
+}
+
+/// Prints the rendered markdown to a PDF file using GTK's print operation.
+///
+/// The markdown is rendered like in the preview, and the compositor prints the
+/// preview's text tags along with the text.
+pub(crate) fn export_pdf(
+    parent: &impl IsA<gtk::Window>,
+    markdown: &str,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let buffer = sourceview5::Buffer::new(None);
+    preview::render_into(buffer.upcast_ref(), markdown);
+
+    let compositor = sourceview5::PrintCompositor::new(&buffer);
+    compositor.set_body_font_name("Sans 11");
+    compositor.set_wrap_mode(gtk::WrapMode::WordChar);
+    // Prints the text tags, there's no syntax highlighting without a language
+    compositor.set_highlight_syntax(true);
+
+    let operation = gtk::PrintOperation::new();
+    operation.set_export_filename(path);
+    operation.connect_paginate({
+        let compositor = compositor.clone();
+        move |operation, context| {
+            // Pagination happens in steps, GTK keeps calling until it's done
+            let done = compositor.paginate(context);
+            if done {
+                operation.set_n_pages(compositor.n_pages());
+            }
+            done
+        }
+    });
+    operation.connect_draw_page(move |_, context, page| {
+        compositor.draw_page(context, page);
+    });
+    operation
+        .run(gtk::PrintOperationAction::Export, Some(parent.as_ref()))
+        .with_context(|| format!("failed to export {}", path.display()))?;
+    Ok(())
+}
+
+/// Loads a document from local storage and writes its text to `output`,
+/// without connecting to any peers.
+pub(crate) async fn export_from_storage(
+    doc_id: DocumentId,
+    text_path: &TextPath,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let format = ExportFormat::from_path(output).with_context(|| {
+        format!(
+            "unknown file type for {}, use .md, .html or .automerge",
+            output.display()
+        )
+    })?;
+
+    let (title, markdown) = with_stored_document(doc_id, |doc| {
+        let markdown = text_path.read(doc)?;
+        Ok((Metadata::read(doc).display_title(&markdown), markdown))
+    })
+    .await?;
+    export_text(output, format, &title, &markdown)
+}
+
+/// Loads a document from local storage, without connecting to any peers, and
+/// runs `f` on it.
+pub(crate) async fn with_stored_document<T>(
+    doc_id: DocumentId,
+    f: impl FnOnce(&mut Automerge) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let samod = samod::Samod::build_tokio()
+        .with_storage(samod::storage::TokioFilesystemStorage::new(DATA_DIR))
+        .load()
+        .await;
+    let handle = samod
+        .find(doc_id.clone())
+        .await?
+        .with_context(|| format!("document {doc_id} isn't in {DATA_DIR}"))?;
+    handle.with_document(f)
+}
+
+/// Runs `glyphcaster export <automerge URL> <file>`.
+pub(crate) fn run_cli(doc_id: DocumentId, text_path: &TextPath, output: &Path) -> ExitCode {
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start tokio runtime: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match rt.block_on(export_from_storage(doc_id, text_path, output)) {
+        Ok(()) => {
+            println!("Exported to {}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to export: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,19 @@
+//! File system helpers shared by everything that writes files.
+
+use std::path::Path;
+
+use anyhow::Context as _;
+
+/// Writes `contents` to a temporary file next to `path` and moves it into place,
+/// so readers never see a partially written file.
+pub(crate) fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?;
+    let temp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    std::fs::write(&temp_path, contents)
+        .with_context(|| format!("failed to write {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
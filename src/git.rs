@@ -0,0 +1,327 @@
+//! Committing the edited text into a git repository, so collaboratively edited
+//! documents end up in the normal repository history.
+//!
+//! Every commit records the document and its heads in `Automerge-Document` and
+//! `Automerge-Heads` trailers, so the next commit of the same document to the
+//! same file can list just the changes made since.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::Context as _;
+use automerge::{Automerge, ChangeHash};
+use glib::ExitCode;
+use samod::DocumentId;
+
+use crate::authors;
+use crate::error::DocumentError;
+use crate::export;
+use crate::fs::write_atomically;
+use crate::schema::Metadata;
+use crate::text_path::TextPath;
+
+const HEADS_TRAILER: &str = "Automerge-Heads:";
+const DOCUMENT_TRAILER: &str = "Automerge-Document:";
+
+/// Runs git in the directory containing `file` and returns its standard output.
+fn git(file: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let dir = file.parent().unwrap_or(Path::new("."));
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The heads recorded in a commit message, if it was a commit of `doc_id`.
+fn heads_from_message(message: &str, doc_id: &DocumentId) -> Vec<ChangeHash> {
+    let document = format!("automerge:{doc_id}");
+    let same_document = message
+        .lines()
+        .filter_map(|line| line.strip_prefix(DOCUMENT_TRAILER))
+        .any(|id| id.trim() == document);
+    if !same_document {
+        return Vec::new();
+    }
+    message
+        .lines()
+        .filter_map(|line| line.strip_prefix(HEADS_TRAILER))
+        .flat_map(|heads| heads.split_whitespace())
+        .filter_map(|hash| ChangeHash::from_str(hash).ok())
+        .collect()
+}
+
+/// The heads recorded by the last commit touching `file`, if it was a commit
+/// of `doc_id`.
+fn last_committed_heads(file: &Path, doc_id: &DocumentId) -> anyhow::Result<Vec<ChangeHash>> {
+    // A repository without commits yet has no HEAD, and git log fails
+    if git(file, &["rev-parse", "--verify", "-q", "HEAD"]).is_err() {
+        return Ok(Vec::new());
+    }
+    let file_name = file.file_name().context("not a file path")?;
+    let message = git(
+        file,
+        &[
+            "log",
+            "-1",
+            "--format=%B",
+            "--",
+            &file_name.to_string_lossy(),
+        ],
+    )?;
+    Ok(heads_from_message(&message, doc_id))
+}
+
+/// Checks that `file` is inside a git working tree and returns the heads
+/// of the last commit of `doc_id` to it, to pass to [`PendingCommit::read`].
+pub(crate) fn committed_heads(file: &Path, doc_id: &DocumentId) -> anyhow::Result<Vec<ChangeHash>> {
+    git(file, &["rev-parse", "--show-toplevel"])
+        .with_context(|| format!("{} isn't inside a git working tree", file.display()))?;
+    last_committed_heads(file, doc_id)
+}
+
+/// Builds the commit message, listing who made the changes since `since`.
+fn commit_message(
+    doc: &Automerge,
+    doc_id: &DocumentId,
+    title: &str,
+    since: &[ChangeHash],
+) -> String {
+    // Heads from another document or history that was never synced here are
+    // unknown, in which case every change counts as new
+    let since: Vec<ChangeHash> = since
+        .iter()
+        .filter(|hash| doc.get_change_by_hash(hash).is_some())
+        .copied()
+        .collect();
+    let author_names = authors::read_authors(doc);
+    let mut change_counts: BTreeMap<String, usize> = BTreeMap::new();
+    for change in doc.get_changes(&since) {
+        *change_counts
+            .entry(authors::display_name(&author_names, change.actor_id()))
+            .or_default() += 1;
+    }
+
+    let mut message = format!("Update {title}\n\n");
+    if change_counts.is_empty() {
+        message.push_str("No document changes since the last commit.\n");
+    } else {
+        message.push_str("Changes by:\n");
+        for (author, count) in change_counts {
+            let plural = if count == 1 { "" } else { "s" };
+            message.push_str(&format!("- {author} ({count} change{plural})\n"));
+        }
+    }
+    let heads: Vec<String> = doc.get_heads().iter().map(ChangeHash::to_string).collect();
+    message.push_str(&format!(
+        "\n{DOCUMENT_TRAILER} automerge:{doc_id}\n{HEADS_TRAILER} {}\n",
+        heads.join(" ")
+    ));
+    message
+}
+
+/// The text and commit message for a commit, read from the document up
+/// front so git can run without holding on to the document.
+pub(crate) struct PendingCommit {
+    markdown: String,
+    message: String,
+}
+
+impl PendingCommit {
+    /// Reads the text at `text_path`, listing who changed the document since
+    /// the `since` heads from [`committed_heads`].
+    pub(crate) fn read(
+        doc: &Automerge,
+        doc_id: &DocumentId,
+        text_path: &TextPath,
+        since: &[ChangeHash],
+    ) -> Result<Self, DocumentError> {
+        let markdown = text_path.read(doc)?;
+        let title = Metadata::read(doc).display_title(&markdown);
+        Ok(Self {
+            message: commit_message(doc, doc_id, &title, since),
+            markdown,
+        })
+    }
+}
+
+/// Writes the text to `file` inside a git working tree and commits just that
+/// file. Returns `None` if the file was already up to date.
+pub(crate) fn commit(file: &Path, pending: &PendingCommit) -> anyhow::Result<Option<String>> {
+    write_atomically(file, &pending.markdown)?;
+
+    let file_name = file
+        .file_name()
+        .context("not a file path")?
+        .to_string_lossy();
+    git(file, &["add", "--", &file_name])?;
+    if git(file, &["status", "--porcelain", "--", &file_name])?.is_empty() {
+        return Ok(None);
+    }
+
+    git(
+        file,
+        &[
+            "commit",
+            "--quiet",
+            "-m",
+            &pending.message,
+            "--",
+            &file_name,
+        ],
+    )?;
+    let commit = git(file, &["rev-parse", "--short", "HEAD"])?;
+    Ok(Some(commit.trim().to_string()))
+}
+
+/// Runs `glyphcaster git-commit <automerge URL> <file>`.
+pub(crate) fn run_cli(doc_id: DocumentId, text_path: &TextPath, file: PathBuf) -> ExitCode {
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Failed to start tokio runtime: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = committed_heads(&file, &doc_id).and_then(|since| {
+        let pending = rt.block_on(export::with_stored_document(doc_id.clone(), |doc| {
+            Ok(PendingCommit::read(doc, &doc_id, text_path, &since)?)
+        }))?;
+        commit(&file, &pending)
+    });
+    match result {
+        Ok(Some(commit)) => {
+            println!("Committed {} as {commit}", file.display());
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            println!("{} is already up to date", file.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to commit: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{ActorId, AutomergeError, ObjType, ROOT, transaction::Transactable};
+
+    use super::*;
+
+    fn doc_id() -> DocumentId {
+        DocumentId::from_str("pEbmSWqJdBuPadRGm8tDZXgWR6").unwrap()
+    }
+
+    fn edit(doc: &mut Automerge, text: &str) {
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            tx.put_object(ROOT, "content", ObjType::Text)
+                .and_then(|text_id| tx.splice_text(&text_id, 0, 0, text))
+        })
+        .unwrap();
+    }
+
+    /// A document with one change by a registered author and two by an
+    /// unknown actor.
+    fn document() -> (Automerge, iroh::NodeId) {
+        let node_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        let actor = ActorId::from(vec![1; 16]);
+        let mut doc = Automerge::new().with_actor(actor.clone());
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let authors_id = tx.put_object(ROOT, authors::AUTHORS_KEY, ObjType::Map)?;
+            tx.put(&authors_id, actor.to_hex_string(), node_id.to_string())
+        })
+        .unwrap();
+        doc.set_actor(ActorId::from(vec![2; 16]));
+        edit(&mut doc, "# Notes");
+        edit(&mut doc, "# Notes\n");
+        (doc, node_id)
+    }
+
+    fn trailers(doc: &Automerge) -> String {
+        let heads: Vec<String> = doc.get_heads().iter().map(ChangeHash::to_string).collect();
+        format!(
+            "\nAutomerge-Document: automerge:{}\nAutomerge-Heads: {}\n",
+            doc_id(),
+            heads.join(" ")
+        )
+    }
+
+    #[test]
+    fn lists_all_changes_for_the_first_commit() {
+        let (doc, node_id) = document();
+        let message = commit_message(&doc, &doc_id(), "Notes", &[]);
+        let author: String = node_id.to_string().chars().take(12).collect();
+        assert!(message.starts_with("Update Notes\n\nChanges by:\n"));
+        assert!(message.contains(&format!("\n- {author}... (1 change)\n")));
+        assert!(message.contains("\n- actor 02020202... (2 changes)\n"));
+        assert!(message.ends_with(&trailers(&doc)));
+
+        // Heads the document doesn't know are treated like a first commit
+        let unknown = [ChangeHash([9; 32])];
+        assert_eq!(commit_message(&doc, &doc_id(), "Notes", &unknown), message);
+    }
+
+    #[test]
+    fn lists_changes_since_the_last_commit() {
+        let (mut doc, _) = document();
+        let since = doc.get_heads();
+        edit(&mut doc, "# Notes\n\nMore");
+        assert_eq!(
+            commit_message(&doc, &doc_id(), "Notes", &since),
+            format!(
+                "Update Notes\n\nChanges by:\n- actor 02020202... (1 change)\n{}",
+                trailers(&doc)
+            )
+        );
+    }
+
+    #[test]
+    fn notes_when_nothing_changed() {
+        let (doc, _) = document();
+        assert_eq!(
+            commit_message(&doc, &doc_id(), "Notes", &doc.get_heads()),
+            format!(
+                "Update Notes\n\nNo document changes since the last commit.\n{}",
+                trailers(&doc)
+            )
+        );
+    }
+
+    #[test]
+    fn reads_heads_only_from_commits_of_the_same_document() {
+        let (doc, _) = document();
+        let message = commit_message(&doc, &doc_id(), "Notes", &[]);
+        assert_eq!(heads_from_message(&message, &doc_id()), doc.get_heads());
+
+        let heads = doc.get_heads()[0];
+        let other =
+            format!("Update\n\n{DOCUMENT_TRAILER} automerge:other\n{HEADS_TRAILER} {heads}\n");
+        assert!(heads_from_message(&other, &doc_id()).is_empty());
+        let untagged = format!("Update\n\n{HEADS_TRAILER} {heads}\n");
+        assert!(heads_from_message(&untagged, &doc_id()).is_empty());
+    }
+
+    #[test]
+    fn treats_repositories_without_commits_as_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("README.md");
+        git(&file, &["init", "--quiet"]).unwrap();
+        assert!(committed_heads(&file, &doc_id()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,644 @@
+//! Browsing the history of the edited text.
+//!
+//! Automerge keeps every change, so any earlier version can be shown by reading
+//! the text at the heads the document had back then.
+
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+use adw::prelude::*;
+use automerge::{ActorId, Automerge, ChangeHash, ObjId, transaction::Transactable};
+use futures::StreamExt;
+use gtk::{glib, prelude::*};
+use similar::{ChangeTag, TextDiff};
+use sourceview5::prelude::*;
+
+use crate::authors;
+use crate::error::DocumentError;
+use crate::sync::TextSynchronizer;
+use crate::text_path::TextPath;
+
+/// Consecutive changes by the same author are shown as one version if they
+/// are at most this many seconds apart.
+const GROUP_GAP_SECS: i64 = 10 * 60;
+
+const ADDED_TAG: &str = "diff-added";
+const REMOVED_TAG: &str = "diff-removed";
+
+/// What the selected version gets compared with, in the order of the dropdown.
+/// The first entry shows the version on its own.
+const COMPARE_PREVIOUS: u32 = 1;
+const COMPARE_CURRENT: u32 = 2;
+
+/// A run of changes by one author, shown as a single version in the history.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HistoryGroup {
+    pub(crate) actor: ActorId,
+    pub(crate) author: String,
+    /// Seconds since the unix epoch, 0 if the changes didn't record a time.
+    pub(crate) start_time: i64,
+    pub(crate) end_time: i64,
+    pub(crate) messages: Vec<String>,
+    pub(crate) change_count: usize,
+    /// The heads of the document right after the last change of the group.
+    pub(crate) heads: Vec<ChangeHash>,
+}
+
+impl HistoryGroup {
+    pub(crate) fn time_range(&self) -> String {
+        let start = format_time(self.start_time);
+        if self.end_time - self.start_time < 60 {
+            return start;
+        }
+        format!("{start} – {}", format_time(self.end_time))
+    }
+
+    pub(crate) fn description(&self) -> String {
+        let changes = match self.change_count {
+            1 => "1 change".to_string(),
+            n => format!("{n} changes"),
+        };
+        if self.messages.is_empty() {
+            return changes;
+        }
+        format!("{changes} · {}", self.messages.join(", "))
+    }
+}
+
+pub(crate) fn format_time(secs: i64) -> String {
+    if secs == 0 {
+        return "Unknown time".to_string();
+    }
+    glib::DateTime::from_unix_local(secs)
+        .and_then(|date| date.format("%x %X"))
+        .map(|date| date.to_string())
+        .unwrap_or_else(|_| secs.to_string())
+}
+
+/// Lists the document's changes as versions, oldest first.
+pub(crate) fn read_history(doc: &Automerge) -> Vec<HistoryGroup> {
+    let authors = authors::read_authors(doc);
+    let mut groups: Vec<HistoryGroup> = Vec::new();
+    // The heads of the document after each change. Changes come in causal order,
+    // so a change's dependencies are always in here already, and concurrent
+    // changes by other peers stay in until a later change depends on them.
+    let mut heads = BTreeSet::new();
+
+    for change in doc.get_changes(&[]) {
+        for dep in change.deps() {
+            heads.remove(dep);
+        }
+        heads.insert(change.hash());
+
+        let actor = change.actor_id();
+        let time = change.timestamp();
+        let continues_group = groups
+            .last()
+            .is_some_and(|group| &group.actor == actor && time - group.end_time <= GROUP_GAP_SECS);
+        if !continues_group {
+            groups.push(HistoryGroup {
+                actor: actor.clone(),
+                author: authors::display_name(&authors, actor),
+                start_time: time,
+                end_time: time,
+                messages: Vec::new(),
+                change_count: 0,
+                heads: Vec::new(),
+            });
+        }
+
+        let group = groups.last_mut().expect("a group was just pushed");
+        group.end_time = group.end_time.max(time);
+        group.change_count += 1;
+        group.heads = heads.iter().copied().collect();
+        if let Some(message) = change.message().filter(|m| !group.messages.contains(m)) {
+            group.messages.push(message.clone());
+        }
+    }
+    groups
+}
+
+/// Shows the line diff from `old` to `new` in `buffer`. Returns the number of
+/// added and removed lines.
+pub(crate) fn show_diff(
+    buffer: &impl IsA<gtk::TextBuffer>,
+    old: &str,
+    new: &str,
+) -> (usize, usize) {
+    let buffer = buffer.as_ref();
+    let tag_table = buffer.tag_table();
+    let added = tag_table.lookup(ADDED_TAG).unwrap_or_else(|| {
+        let tag = gtk::TextTag::builder()
+            .name(ADDED_TAG)
+            .paragraph_background("rgba(46, 194, 126, 0.25)")
+            .build();
+        tag_table.add(&tag);
+        tag
+    });
+    let removed = tag_table.lookup(REMOVED_TAG).unwrap_or_else(|| {
+        let tag = gtk::TextTag::builder()
+            .name(REMOVED_TAG)
+            .paragraph_background("rgba(224, 27, 36, 0.2)")
+            .strikethrough(true)
+            .build();
+        tag_table.add(&tag);
+        tag
+    });
+
+    buffer.set_text("");
+    let (mut added_lines, mut removed_lines) = (0, 0);
+    for change in TextDiff::from_lines(old, new).iter_all_changes() {
+        let mut end = buffer.end_iter();
+        match change.tag() {
+            ChangeTag::Equal => buffer.insert(&mut end, change.value()),
+            ChangeTag::Insert => {
+                added_lines += 1;
+                buffer.insert_with_tags(&mut end, change.value(), &[&added]);
+            }
+            ChangeTag::Delete => {
+                removed_lines += 1;
+                buffer.insert_with_tags(&mut end, change.value(), &[&removed]);
+            }
+        }
+    }
+    (added_lines, removed_lines)
+}
+
+/// Changes the edited text back to how it was at `heads`.
+pub(crate) fn restore(
+    sync: &TextSynchronizer,
+    heads: &[ChangeHash],
+    message: &str,
+) -> Result<(), DocumentError> {
+    let path = sync.path();
+    sync.change_text(message, |tx, text_obj_id| {
+        restore_text(tx, text_obj_id, &path, heads)
+    })
+}
+
+/// Changes the text object back to how the text at `path` was at `heads`.
+///
+/// Nothing gets rewritten: the difference to the current text is applied as
+/// splices in a new change, which syncs to peers like any other edit and keeps
+/// edits they made concurrently.
+fn restore_text<T: Transactable>(
+    tx: &mut T,
+    text_obj_id: &ObjId,
+    path: &TextPath,
+    heads: &[ChangeHash],
+) -> Result<(), DocumentError> {
+    let old_text = path.read_at(tx, heads)?;
+    tx.update_text(text_obj_id, &old_text)?;
+    Ok(())
+}
+
+/// A window listing all versions of the edited text, showing the selected one
+/// read-only and optionally what changed compared to another version.
+#[derive(Clone)]
+pub(crate) struct HistoryWindow {
+    window: gtk::Window,
+    list: gtk::ListBox,
+    scale: gtk::Scale,
+    compare: gtk::DropDown,
+    restore_button: gtk::Button,
+    status: gtk::Label,
+    view: sourceview5::View,
+    sync: TextSynchronizer,
+    /// Oldest first, the list shows them in reverse.
+    groups: Rc<RefCell<Vec<HistoryGroup>>>,
+}
+
+impl HistoryWindow {
+    pub(crate) fn present(parent: &impl IsA<gtk::Window>, sync: &TextSynchronizer) -> Self {
+        let window = gtk::Window::builder()
+            .title(format!("History of {}", sync.path()))
+            .transient_for(parent)
+            .default_width(900)
+            .default_height(600)
+            .build();
+
+        let list = gtk::ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::Single);
+        list.add_css_class("navigation-sidebar");
+        let list_scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .width_request(280)
+            .vexpand(true)
+            .child(&list)
+            .build();
+
+        let scale = gtk::Scale::with_range(gtk::Orientation::Horizontal, 0.0, 1.0, 1.0);
+        scale.set_digits(0);
+        scale.set_round_digits(0);
+        scale.set_draw_value(false);
+        scale.set_hexpand(true);
+        scale.set_tooltip_text(Some("Drag to travel through time"));
+
+        let compare = gtk::DropDown::from_strings(&[
+            "Show version",
+            "Compare with previous version",
+            "Compare with current version",
+        ]);
+
+        let restore_button = gtk::Button::with_label("Restore This Version");
+        restore_button.set_tooltip_text(Some("Change the text back to this version"));
+
+        let controls = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        controls.append(&scale);
+        controls.append(&compare);
+        controls.append(&restore_button);
+
+        let status = gtk::Label::new(None);
+        status.set_halign(gtk::Align::Start);
+        status.set_ellipsize(gtk::pango::EllipsizeMode::End);
+        status.add_css_class("dim-label");
+
+        let buffer = sourceview5::Buffer::new(None);
+        buffer.set_language(sync.buffer().language().as_ref());
+        buffer.set_style_scheme(sync.buffer().style_scheme().as_ref());
+        let view = sourceview5::View::with_buffer(&buffer);
+        view.set_editable(false);
+        view.set_monospace(true);
+        view.set_show_line_numbers(true);
+        view.set_wrap_mode(gtk::WrapMode::WordChar);
+        let view_scroll = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&view)
+            .build();
+
+        let version_box = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        version_box.set_margin_top(8);
+        version_box.set_margin_bottom(8);
+        version_box.set_margin_start(8);
+        version_box.set_margin_end(8);
+        version_box.append(&controls);
+        version_box.append(&status);
+        version_box.append(&view_scroll);
+
+        let paned = gtk::Paned::new(gtk::Orientation::Horizontal);
+        paned.set_start_child(Some(&list_scroll));
+        paned.set_end_child(Some(&version_box));
+        paned.set_resize_start_child(false);
+        paned.set_shrink_start_child(false);
+        window.set_child(Some(&paned));
+
+        let history = Self {
+            window,
+            list,
+            scale,
+            compare,
+            restore_button,
+            status,
+            view,
+            sync: sync.clone(),
+            groups: Rc::new(RefCell::new(Vec::new())),
+        };
+        history.connect_signals();
+        history.refresh();
+        history.window.present();
+
+        // Peers keep editing while the history is open
+        glib::spawn_future_local({
+            let history = history.clone();
+            async move {
+                let mut changes = history.sync.handle().changes();
+                while changes.next().await.is_some() {
+                    if !history.window.is_visible() {
+                        break;
+                    }
+                    history.refresh();
+                }
+            }
+        });
+
+        history
+    }
+
+    fn connect_signals(&self) {
+        self.list.connect_row_selected({
+            let history = self.clone();
+            move |_, row| {
+                let Some(row) = row else {
+                    return;
+                };
+                let index = history.group_index(row.index());
+                if history.scale.value() as usize != index {
+                    history.scale.set_value(index as f64);
+                }
+                history.show_version(index);
+            }
+        });
+
+        self.scale.connect_value_changed({
+            let history = self.clone();
+            move |scale| {
+                let index = scale.value().round() as usize;
+                let row = history.row_index(index);
+                if let Some(row) = history.list.row_at_index(row) {
+                    history.list.select_row(Some(&row));
+                }
+            }
+        });
+
+        self.restore_button.connect_clicked({
+            let history = self.clone();
+            move |_| history.confirm_restore()
+        });
+
+        self.compare.connect_selected_notify({
+            let history = self.clone();
+            move |_| {
+                if let Some(index) = history.selected() {
+                    history.show_version(index);
+                }
+            }
+        });
+    }
+
+    /// The group shown in the list row at `row`, the newest version being on top.
+    fn group_index(&self, row: i32) -> usize {
+        self.groups.borrow().len().saturating_sub(row as usize + 1)
+    }
+
+    fn row_index(&self, index: usize) -> i32 {
+        self.groups.borrow().len().saturating_sub(index + 1) as i32
+    }
+
+    /// The index of the selected version in `groups`.
+    pub(crate) fn selected(&self) -> Option<usize> {
+        let row = self.list.selected_row()?;
+        Some(self.group_index(row.index()))
+    }
+
+    /// The selected version, if there is one.
+    pub(crate) fn selected_group(&self) -> Option<HistoryGroup> {
+        let index = self.selected()?;
+        self.groups.borrow().get(index).cloned()
+    }
+
+    /// Re-reads the history from the document, keeping the selected version.
+    pub(crate) fn refresh(&self) {
+        let groups = self.sync.handle().with_document(|doc| read_history(doc));
+        if *self.groups.borrow() == groups {
+            return;
+        }
+        let selected_heads = self.selected_group().map(|group| group.heads);
+
+        self.list.remove_all();
+        for group in groups.iter().rev() {
+            self.list.append(&version_row(group));
+        }
+        let count = groups.len();
+        let selected = selected_heads
+            .and_then(|heads| groups.iter().position(|group| group.heads == heads))
+            .unwrap_or(count.saturating_sub(1));
+        *self.groups.borrow_mut() = groups;
+
+        self.scale
+            .set_range(0.0, count.saturating_sub(1).max(1) as f64);
+        self.scale.set_sensitive(count > 1);
+        if let Some(row) = self.list.row_at_index(self.row_index(selected)) {
+            self.list.select_row(Some(&row));
+        }
+    }
+
+    fn show_version(&self, index: usize) {
+        let groups = self.groups.borrow();
+        let Some(group) = groups.get(index) else {
+            return;
+        };
+        let path = self.sync.path();
+        let current_heads = self.sync.view_heads();
+
+        let (text, previous, current) = self.sync.handle().with_document(|doc| {
+            let previous = match index.checked_sub(1) {
+                Some(previous) => path.read_at(doc, &groups[previous].heads),
+                None => Ok(String::new()),
+            };
+            (
+                path.read_at(doc, &group.heads),
+                previous,
+                path.read_at(doc, &current_heads),
+            )
+        });
+
+        let buffer = self.view.buffer();
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                buffer.set_text("");
+                self.restore_button.set_sensitive(false);
+                self.status
+                    .set_text(&format!("Not available in this version: {e}"));
+                return;
+            }
+        };
+        // A text that doesn't exist in the other version compares as empty
+        let current = current.unwrap_or_default();
+        self.restore_button
+            .set_sensitive(text != current && !self.sync.is_read_only());
+
+        let summary = format!("{} · {}", group.author, group.time_range());
+        match self.compare.selected() {
+            COMPARE_PREVIOUS => {
+                let (added, removed) = show_diff(&buffer, &previous.unwrap_or_default(), &text);
+                self.status.set_text(&format!(
+                    "{summary} · +{added} −{removed} lines compared to the previous version"
+                ));
+            }
+            COMPARE_CURRENT => {
+                let (added, removed) = show_diff(&buffer, &text, &current);
+                self.status.set_text(&format!(
+                    "{summary} · +{added} −{removed} lines changed since this version"
+                ));
+            }
+            _ => {
+                buffer.set_text(&text);
+                self.status.set_text(&summary);
+            }
+        }
+    }
+
+    /// Asks for confirmation, then restores the selected version.
+    fn confirm_restore(&self) {
+        let Some(group) = self.selected_group() else {
+            return;
+        };
+        let time = format_time(group.end_time);
+
+        let dialog = adw::AlertDialog::new(
+            Some("Restore This Version?"),
+            Some(&format!(
+                "{} will be changed back to how it was on {time}. The restore is recorded as a \
+                 new change, so peers receive it like any other edit and it shows up in the \
+                 history itself.",
+                self.sync.path()
+            )),
+        );
+        dialog.add_responses(&[("cancel", "Cancel"), ("restore", "Restore")]);
+        dialog.set_response_appearance("restore", adw::ResponseAppearance::Destructive);
+        dialog.set_default_response(Some("cancel"));
+        dialog.set_close_response("cancel");
+
+        let history = self.clone();
+        dialog.connect_response(None, move |_, response| {
+            if response != "restore" {
+                return;
+            }
+            let message = format!("Restore version from {time}");
+            if let Err(e) = restore(&history.sync, &group.heads, &message) {
+                history.status.set_text(&format!("Failed to restore: {e}"));
+            }
+        });
+        dialog.present(Some(&self.window));
+    }
+}
+
+fn version_row(group: &HistoryGroup) -> gtk::Box {
+    let row = gtk::Box::new(gtk::Orientation::Vertical, 2);
+    row.set_margin_top(4);
+    row.set_margin_bottom(4);
+
+    let author = gtk::Label::new(Some(&group.author));
+    author.set_halign(gtk::Align::Start);
+    author.add_css_class("heading");
+    author.set_tooltip_text(Some(&format!("Actor {}", group.actor)));
+    row.append(&author);
+
+    let time = gtk::Label::new(Some(&group.time_range()));
+    time.set_halign(gtk::Align::Start);
+    time.add_css_class("caption");
+    row.append(&time);
+
+    let description = gtk::Label::new(Some(&group.description()));
+    description.set_halign(gtk::Align::Start);
+    description.set_ellipsize(gtk::pango::EllipsizeMode::End);
+    description.add_css_class("caption");
+    description.add_css_class("dim-label");
+    row.append(&description);
+
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{AutomergeError, ObjType, ROOT, ReadDoc, transaction::CommitOptions};
+
+    use super::*;
+
+    /// Adds `text` to the end of the document's text as `actor` at `time`.
+    fn edit(doc: &mut Automerge, actor: u8, time: i64, message: &str, text: &str) -> ChangeHash {
+        doc.set_actor(ActorId::from(vec![actor; 16]));
+        doc.transact_with::<_, _, AutomergeError, _>(
+            |_| {
+                CommitOptions::default()
+                    .with_message(message.to_string())
+                    .with_time(time)
+            },
+            |tx| {
+                let text_id = match tx.get(ROOT, "content")? {
+                    Some((_, text_id)) => text_id,
+                    None => tx.put_object(ROOT, "content", ObjType::Text)?,
+                };
+                let length = tx.length(&text_id);
+                tx.splice_text(&text_id, length, 0, text)?;
+                Ok(())
+            },
+        )
+        .unwrap();
+        doc.get_heads()[0]
+    }
+
+    #[test]
+    fn groups_nearby_changes_by_the_same_actor() {
+        let mut doc = Automerge::new();
+        edit(&mut doc, 1, 1000, "Create", "# Notes");
+        edit(&mut doc, 1, 1100, "Edit text", "\n");
+        let first_end = edit(&mut doc, 1, 1100 + GROUP_GAP_SECS, "Edit text", "a");
+        let second_end = edit(&mut doc, 2, 1700, "Edit text (2 edits)", "b");
+        let third_end = edit(&mut doc, 1, 1800, "Edit text", "c");
+
+        let groups = read_history(&doc);
+        assert_eq!(groups.len(), 3);
+
+        assert_eq!(groups[0].actor, ActorId::from(vec![1; 16]));
+        assert_eq!(groups[0].start_time, 1000);
+        assert_eq!(groups[0].end_time, 1100 + GROUP_GAP_SECS);
+        assert_eq!(groups[0].change_count, 3);
+        assert_eq!(groups[0].messages, ["Create", "Edit text"]);
+        assert_eq!(groups[0].heads, [first_end]);
+
+        assert_eq!(groups[1].actor, ActorId::from(vec![2; 16]));
+        assert_eq!(groups[1].author, "actor 02020202...");
+        assert_eq!(groups[1].change_count, 1);
+        assert_eq!(groups[1].messages, ["Edit text (2 edits)"]);
+        assert_eq!(groups[1].heads, [second_end]);
+
+        assert_eq!(groups[2].change_count, 1);
+        assert_eq!(groups[2].heads, [third_end]);
+    }
+
+    #[test]
+    fn splits_groups_after_a_pause() {
+        let mut doc = Automerge::new();
+        edit(&mut doc, 1, 1000, "Edit text", "a");
+        edit(&mut doc, 1, 1001 + GROUP_GAP_SECS, "Edit text", "b");
+
+        let groups = read_history(&doc);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].end_time, 1000);
+        assert_eq!(groups[1].start_time, 1001 + GROUP_GAP_SECS);
+    }
+
+    #[test]
+    fn keeps_concurrent_changes_in_the_heads() {
+        let mut doc = Automerge::new();
+        let base = edit(&mut doc, 1, 1000, "Create", "Hello");
+        let mut peer = doc.fork();
+        edit(&mut doc, 1, 1100, "Edit text", "!");
+        let remote = edit(&mut peer, 2, 1100, "Edit text", "?");
+        doc.merge(&mut peer).unwrap();
+
+        let groups = read_history(&doc);
+        // Whichever change comes last, the version after it has both
+        let last = groups.last().unwrap();
+        assert_eq!(last.heads, doc.get_heads());
+        assert_eq!(last.heads.len(), 2);
+        let peer_group = groups
+            .iter()
+            .find(|group| group.actor == ActorId::from(vec![2; 16]))
+            .unwrap();
+        assert!(peer_group.heads.contains(&remote));
+        assert!(!peer_group.heads.contains(&base));
+    }
+
+    #[test]
+    fn reads_empty_documents() {
+        assert!(read_history(&Automerge::new()).is_empty());
+    }
+
+    #[test]
+    fn restores_versions_in_a_new_change() {
+        let mut doc = Automerge::new();
+        edit(&mut doc, 1, 1000, "Create", "Hello");
+        let version = doc.get_heads();
+        edit(&mut doc, 1, 1100, "Edit text", " world");
+        let before_restore = doc.get_heads();
+
+        // A peer adds to the text at the same time
+        let mut peer = doc.fork();
+        edit(&mut peer, 2, 1200, "Edit text", "!");
+
+        let path: TextPath = "content".parse().unwrap();
+        let text_id = path.resolve(&doc).unwrap();
+        doc.transact::<_, _, DocumentError>(|tx| restore_text(tx, &text_id, &path, &version))
+            .unwrap();
+        assert_eq!(path.read(&doc).unwrap(), "Hello");
+        assert_eq!(doc.get_changes(&before_restore).len(), 1);
+        assert_eq!(path.read_at(&doc, &before_restore).unwrap(), "Hello world");
+
+        // Only the restored part is removed, the peer's edit stays
+        doc.merge(&mut peer).unwrap();
+        assert_eq!(path.read(&doc).unwrap(), "Hello!");
+    }
+}
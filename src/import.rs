@@ -0,0 +1,123 @@
+//! Creating new documents from existing markdown files.
+
+use std::path::Path;
+
+use adw::prelude::*;
+use anyhow::Context as _;
+use automerge::Automerge;
+use samod::DocumentId;
+
+use crate::automerge_file;
+use crate::schema;
+
+/// File extensions offered and accepted for import.
+pub(crate) const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// A file to create the new document from on startup.
+pub(crate) enum Import {
+    Markdown(ImportedFile),
+    /// A whole document saved by glyphcaster or other automerge tooling.
+    Automerge(Automerge),
+}
+
+/// The contents of a file to become a new document.
+#[derive(Debug, Clone)]
+pub(crate) struct ImportedFile {
+    pub(crate) title: String,
+    pub(crate) content: String,
+}
+
+impl ImportedFile {
+    /// Reads a markdown file, titling the document after the file name.
+    pub(crate) fn read(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let title = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "Untitled".to_string());
+        Ok(Self { title, content })
+    }
+}
+
+pub(crate) fn is_automerge(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(automerge_file::EXTENSION))
+}
+
+pub(crate) fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| MARKDOWN_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Creates a new document in samod from the markdown or automerge file at `path`.
+pub(crate) async fn import_file(
+    samod: &samod::Samod,
+    node_id: iroh::NodeId,
+    path: &Path,
+) -> anyhow::Result<DocumentId> {
+    if is_automerge(path) {
+        return automerge_file::load_new(samod, node_id, path).await;
+    }
+    let file = ImportedFile::read(path)?;
+    let doc = schema::new_document(&file.title, &file.content, node_id)?;
+    let handle = samod.create(doc).await?;
+    tracing::info!(path = %path.display(), id = %handle.document_id(), "imported file");
+    Ok(handle.document_id())
+}
+
+/// Imports a file picked or dropped in the UI and shows how to open the new document.
+pub(crate) async fn import_and_show(
+    parent: &impl IsA<gtk::Widget>,
+    samod: &samod::Samod,
+    node_id: iroh::NodeId,
+    path: &Path,
+) {
+    let result = import_file(samod, node_id, path).await;
+    show_imported(parent, node_id, path, result);
+}
+
+/// Shows the connection string of a document created from the file at `path`,
+/// or why creating it failed.
+pub(crate) fn show_imported(
+    parent: &impl IsA<gtk::Widget>,
+    node_id: iroh::NodeId,
+    path: &Path,
+    result: anyhow::Result<DocumentId>,
+) {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let (heading, body, ticket) = match result {
+        Ok(doc_id) => {
+            let ticket = format!("automerge:{doc_id} {node_id}");
+            (
+                format!("Imported {file_name}"),
+                format!("Open or share the new document with:\n\n{ticket}"),
+                Some(ticket),
+            )
+        }
+        Err(e) => (
+            format!("Failed to Import {file_name}"),
+            format!("{e:#}"),
+            None,
+        ),
+    };
+
+    let dialog = adw::AlertDialog::new(Some(&heading), Some(&body));
+    dialog.set_body_use_markup(false);
+    dialog.add_response("close", "Close");
+    dialog.set_close_response("close");
+    if let Some(ticket) = ticket {
+        dialog.add_response("copy", "Copy Connection String");
+        dialog.set_response_appearance("copy", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("copy"));
+        dialog.connect_response(Some("copy"), move |dialog, _| {
+            dialog.clipboard().set_text(&ticket);
+        });
+    }
+    dialog.present(Some(parent));
+}
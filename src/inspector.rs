@@ -0,0 +1,38 @@
+use automerge::AutoSerde;
+use gtk::prelude::*;
+use samod::DocHandle;
+
+/// Renders the whole document as pretty-printed JSON.
+pub fn document_to_json(handle: &DocHandle) -> String {
+    handle.with_document(|doc| {
+        serde_json::to_string_pretty(&AutoSerde::from(&*doc))
+            .unwrap_or_else(|e| format!("Failed to serialize document: {e}"))
+    })
+}
+
+/// Opens a read-only window showing the raw document contents, no matter
+/// what shape the document has.
+pub fn show_inspector(parent: &impl IsA<gtk::Window>, handle: &DocHandle) {
+    let window = gtk::Window::builder()
+        .title(format!("Inspect automerge:{}", handle.document_id()))
+        .transient_for(parent)
+        .default_width(600)
+        .default_height(500)
+        .build();
+
+    let text_view = gtk::TextView::new();
+    text_view.set_editable(false);
+    text_view.set_monospace(true);
+    text_view.set_left_margin(8);
+    text_view.set_top_margin(8);
+    text_view.buffer().set_text(&document_to_json(handle));
+
+    let scroll = gtk::ScrolledWindow::builder()
+        .hexpand(true)
+        .vexpand(true)
+        .child(&text_view)
+        .build();
+
+    window.set_child(Some(&scroll));
+    window.present();
+}
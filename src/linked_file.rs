@@ -0,0 +1,185 @@
+//! Linked files: keeping the edited text and a markdown file on disk in sync.
+//!
+//! External changes to the file are diffed against the document and applied as
+//! a regular change, document changes are written back to the file atomically.
+//! That way other tools like editors, git or static site generators can work
+//! on the same text.
+
+use std::{
+    cell::{Cell, RefCell},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Context as _;
+use automerge::transaction::Transactable;
+use gtk::{gio, prelude::*};
+
+use crate::fs::write_atomically;
+use crate::sync::TextSynchronizer;
+
+#[derive(Clone)]
+pub(crate) struct LinkedFile {
+    path: Rc<PathBuf>,
+    sync: TextSynchronizer,
+    monitor: gio::FileMonitor,
+    /// The file contents as last read or written by us, to tell our own writes
+    /// apart from external ones.
+    known_contents: Rc<RefCell<Option<String>>>,
+    active: Rc<Cell<bool>>,
+}
+
+impl LinkedFile {
+    /// Starts keeping the text and the file at `path` in sync.
+    ///
+    /// An existing file wins over the document, so linking never overwrites
+    /// work done outside of glyphcaster. Otherwise the file gets created.
+    pub(crate) fn link(sync: &TextSynchronizer, path: PathBuf) -> anyhow::Result<Self> {
+        let monitor = gio::File::for_path(&path)
+            .monitor_file(gio::FileMonitorFlags::WATCH_MOVES, gio::Cancellable::NONE)
+            .with_context(|| format!("failed to watch {}", path.display()))?;
+
+        let linked = Self {
+            path: Rc::new(path),
+            sync: sync.clone(),
+            monitor,
+            known_contents: Rc::new(RefCell::new(None)),
+            active: Rc::new(Cell::new(true)),
+        };
+        linked.sync(true)?;
+
+        linked.monitor.connect_changed({
+            let linked = linked.clone();
+            move |_, _, _, event| {
+                // Wait for writers to finish instead of reading half-written files
+                if !matches!(
+                    event,
+                    gio::FileMonitorEvent::ChangesDoneHint
+                        | gio::FileMonitorEvent::Created
+                        | gio::FileMonitorEvent::MovedIn
+                        | gio::FileMonitorEvent::Renamed
+                ) {
+                    return;
+                }
+                if let Err(e) = linked.sync(false) {
+                    tracing::warn!(e = format!("{e:#}"), "failed to read linked file");
+                }
+            }
+        });
+        sync.connect_reconciled({
+            let linked = linked.clone();
+            move || {
+                if let Err(e) = linked.sync(true) {
+                    tracing::warn!(e = format!("{e:#}"), "failed to write linked file");
+                }
+            }
+        });
+
+        tracing::info!(path = %linked.path.display(), "linked file");
+        Ok(linked)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops syncing, leaving the file as it is.
+    pub(crate) fn unlink(&self) {
+        self.active.set(false);
+        self.monitor.cancel();
+        tracing::info!(path = %self.path.display(), "unlinked file");
+    }
+
+    /// Brings the file and the text back in sync, see [`reconcile`]. A missing
+    /// file is only created if `create` is set, as editors replacing the file
+    /// may briefly leave no file behind.
+    fn sync(&self, create: bool) -> anyhow::Result<()> {
+        if !self.active.get() {
+            return Ok(());
+        }
+        let file = match std::fs::read_to_string(&*self.path) {
+            Ok(contents) => Some(contents),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && create => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", self.path.display()));
+            }
+        };
+        let buffer = self.sync.buffer();
+        let (start, end) = buffer.bounds();
+        let text = buffer.text(&start, &end, false).to_string();
+
+        let known = self.known_contents.borrow().clone();
+        let contents = match reconcile(known.as_deref(), file.as_deref(), &text) {
+            Reconcile::Nothing => text,
+            Reconcile::WriteFile => {
+                write_atomically(&self.path, &text)?;
+                text
+            }
+            Reconcile::ReadFile => {
+                let contents = file.unwrap_or_default();
+                self.sync
+                    .change_text("Update from linked file", |tx, text_obj_id| {
+                        tx.update_text(text_obj_id, &contents)
+                    })?;
+                contents
+            }
+        };
+        *self.known_contents.borrow_mut() = Some(contents);
+        Ok(())
+    }
+}
+
+/// What it takes to bring a linked file and the text back in sync.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reconcile {
+    Nothing,
+    WriteFile,
+    /// Also when both changed: edits to the text are kept in the document's
+    /// history, but the file's would be lost.
+    ReadFile,
+}
+
+/// Decides how to reconcile the `file` contents with the `text`, given the
+/// `known` file contents as last read or written by us.
+fn reconcile(known: Option<&str>, file: Option<&str>, text: &str) -> Reconcile {
+    match file {
+        Some(file) if file == text => Reconcile::Nothing,
+        // An existing file that was never read wins too
+        Some(file) if known != Some(file) => Reconcile::ReadFile,
+        _ => Reconcile::WriteFile,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_files_matching_the_text_alone() {
+        assert_eq!(reconcile(None, Some("a"), "a"), Reconcile::Nothing);
+        assert_eq!(reconcile(Some("a"), Some("a"), "a"), Reconcile::Nothing);
+        // Both changed the same way
+        assert_eq!(reconcile(Some("a"), Some("b"), "b"), Reconcile::Nothing);
+    }
+
+    #[test]
+    fn writes_text_changes() {
+        assert_eq!(reconcile(Some("a"), Some("a"), "b"), Reconcile::WriteFile);
+        // A missing file gets created
+        assert_eq!(reconcile(None, None, "b"), Reconcile::WriteFile);
+        assert_eq!(reconcile(Some("a"), None, "a"), Reconcile::WriteFile);
+    }
+
+    #[test]
+    fn reads_file_changes() {
+        assert_eq!(reconcile(Some("a"), Some("b"), "a"), Reconcile::ReadFile);
+        // Linking to an existing file
+        assert_eq!(reconcile(None, Some("b"), "a"), Reconcile::ReadFile);
+    }
+
+    #[test]
+    fn prefers_the_file_when_both_changed() {
+        assert_eq!(reconcile(Some("a"), Some("b"), "c"), Reconcile::ReadFile);
+    }
+}
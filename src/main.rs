@@ -1,19 +1,47 @@
+use automerge::ChangeHash;
 use gio::ApplicationFlags;
 use glib::ExitCode;
 use gtk::prelude::*;
 use samod::DocumentId;
+use std::ffi::OsString;
 use std::str::FromStr;
 
 mod app_state;
+mod authors;
+mod automerge_file;
+mod blame;
+mod comments;
 mod document_loader;
+mod drafts;
+mod error;
+mod export;
+mod fs;
+mod git;
+mod history;
+mod import;
+mod inspector;
+mod linked_file;
+mod marks;
+mod notifications;
+mod outline;
+mod preferences;
+mod preview;
+mod schema;
+mod search;
+mod share;
+mod snapshots;
 mod sync;
+mod text_path;
 
 use app_state::AppState;
 use document_loader::DocumentLoader;
+use import::{Import, ImportedFile};
+use share::Invite;
+use text_path::TextPath;
 
 const APP_ID: &str = "xyz.patternist.glyphcaster";
 
-fn main() {
+fn main() -> ExitCode {
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         // .with_thread_ids(true)
@@ -22,7 +50,12 @@ fn main() {
         // .with_target(false)
         .init();
 
+    if let Some(exit_code) = run_subcommand(std::env::args_os().collect()) {
+        return exit_code;
+    }
+
     let application = adw::Application::new(Some(APP_ID), ApplicationFlags::HANDLES_COMMAND_LINE);
+    application.connect_startup(preferences::setup);
     application.connect_command_line(move |app, cli| {
         let env = cli.environ();
         let iroh_secret = env
@@ -31,8 +64,91 @@ fn main() {
             .find_map(|env| env.strip_prefix("IROH_SECRET="))
             .map(String::from);
 
-        let doc_id = if cli.arguments().len() > 1 {
-            let Some(automerge_url) = cli.arguments().get(1).cloned() else {
+        let mut arguments = cli.arguments();
+
+        let text_path = match take_text_path(&mut arguments) {
+            Ok(text_path) => text_path,
+            Err(exit_code) => return exit_code,
+        };
+
+        // `--link <file>` keeps the text in sync with a markdown file on disk
+        let linked_file = if let Some(flag_pos) = arguments.iter().position(|arg| arg == "--link") {
+            arguments.remove(flag_pos);
+            if flag_pos >= arguments.len() {
+                eprintln!("--link requires a file, e.g. --link notes.md");
+                return ExitCode::FAILURE;
+            }
+            let file = arguments.remove(flag_pos);
+            let Some(path) = cli.create_file_for_arg(&file).path() else {
+                eprintln!("{} is not a local file", file.to_string_lossy());
+                return ExitCode::FAILURE;
+            };
+            Some(path)
+        } else {
+            None
+        };
+
+        // `--addr <ip:port>` lets the peer be reached directly, it can be given several times
+        let mut direct_addresses = Vec::new();
+        while let Some(flag_pos) = arguments.iter().position(|arg| arg == "--addr") {
+            arguments.remove(flag_pos);
+            if flag_pos >= arguments.len() {
+                eprintln!("--addr requires an address, e.g. --addr 192.168.1.5:4433");
+                return ExitCode::FAILURE;
+            }
+            let addr = arguments.remove(flag_pos);
+            match addr.to_string_lossy().parse() {
+                Ok(addr) => direct_addresses.push(addr),
+                Err(e) => {
+                    eprintln!("Invalid address {}: {e}", addr.to_string_lossy());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+
+        // `--read-only` opens the editor without editing, as shared by read-only links
+        let read_only =
+            if let Some(flag_pos) = arguments.iter().position(|arg| arg == "--read-only") {
+                arguments.remove(flag_pos);
+                true
+            } else {
+                false
+            };
+
+        // `import <file>` creates a new document from a markdown file
+        let import = if arguments.get(1).is_some_and(|arg| arg == "import") {
+            let Some(file) = arguments.get(2) else {
+                eprintln!("import requires a file, e.g. glyphcaster import notes.md");
+                return ExitCode::FAILURE;
+            };
+            let Some(path) = cli.create_file_for_arg(file).path() else {
+                eprintln!("{} is not a local file", file.to_string_lossy());
+                return ExitCode::FAILURE;
+            };
+            let file = if import::is_automerge(&path) {
+                automerge_file::load(&path).map(Import::Automerge)
+            } else {
+                ImportedFile::read(&path).map(Import::Markdown)
+            };
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Failed to import: {e:#}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            arguments.drain(1..3);
+            if arguments.len() > 1 {
+                eprintln!("import creates a new document and doesn't take a document ID");
+                return ExitCode::FAILURE;
+            }
+            Some(file)
+        } else {
+            None
+        };
+
+        let doc_id = if arguments.len() > 1 {
+            let Some(automerge_url) = arguments.get(1).cloned() else {
                 eprintln!("No automerge URL provided");
                 return ExitCode::FAILURE;
             };
@@ -55,7 +171,12 @@ fn main() {
             None
         };
 
-        let node_id = if let Some(node_id) = cli.arguments().get(2).cloned() {
+        if doc_id.is_none() && text_path != TextPath::default() {
+            eprintln!("--path can only be used when opening an existing document");
+            return ExitCode::FAILURE;
+        }
+
+        let invite = if let Some(node_id) = arguments.get(2).cloned() {
             let Some(node_id) = node_id.to_str() else {
                 eprintln!("node ID was not a valid UTF-8 string");
                 return ExitCode::FAILURE;
@@ -65,13 +186,28 @@ fn main() {
                     eprintln!("Invalid node ID {node_id}: {e}");
                     return ExitCode::FAILURE;
                 }
-                Ok(node_id) => Some(node_id),
+                Ok(node_id) => Some(Invite {
+                    node_id,
+                    direct_addresses,
+                    read_only,
+                }),
             }
+        } else if read_only || !direct_addresses.is_empty() {
+            eprintln!("--addr and --read-only only apply when connecting to a node ID");
+            return ExitCode::FAILURE;
         } else {
             None
         };
 
-        let app_state = AppState::new(app, doc_id, node_id, iroh_secret);
+        let app_state = AppState::new(
+            app,
+            doc_id,
+            invite,
+            iroh_secret,
+            text_path,
+            import,
+            linked_file,
+        );
 
         // Show the window
         app_state.window.present();
@@ -82,5 +218,125 @@ fn main() {
         ExitCode::SUCCESS
     });
 
-    application.run();
+    application.run()
+}
+
+/// Runs the subcommands that work on stored documents without opening the editor.
+///
+/// They run before the application is registered, as a running instance would
+/// otherwise receive the command line and run them in its own process.
+fn run_subcommand(mut arguments: Vec<OsString>) -> Option<ExitCode> {
+    let text_path = match take_text_path(&mut arguments) {
+        Ok(text_path) => text_path,
+        Err(exit_code) => return Some(exit_code),
+    };
+
+    // `--since <heads>` limits `.automerge` exports to the changes after the given heads
+    let since = if let Some(flag_pos) = arguments.iter().position(|arg| arg == "--since") {
+        arguments.remove(flag_pos);
+        if flag_pos >= arguments.len() {
+            eprintln!("--since requires the heads printed by a previous export");
+            return Some(ExitCode::FAILURE);
+        }
+        let heads = arguments.remove(flag_pos);
+        match automerge_file::parse_heads(&heads.to_string_lossy()) {
+            Ok(heads) => heads,
+            Err(e) => {
+                eprintln!("Invalid heads: {e:#}");
+                return Some(ExitCode::FAILURE);
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    match arguments.get(1) {
+        Some(arg) if arg == "export" => Some(run_export(&arguments, &text_path, &since)),
+        _ if !since.is_empty() => {
+            eprintln!("--since can only be used with export");
+            Some(ExitCode::FAILURE)
+        }
+        Some(arg) if arg == "git-commit" => Some(run_git_commit(&arguments, &text_path)),
+        _ => None,
+    }
+}
+
+/// Runs `export <automerge URL> <file>`, which writes a stored document out without opening
+/// the editor.
+fn run_export(arguments: &[OsString], text_path: &TextPath, since: &[ChangeHash]) -> ExitCode {
+    let (Some(url), Some(output)) = (arguments.get(2), arguments.get(3)) else {
+        eprintln!(
+            "usage: glyphcaster export automerge:<document ID> \
+             <file.md|file.html|file.automerge> [--since <heads>]"
+        );
+        return ExitCode::FAILURE;
+    };
+    let doc_id = url
+        .to_str()
+        .and_then(|url| url.trim().strip_prefix("automerge:"))
+        .map(DocumentId::from_str);
+    let Some(Ok(doc_id)) = doc_id else {
+        eprintln!("Invalid automerge URL {}", url.to_string_lossy());
+        return ExitCode::FAILURE;
+    };
+    let output = match std::path::absolute(output) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Invalid file {}: {e}", output.to_string_lossy());
+            return ExitCode::FAILURE;
+        }
+    };
+    if import::is_automerge(&output) {
+        return automerge_file::run_save_cli(doc_id, since, &output);
+    }
+    if !since.is_empty() {
+        eprintln!("--since only works when exporting to an .automerge file");
+        return ExitCode::FAILURE;
+    }
+    export::run_cli(doc_id, text_path, &output)
+}
+
+/// Runs `git-commit <automerge URL> <file>`, which commits the text into a git working tree.
+fn run_git_commit(arguments: &[OsString], text_path: &TextPath) -> ExitCode {
+    let (Some(url), Some(file)) = (arguments.get(2), arguments.get(3)) else {
+        eprintln!("usage: glyphcaster git-commit automerge:<document ID> <file.md>");
+        return ExitCode::FAILURE;
+    };
+    let doc_id = url
+        .to_str()
+        .and_then(|url| url.trim().strip_prefix("automerge:"))
+        .map(DocumentId::from_str);
+    let Some(Ok(doc_id)) = doc_id else {
+        eprintln!("Invalid automerge URL {}", url.to_string_lossy());
+        return ExitCode::FAILURE;
+    };
+    let file = match std::path::absolute(file) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Invalid file {}: {e}", file.to_string_lossy());
+            return ExitCode::FAILURE;
+        }
+    };
+    git::run_cli(doc_id, text_path, file)
+}
+
+/// Takes `--path <path>`, which selects the text object to edit, e.g. `notes[3].body`.
+fn take_text_path(arguments: &mut Vec<OsString>) -> Result<TextPath, ExitCode> {
+    let Some(flag_pos) = arguments.iter().position(|arg| arg == "--path") else {
+        return Ok(TextPath::default());
+    };
+    arguments.remove(flag_pos);
+    if flag_pos >= arguments.len() {
+        eprintln!("--path requires a value, e.g. --path notes[3].body");
+        return Err(ExitCode::FAILURE);
+    }
+    let path = arguments.remove(flag_pos);
+    let Some(path) = path.to_str() else {
+        eprintln!("path was not a valid UTF-8 string");
+        return Err(ExitCode::FAILURE);
+    };
+    TextPath::from_str(path).map_err(|e| {
+        eprintln!("Invalid path {path}: {e}");
+        ExitCode::FAILURE
+    })
 }
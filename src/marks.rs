@@ -0,0 +1,120 @@
+//! Maps automerge marks to `gtk::TextTag`s on the editor buffer.
+//!
+//! Marks are stored in the document next to the text, so formatting doesn't
+//! have to be written into the markdown source.
+
+use automerge::{ScalarValue, marks::ExpandMark, marks::Mark};
+use gtk::prelude::*;
+
+/// The marks glyphcaster knows how to display.
+pub(crate) const BOLD: &str = "bold";
+pub(crate) const ITALIC: &str = "italic";
+pub(crate) const LINK: &str = "link";
+pub(crate) const COMMENT: &str = "comment";
+
+const TAG_PREFIX: &str = "mark:";
+
+/// How a mark grows when text is typed at its edges, following the conventions
+/// of other automerge rich text editors.
+pub(crate) fn expand_for(name: &str) -> ExpandMark {
+    match name {
+        BOLD | ITALIC => ExpandMark::After,
+        _ => ExpandMark::None,
+    }
+}
+
+/// The name of the tag displaying the mark `name` with `value`.
+///
+/// Marks with a value that matters for display (like a link's URL) get a tag
+/// per value, boolean marks share one tag.
+pub(crate) fn tag_name(name: &str, value: &ScalarValue) -> String {
+    match (name, value) {
+        (LINK, ScalarValue::Str(url)) => format!("{TAG_PREFIX}{LINK}:{url}"),
+        _ => format!("{TAG_PREFIX}{name}"),
+    }
+}
+
+/// Looks up or creates the tag for a mark. Returns `None` for marks that
+/// can't be displayed.
+pub(crate) fn ensure_tag(
+    buffer: &impl IsA<gtk::TextBuffer>,
+    name: &str,
+    value: &ScalarValue,
+) -> Option<gtk::TextTag> {
+    let tag_name = tag_name(name, value);
+    let tag_table = buffer.as_ref().tag_table();
+    if let Some(tag) = tag_table.lookup(&tag_name) {
+        return Some(tag);
+    }
+
+    let builder = gtk::TextTag::builder().name(tag_name.as_str());
+    let tag = match name {
+        BOLD => builder.weight(700).build(),
+        ITALIC => builder.style(gtk::pango::Style::Italic).build(),
+        LINK => builder
+            .underline(gtk::pango::Underline::Single)
+            .foreground("#1c71d8")
+            .build(),
+        COMMENT => builder.background("rgba(246, 211, 45, 0.45)").build(),
+        _ => return None,
+    };
+    tag_table.add(&tag);
+    Some(tag)
+}
+
+/// Replaces all mark tags in the buffer with the given marks.
+pub(crate) fn apply(buffer: &impl IsA<gtk::TextBuffer>, marks: &[Mark<'_>]) {
+    let buffer = buffer.as_ref();
+    let (start, end) = buffer.bounds();
+
+    let mut mark_tags = Vec::new();
+    buffer.tag_table().foreach(|tag| {
+        if tag.name().is_some_and(|name| name.starts_with(TAG_PREFIX)) {
+            mark_tags.push(tag.clone());
+        }
+    });
+    for tag in &mark_tags {
+        buffer.remove_tag(tag, &start, &end);
+    }
+
+    for mark in marks {
+        if mark.value().is_null() {
+            continue;
+        }
+        let Some(tag) = ensure_tag(buffer, mark.name(), mark.value()) else {
+            continue;
+        };
+        let start = buffer.iter_at_offset(mark.start as i32);
+        let end = buffer.iter_at_offset(mark.end as i32);
+        buffer.apply_tag(&tag, &start, &end);
+    }
+}
+
+/// Whether every character between `start` and `end` displays the mark `name`.
+pub(crate) fn range_has_mark(
+    buffer: &impl IsA<gtk::TextBuffer>,
+    name: &str,
+    start: i32,
+    end: i32,
+) -> bool {
+    let buffer = buffer.as_ref();
+    let prefix = tag_name(name, &ScalarValue::Null);
+    start < end
+        && (start..end).all(|offset| {
+            buffer.iter_at_offset(offset).tags().iter().any(|tag| {
+                tag.name()
+                    .is_some_and(|tag_name| tag_name.starts_with(&prefix))
+            })
+        })
+}
+
+/// Returns the URL of a link at `offset`, if there is one.
+pub(crate) fn link_at(buffer: &impl IsA<gtk::TextBuffer>, offset: i32) -> Option<String> {
+    let prefix = format!("{TAG_PREFIX}{LINK}:");
+    buffer
+        .as_ref()
+        .iter_at_offset(offset)
+        .tags()
+        .iter()
+        .find_map(|tag| Some(tag.name()?.strip_prefix(&prefix)?.to_string()))
+}
@@ -0,0 +1,190 @@
+//! Toasts about things happening in the background, like peers coming and
+//! going or files being saved.
+//!
+//! Connections come and go a lot, so notifications about the same subject are
+//! rate limited: within the interval, a newer notification only updates the
+//! toast if it's still shown. Peers also have to be gone for a while before
+//! they count as having left, so short gaps don't show up at all.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// How long a peer has to be missing from the remote infos to count as gone.
+const PEER_GONE_AFTER: Duration = Duration::from_secs(10);
+
+pub(crate) enum Notification {
+    LinkCopied,
+    DiagnosticsCopied,
+    PeerJoined(iroh::NodeId),
+    PeerLeft(iroh::NodeId),
+    /// The node given on the command line, which the document is synced with.
+    ConnectionLost(iroh::NodeId),
+    Reconnected(iroh::NodeId),
+    SyncError(String),
+    /// Opened without the relay or the node from the connection string.
+    WorkingOffline,
+    Saved(String),
+    SaveFailed(String),
+    /// A git commit of the text, by its short hash.
+    Committed(String),
+    AlreadyCommitted(String),
+}
+
+fn short_node_id(node: &iroh::NodeId) -> String {
+    format!(
+        "{}...",
+        node.to_string().chars().take(12).collect::<String>()
+    )
+}
+
+impl Notification {
+    pub(crate) fn file_saved(path: &Path, saved: bool) -> Self {
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        if saved {
+            Self::Saved(name)
+        } else {
+            Self::SaveFailed(name)
+        }
+    }
+
+    /// Notifications with the same key are about the same subject.
+    fn key(&self) -> String {
+        match self {
+            Self::LinkCopied => "link".to_string(),
+            Self::DiagnosticsCopied => "diagnostics".to_string(),
+            Self::PeerJoined(node) | Self::PeerLeft(node) => format!("peer:{node}"),
+            Self::ConnectionLost(node) | Self::Reconnected(node) => format!("connection:{node}"),
+            Self::SyncError(_) => "sync-error".to_string(),
+            Self::WorkingOffline => "offline".to_string(),
+            Self::Saved(name) | Self::SaveFailed(name) | Self::AlreadyCommitted(name) => {
+                format!("save:{name}")
+            }
+            Self::Committed(commit) => format!("commit:{commit}"),
+        }
+    }
+
+    fn title(&self) -> String {
+        match self {
+            Self::LinkCopied => "Connection string copied".to_string(),
+            Self::DiagnosticsCopied => "Diagnostics copied".to_string(),
+            Self::PeerJoined(node) => format!("Peer {} joined", short_node_id(node)),
+            Self::PeerLeft(node) => format!("Peer {} left", short_node_id(node)),
+            Self::ConnectionLost(node) => {
+                format!("Lost connection to {}, retrying", short_node_id(node))
+            }
+            Self::Reconnected(node) => format!("Reconnected to {}", short_node_id(node)),
+            Self::SyncError(e) => format!("Sync problem: {e}"),
+            Self::WorkingOffline => {
+                "Working offline, only peers on the local network can connect".to_string()
+            }
+            Self::Saved(name) => format!("Saved {name}"),
+            Self::SaveFailed(name) => format!("Couldn't save {name}"),
+            Self::Committed(commit) => format!("Created commit {commit}"),
+            Self::AlreadyCommitted(name) => format!("{name} is already up to date"),
+        }
+    }
+
+    /// The least time between two toasts with the same key.
+    fn min_interval(&self) -> Duration {
+        match self {
+            Self::LinkCopied
+            | Self::DiagnosticsCopied
+            | Self::WorkingOffline
+            | Self::Saved(_)
+            | Self::SaveFailed(_)
+            | Self::Committed(_)
+            | Self::AlreadyCommitted(_) => Duration::ZERO,
+            Self::PeerJoined(_)
+            | Self::PeerLeft(_)
+            | Self::ConnectionLost(_)
+            | Self::Reconnected(_) => Duration::from_secs(30),
+            Self::SyncError(_) => Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    overlay: adw::ToastOverlay,
+    /// The last toast for each key, and when it was added.
+    shown: Rc<RefCell<HashMap<String, (adw::Toast, Instant)>>>,
+    /// When each connected peer was last seen, `None` before the first update.
+    peers: Rc<RefCell<Option<HashMap<iroh::NodeId, Instant>>>>,
+}
+
+impl Notifier {
+    pub(crate) fn new(overlay: &adw::ToastOverlay) -> Self {
+        Self {
+            overlay: overlay.clone(),
+            shown: Rc::new(RefCell::new(HashMap::new())),
+            peers: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    pub(crate) fn notify(&self, notification: Notification) {
+        let key = notification.key();
+        let title = notification.title();
+        let mut shown = self.shown.borrow_mut();
+        let recent = shown
+            .get(&key)
+            .filter(|(_, added)| added.elapsed() < notification.min_interval());
+        if let Some((toast, _)) = recent {
+            toast.set_title(&title);
+            return;
+        }
+        let toast = adw::Toast::builder()
+            .title(&title)
+            .use_markup(false)
+            .build();
+        self.overlay.add_toast(toast.clone());
+        shown.insert(key, (toast, Instant::now()));
+    }
+
+    /// Tells about peers joining and leaving, given the currently connected ones.
+    /// `sync_peer` is the node the document is synced with, if any.
+    pub(crate) fn update_peers(
+        &self,
+        connected: impl IntoIterator<Item = iroh::NodeId>,
+        sync_peer: Option<iroh::NodeId>,
+    ) {
+        let now = Instant::now();
+        let mut peers = self.peers.borrow_mut();
+        // Peers that are there from the start aren't news
+        let first_update = peers.is_none();
+        let peers = peers.get_or_insert_with(HashMap::new);
+
+        let mut notifications = Vec::new();
+        for node in connected {
+            if peers.insert(node, now).is_none() && !first_update {
+                notifications.push(if Some(node) == sync_peer {
+                    Notification::Reconnected(node)
+                } else {
+                    Notification::PeerJoined(node)
+                });
+            }
+        }
+        peers.retain(|node, last_seen| {
+            let gone = now.duration_since(*last_seen) > PEER_GONE_AFTER;
+            if gone {
+                notifications.push(if Some(*node) == sync_peer {
+                    Notification::ConnectionLost(*node)
+                } else {
+                    Notification::PeerLeft(*node)
+                });
+            }
+            !gone
+        });
+
+        for notification in notifications {
+            self.notify(notification);
+        }
+    }
+}
@@ -0,0 +1,557 @@
+//! The outline of the edited markdown, and which section each peer is in.
+//!
+//! Peers tell each other where their caret is with samod's ephemeral
+//! messages, so presence never ends up in the document's history. A message
+//! is only sent when the caret settles in another section, and repeated now
+//! and then so peers that connect later learn about it too:
+//!
+//! ```typescript
+//! type PresenceMessage =
+//!   | {
+//!       type: "presence"
+//!       node: string // iroh node ID
+//!       target: string // The text object the peer is editing, e.g. "content"
+//!       cursor: string // Automerge cursor of the peer's caret
+//!     }
+//!   | { type: "leave", node: string }
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use automerge::{ChangeHash, Cursor, CursorPosition, ReadDoc};
+use futures::StreamExt;
+use gtk::{glib, prelude::*};
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag, TagEnd};
+use serde_json::json;
+use sourceview5::prelude::*;
+
+use crate::blame::author_color;
+use crate::error::DocumentError;
+use crate::export::markdown_options;
+use crate::sync::TextSynchronizer;
+use crate::text_path::TextPath;
+
+/// Peers that weren't heard of for this long most likely quit without
+/// saying so.
+const PRESENCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to repeat where the caret is, well within `PRESENCE_TIMEOUT`.
+const PRESENCE_HEARTBEAT: Duration = Duration::from_secs(20);
+
+/// How long the caret has to stay in a section before telling peers.
+const PRESENCE_DELAY: Duration = Duration::from_secs(1);
+
+/// A heading of the markdown, in buffer offsets.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Heading {
+    pub(crate) level: HeadingLevel,
+    pub(crate) title: String,
+    pub(crate) offset: usize,
+}
+
+/// Finds the headings of the markdown, in order.
+pub(crate) fn read_headings(markdown: &str) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut current: Option<Heading> = None;
+    let (mut counted_bytes, mut counted_chars) = (0, 0);
+    for (event, range) in Parser::new_ext(markdown, markdown_options()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                counted_chars += markdown[counted_bytes..range.start].chars().count();
+                counted_bytes = range.start;
+                current = Some(Heading {
+                    level,
+                    title: String::new(),
+                    offset: counted_chars,
+                });
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if let Some(heading) = current.as_mut() {
+                    heading.title.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::Heading(_)) => headings.extend(current.take()),
+            _ => {}
+        }
+    }
+    headings
+}
+
+/// Where a remote peer's caret is, as last heard from it.
+#[derive(Debug, Clone, PartialEq)]
+struct PeerPresence {
+    target: String,
+    cursor: Cursor,
+    received: Instant,
+}
+
+/// A presence message from a peer, see the module docs.
+#[derive(Debug, Clone, PartialEq)]
+enum PresenceMessage {
+    Presence {
+        node: String,
+        target: String,
+        cursor: Cursor,
+    },
+    Leave {
+        node: String,
+    },
+}
+
+impl PresenceMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let message = match self {
+            Self::Presence {
+                node,
+                target,
+                cursor,
+            } => json!({
+                "type": "presence",
+                "node": node,
+                "target": target,
+                "cursor": cursor.to_string(),
+            }),
+            Self::Leave { node } => json!({ "type": "leave", "node": node }),
+        };
+        message.to_string().into_bytes()
+    }
+
+    /// Parses a message, `None` for messages that aren't about presence.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let message: serde_json::Value = serde_json::from_slice(bytes).ok()?;
+        let field = |name: &str| message.get(name)?.as_str().map(str::to_string);
+        let node = field("node")?;
+        match message.get("type")?.as_str()? {
+            "presence" => Some(Self::Presence {
+                node,
+                target: field("target")?,
+                cursor: Cursor::try_from(field("cursor")?.as_str()).ok()?,
+            }),
+            "leave" => Some(Self::Leave { node }),
+            _ => None,
+        }
+    }
+}
+
+/// A cursor for the caret at `offset` in the text at `heads`. The end of the
+/// text gets a cursor that stays at the end, as there's no character to
+/// point at.
+fn caret_cursor<R: ReadDoc>(
+    doc: &R,
+    target: &TextPath,
+    offset: usize,
+    heads: &[ChangeHash],
+) -> Result<Cursor, DocumentError> {
+    let text_obj_id = target.resolve_at(doc, heads)?;
+    let cursor = if offset >= doc.length_at(&text_obj_id, heads) {
+        doc.get_cursor(&text_obj_id, CursorPosition::End, Some(heads))?
+    } else {
+        doc.get_cursor(&text_obj_id, offset, Some(heads))?
+    };
+    Ok(cursor)
+}
+
+/// Resolves where the peers editing `target` are, as node IDs and text offsets.
+fn locate_peers<R: ReadDoc>(
+    doc: &R,
+    target: &TextPath,
+    peers: &HashMap<String, PeerPresence>,
+) -> Vec<(String, usize)> {
+    let Ok(text_obj_id) = target.resolve(doc) else {
+        return Vec::new();
+    };
+    let target = target.to_string();
+    peers
+        .iter()
+        .filter(|(_, presence)| {
+            presence.target == target && presence.received.elapsed() < PRESENCE_TIMEOUT
+        })
+        .filter_map(|(node, presence)| {
+            // The peer's caret may be in changes that haven't arrived yet
+            let offset = doc
+                .get_cursor_position(&text_obj_id, &presence.cursor, None)
+                .ok()?;
+            Some((node.clone(), offset))
+        })
+        .collect()
+}
+
+/// A line of the outline: a heading and the remote peers in its section.
+#[derive(Debug, Clone, PartialEq)]
+struct OutlineEntry {
+    heading: Heading,
+    peers: Vec<String>,
+}
+
+/// The index of the section containing `offset`, `None` before the first heading.
+fn section_at(entries: &[OutlineEntry], offset: usize) -> Option<usize> {
+    entries
+        .partition_point(|entry| entry.heading.offset <= offset)
+        .checked_sub(1)
+}
+
+/// The outline page of the side pane.
+#[derive(Clone)]
+pub(crate) struct OutlinePanel {
+    pub(crate) container: gtk::Box,
+    list: gtk::ListBox,
+    sync: TextSynchronizer,
+    view: sourceview5::View,
+    local_node: String,
+    entries: Rc<RefCell<Vec<OutlineEntry>>>,
+    /// Remote peers by node ID.
+    peers: Rc<RefCell<HashMap<String, PeerPresence>>>,
+    /// The section last shared with peers, to only send when it changes.
+    shared_section: Rc<Cell<Option<Option<usize>>>>,
+    presence_pending: Rc<Cell<bool>>,
+    heartbeat: Rc<RefCell<Option<glib::SourceId>>>,
+}
+
+impl OutlinePanel {
+    pub(crate) fn new(
+        sync: &TextSynchronizer,
+        view: &sourceview5::View,
+        local_node: String,
+    ) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        container.set_margin_top(8);
+        container.set_margin_bottom(8);
+        container.set_margin_start(8);
+        container.set_margin_end(8);
+
+        let list = gtk::ListBox::new();
+        list.add_css_class("navigation-sidebar");
+        let placeholder = gtk::Label::new(Some("No Headings"));
+        placeholder.set_margin_top(16);
+        placeholder.add_css_class("dim-label");
+        list.set_placeholder(Some(&placeholder));
+
+        let scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&list)
+            .build();
+        container.append(&scroll);
+
+        let panel = Self {
+            container,
+            list,
+            sync: sync.clone(),
+            view: view.clone(),
+            local_node,
+            entries: Rc::new(RefCell::new(Vec::new())),
+            peers: Rc::new(RefCell::new(HashMap::new())),
+            shared_section: Rc::new(Cell::new(None)),
+            presence_pending: Rc::new(Cell::new(false)),
+            heartbeat: Rc::new(RefCell::new(None)),
+        };
+
+        panel.list.connect_row_activated({
+            let panel = panel.clone();
+            move |_, row| panel.jump_to(row.index())
+        });
+        sync.connect_reconciled({
+            let panel = panel.clone();
+            move || panel.refresh()
+        });
+        sync.buffer().connect_cursor_position_notify({
+            let panel = panel.clone();
+            move |_| {
+                panel.select_current_section();
+                panel.schedule_presence();
+            }
+        });
+        glib::spawn_future_local({
+            let panel = panel.clone();
+            async move {
+                let mut messages = panel.sync.handle().ephemera();
+                while let Some(message) = messages.next().await {
+                    if let Some(message) = PresenceMessage::from_bytes(&message) {
+                        panel.receive_presence(message);
+                    }
+                }
+            }
+        });
+        let heartbeat = glib::timeout_add_local(PRESENCE_HEARTBEAT, {
+            let panel = panel.clone();
+            move || {
+                panel
+                    .peers
+                    .borrow_mut()
+                    .retain(|_, presence| presence.received.elapsed() < PRESENCE_TIMEOUT);
+                panel.broadcast_presence();
+                panel.refresh();
+                glib::ControlFlow::Continue
+            }
+        });
+        panel.heartbeat.replace(Some(heartbeat));
+        panel.refresh();
+        panel.broadcast_presence();
+        panel
+    }
+
+    fn caret_offset(&self) -> usize {
+        self.sync.buffer().cursor_position().max(0) as usize
+    }
+
+    fn jump_to(&self, index: i32) {
+        let Some(entry) = self.entries.borrow().get(index as usize).cloned() else {
+            return;
+        };
+        let buffer = self.sync.buffer();
+        let mut iter = buffer.iter_at_offset(entry.heading.offset as i32);
+        buffer.place_cursor(&iter);
+        self.view.scroll_to_iter(&mut iter, 0.0, true, 0.0, 0.1);
+        self.view.grab_focus();
+    }
+
+    /// Highlights the section containing the caret.
+    fn select_current_section(&self) {
+        let row = section_at(&self.entries.borrow(), self.caret_offset())
+            .and_then(|index| self.list.row_at_index(index as i32));
+        self.list.select_row(row.as_ref());
+    }
+
+    /// Shares the caret's section with peers once it settles there.
+    fn schedule_presence(&self) {
+        if self.presence_pending.replace(true) {
+            return;
+        }
+        let panel = self.clone();
+        glib::timeout_add_local_once(PRESENCE_DELAY, move || {
+            panel.presence_pending.set(false);
+            panel.share_presence();
+        });
+    }
+
+    fn share_presence(&self) {
+        let section = section_at(&self.entries.borrow(), self.caret_offset());
+        if self.shared_section.replace(Some(section)) == Some(section) {
+            return;
+        }
+        self.broadcast_presence();
+    }
+
+    fn broadcast_presence(&self) {
+        let target = self.sync.path();
+        // Edits that are still pending may put the caret past the end of the
+        // committed text, which then counts as the end
+        let heads = self.sync.view_heads();
+        let offset = self.caret_offset();
+        let cursor = self
+            .sync
+            .handle()
+            .with_document(|doc| caret_cursor(doc, &target, offset, &heads));
+        match cursor {
+            Ok(cursor) => self.sync.handle().broadcast(
+                PresenceMessage::Presence {
+                    node: self.local_node.clone(),
+                    target: target.to_string(),
+                    cursor,
+                }
+                .to_bytes(),
+            ),
+            Err(e) => tracing::warn!(%e, "failed to share presence"),
+        }
+    }
+
+    fn receive_presence(&self, message: PresenceMessage) {
+        match message {
+            PresenceMessage::Presence {
+                node,
+                target,
+                cursor,
+            } => {
+                if node == self.local_node {
+                    return;
+                }
+                self.peers.borrow_mut().insert(
+                    node,
+                    PeerPresence {
+                        target,
+                        cursor,
+                        received: Instant::now(),
+                    },
+                );
+            }
+            PresenceMessage::Leave { node } => {
+                self.peers.borrow_mut().remove(&node);
+            }
+        }
+        self.refresh();
+    }
+
+    /// Removes this peer from the outline of the others, e.g. when closing.
+    pub(crate) fn clear_presence(&self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.remove();
+        }
+        self.sync.handle().broadcast(
+            PresenceMessage::Leave {
+                node: self.local_node.clone(),
+            }
+            .to_bytes(),
+        );
+    }
+
+    pub(crate) fn refresh(&self) {
+        let buffer = self.sync.buffer();
+        let (start, end) = buffer.bounds();
+        let headings = read_headings(&buffer.text(&start, &end, false));
+
+        let target = self.sync.path();
+        let presence = self
+            .sync
+            .handle()
+            .with_document(|doc| locate_peers(doc, &target, &self.peers.borrow()));
+        let mut entries: Vec<OutlineEntry> = headings
+            .into_iter()
+            .map(|heading| OutlineEntry {
+                heading,
+                peers: Vec::new(),
+            })
+            .collect();
+        for (node, offset) in presence {
+            if let Some(index) = section_at(&entries, offset) {
+                entries[index].peers.push(node);
+            }
+        }
+        // The peers map has no order, keep the badges from jumping around
+        for entry in &mut entries {
+            entry.peers.sort();
+        }
+
+        if *self.entries.borrow() != entries {
+            while let Some(row) = self.list.row_at_index(0) {
+                self.list.remove(&row);
+            }
+            for entry in &entries {
+                self.list.append(&outline_row(entry));
+            }
+            *self.entries.borrow_mut() = entries;
+        }
+        self.select_current_section();
+    }
+}
+
+fn outline_row(entry: &OutlineEntry) -> gtk::Box {
+    let row = gtk::Box::new(gtk::Orientation::Horizontal, 4);
+    row.set_margin_start(12 * (entry.heading.level as i32 - 1));
+
+    let title = gtk::Label::new(Some(&entry.heading.title));
+    title.set_halign(gtk::Align::Start);
+    title.set_hexpand(true);
+    title.set_ellipsize(gtk::pango::EllipsizeMode::End);
+    title.set_tooltip_text(Some(&entry.heading.title));
+    if entry.heading.level == HeadingLevel::H1 {
+        title.add_css_class("heading");
+    }
+    row.append(&title);
+
+    for node in &entry.peers {
+        let badge = gtk::Label::new(None);
+        badge.set_markup(&format!(
+            "<span foreground='{}'>●</span>",
+            author_color(node)
+        ));
+        badge.set_tooltip_text(Some(&format!(
+            "{}... is here",
+            node.chars().take(12).collect::<String>()
+        )));
+        row.append(&badge);
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{Automerge, AutomergeError, ObjType, ROOT, transaction::Transactable};
+
+    use super::*;
+
+    fn heading(level: HeadingLevel, title: &str, offset: usize) -> Heading {
+        Heading {
+            level,
+            title: title.to_string(),
+            offset,
+        }
+    }
+
+    #[test]
+    fn reads_headings_with_char_offsets() {
+        let markdown = "# Grüße\n\nÜber `code` 🎉\n\n## The `main` loop\n\nSetext\n---\n";
+        assert_eq!(
+            read_headings(markdown),
+            [
+                heading(HeadingLevel::H1, "Grüße", 0),
+                heading(HeadingLevel::H2, "The main loop", 24),
+                heading(HeadingLevel::H2, "Setext", 44),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_headings_in_code_blocks() {
+        let markdown = "```\n# Not a heading\n```\n\n### Real";
+        assert_eq!(
+            read_headings(markdown),
+            [heading(HeadingLevel::H3, "Real", 25)]
+        );
+    }
+
+    fn document() -> (Automerge, TextPath) {
+        let mut doc = Automerge::new();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let text_id = tx.put_object(ROOT, "content", ObjType::Text)?;
+            tx.splice_text(&text_id, 0, 0, "Hello")
+        })
+        .unwrap();
+        (doc, "content".parse().unwrap())
+    }
+
+    #[test]
+    fn points_cursors_at_the_caret() {
+        let (mut doc, target) = document();
+        let text_id = target.resolve(&doc).unwrap();
+        let heads = doc.get_heads();
+
+        let middle = caret_cursor(&doc, &target, 2, &heads).unwrap();
+        assert_eq!(doc.get_cursor_position(&text_id, &middle, None).unwrap(), 2);
+
+        // The caret after the last character follows text added at the end
+        let end = caret_cursor(&doc, &target, 5, &heads).unwrap();
+        doc.transact::<_, _, AutomergeError>(|tx| tx.splice_text(&text_id, 5, 0, "!"))
+            .unwrap();
+        assert_eq!(doc.get_cursor_position(&text_id, &end, None).unwrap(), 6);
+    }
+
+    #[test]
+    fn round_trips_presence_messages() {
+        let (doc, target) = document();
+        let cursor = caret_cursor(&doc, &target, 2, &doc.get_heads()).unwrap();
+        let presence = PresenceMessage::Presence {
+            node: "node".to_string(),
+            target: "content".to_string(),
+            cursor,
+        };
+        let leave = PresenceMessage::Leave {
+            node: "node".to_string(),
+        };
+        for message in [presence, leave] {
+            assert_eq!(
+                PresenceMessage::from_bytes(&message.to_bytes()),
+                Some(message)
+            );
+        }
+        assert_eq!(PresenceMessage::from_bytes(b"not json"), None);
+        assert_eq!(
+            PresenceMessage::from_bytes(br#"{"type":"cursor","node":"node"}"#),
+            None
+        );
+    }
+}
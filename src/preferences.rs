@@ -0,0 +1,453 @@
+//! Editor preferences, persisted with GSettings and applied live to every open
+//! editor.
+//!
+//! The schema lives in `schemas/` and is compiled by the build script, so it's
+//! found next to the build when it isn't installed. Without a schema the
+//! editors keep the defaults and the preferences window can't be opened.
+//!
+//! Highlighting follows libadwaita's dark style: the chosen style scheme is
+//! swapped for its dark variant, if it has one, whenever the application is
+//! dark, be it because of the system preference or the `color-scheme` key.
+
+use std::cell::{Cell, OnceCell};
+
+use adw::prelude::*;
+use gtk::{gio, glib::translate::IntoGlib, pango};
+use sourceview5::prelude::*;
+
+const SCHEMA_ID: &str = "xyz.patternist.glyphcaster";
+
+/// Added to editor views, so the font can be styled for all of them at once.
+const EDITOR_CSS_CLASS: &str = "glyphcaster-editor";
+
+const DEFAULT_FONT: &str = "Monospace 11";
+const DEFAULT_STYLE_SCHEME: &str = "solarized-light";
+const FALLBACK_STYLE_SCHEME: &str = "classic";
+
+/// The values of the `wrap-mode` key, with their labels.
+const WRAP_MODES: [(&str, &str, gtk::WrapMode); 4] = [
+    ("none", "None", gtk::WrapMode::None),
+    ("word", "Words", gtk::WrapMode::Word),
+    ("char", "Characters", gtk::WrapMode::Char),
+    (
+        "word-char",
+        "Words, Then Characters",
+        gtk::WrapMode::WordChar,
+    ),
+];
+
+/// The values of the `color-scheme` key, with their labels.
+const COLOR_SCHEMES: [(&str, &str, adw::ColorScheme); 3] = [
+    ("follow-system", "Follow System", adw::ColorScheme::Default),
+    ("light", "Light", adw::ColorScheme::ForceLight),
+    ("dark", "Dark", adw::ColorScheme::ForceDark),
+];
+
+thread_local! {
+    static SETTINGS: OnceCell<Option<gio::Settings>> = const { OnceCell::new() };
+    static FONT_CSS: OnceCell<gtk::CssProvider> = const { OnceCell::new() };
+}
+
+fn lookup_schema() -> Option<gio::SettingsSchema> {
+    if let Some(schema) =
+        gio::SettingsSchemaSource::default().and_then(|source| source.lookup(SCHEMA_ID, true))
+    {
+        return Some(schema);
+    }
+    // Not installed, fall back to the one compiled by the build script
+    let source = gio::SettingsSchemaSource::from_directory(
+        env!("OUT_DIR"),
+        gio::SettingsSchemaSource::default().as_ref(),
+        false,
+    );
+    match source {
+        Ok(source) => source.lookup(SCHEMA_ID, false),
+        Err(e) => {
+            tracing::warn!(%e, "failed to read the compiled settings schema");
+            None
+        }
+    }
+}
+
+/// The editor settings, `None` if the schema couldn't be found.
+pub(crate) fn settings() -> Option<gio::Settings> {
+    SETTINGS.with(|settings| {
+        settings
+            .get_or_init(|| {
+                let Some(schema) = lookup_schema() else {
+                    tracing::warn!("settings schema {SCHEMA_ID} not found, using defaults");
+                    return None;
+                };
+                Some(gio::Settings::new_full(
+                    &schema,
+                    None::<&gio::SettingsBackend>,
+                    None,
+                ))
+            })
+            .clone()
+    })
+}
+
+/// The style scheme to highlight the markdown with, in the variant matching
+/// the application's light or dark style.
+pub(crate) fn style_scheme() -> Option<sourceview5::StyleScheme> {
+    let id = settings()
+        .map(|settings| settings.string("style-scheme"))
+        .unwrap_or_else(|| DEFAULT_STYLE_SCHEME.into());
+    let manager = sourceview5::StyleSchemeManager::default();
+    let scheme = manager
+        .scheme(&id)
+        .or_else(|| manager.scheme(FALLBACK_STYLE_SCHEME))?;
+    let variant = if adw::StyleManager::default().is_dark() {
+        "dark-variant"
+    } else {
+        "light-variant"
+    };
+    Some(
+        scheme
+            .metadata(variant)
+            .and_then(|id| manager.scheme(&id))
+            .unwrap_or(scheme),
+    )
+}
+
+fn update_style_scheme(view: &sourceview5::View) {
+    if let Ok(buffer) = view.buffer().downcast::<sourceview5::Buffer>() {
+        buffer.set_style_scheme(style_scheme().as_ref());
+    }
+}
+
+fn color_scheme(settings: &gio::Settings) -> adw::ColorScheme {
+    let nick = settings.string("color-scheme");
+    COLOR_SCHEMES
+        .iter()
+        .find(|(value, _, _)| *value == nick)
+        .map_or(adw::ColorScheme::Default, |(_, _, scheme)| *scheme)
+}
+
+fn wrap_mode(settings: &gio::Settings) -> gtk::WrapMode {
+    let nick = settings.string("wrap-mode");
+    WRAP_MODES
+        .iter()
+        .find(|(value, _, _)| *value == nick)
+        .map_or(gtk::WrapMode::None, |(_, _, mode)| *mode)
+}
+
+fn background_pattern(settings: &gio::Settings) -> sourceview5::BackgroundPatternType {
+    if settings.boolean("show-grid") {
+        sourceview5::BackgroundPatternType::Grid
+    } else {
+        sourceview5::BackgroundPatternType::None
+    }
+}
+
+/// Turns a font description into CSS, since views can't be given a font directly.
+fn font_css(font: &str) -> String {
+    let font = pango::FontDescription::from_string(font);
+    let mut css = format!(".{EDITOR_CSS_CLASS} {{");
+    if let Some(family) = font.family() {
+        css.push_str(&format!(" font-family: \"{family}\";"));
+    }
+    if font.size() > 0 {
+        let unit = if font.is_size_absolute() { "px" } else { "pt" };
+        css.push_str(&format!(
+            " font-size: {}{unit};",
+            f64::from(font.size()) / f64::from(pango::SCALE)
+        ));
+    }
+    css.push_str(&format!(
+        " font-weight: {};",
+        font.weight().into_glib().clamp(100, 1000)
+    ));
+    let style = match font.style() {
+        pango::Style::Italic => "italic",
+        pango::Style::Oblique => "oblique",
+        _ => "normal",
+    };
+    css.push_str(&format!(" font-style: {style}; }}"));
+    css
+}
+
+/// Styles every editor view with the configured font, following changes.
+fn install_font_css(settings: &gio::Settings) {
+    FONT_CSS.with(|provider| {
+        if provider.get().is_some() {
+            return;
+        }
+        let Some(display) = gtk::gdk::Display::default() else {
+            return;
+        };
+        let css = gtk::CssProvider::new();
+        css.load_from_string(&font_css(&settings.string("font")));
+        gtk::style_context_add_provider_for_display(
+            &display,
+            &css,
+            gtk::STYLE_PROVIDER_PRIORITY_APPLICATION,
+        );
+        settings.connect_changed(Some("font"), {
+            let css = css.clone();
+            move |settings, _| css.load_from_string(&font_css(&settings.string("font")))
+        });
+        let _ = provider.set(css);
+    });
+}
+
+/// Applies the editor settings to `view` and keeps it up to date with them.
+pub(crate) fn apply(view: &sourceview5::View) {
+    view.set_monospace(true);
+    view.add_css_class(EDITOR_CSS_CLASS);
+    follow_dark_style(view);
+    let Some(settings) = settings() else {
+        view.set_background_pattern(sourceview5::BackgroundPatternType::Grid);
+        view.set_show_line_numbers(true);
+        view.set_highlight_current_line(true);
+        view.set_tab_width(4);
+        view.set_insert_spaces_instead_of_tabs(true);
+        return;
+    };
+    install_font_css(&settings);
+
+    for (key, property) in [
+        ("tab-width", "tab-width"),
+        ("insert-spaces", "insert-spaces-instead-of-tabs"),
+        ("show-line-numbers", "show-line-numbers"),
+        ("highlight-current-line", "highlight-current-line"),
+    ] {
+        settings.bind(key, view, property).get().build();
+    }
+
+    // The rest needs converting, so follow changes by hand for as long as the view lives
+    view.set_wrap_mode(wrap_mode(&settings));
+    view.set_background_pattern(background_pattern(&settings));
+    let weak_view = view.downgrade();
+    let handler = settings.connect_changed(None, move |settings, key| {
+        let Some(view) = weak_view.upgrade() else {
+            return;
+        };
+        match key {
+            "wrap-mode" => view.set_wrap_mode(wrap_mode(settings)),
+            "show-grid" => view.set_background_pattern(background_pattern(settings)),
+            "style-scheme" => update_style_scheme(&view),
+            _ => {}
+        }
+    });
+    let handler = Cell::new(Some(handler));
+    view.connect_destroy(move |_| {
+        if let Some(handler) = handler.take() {
+            settings.disconnect(handler);
+        }
+    });
+}
+
+/// Switches the highlighting of `view` between light and dark along with the application.
+fn follow_dark_style(view: &sourceview5::View) {
+    let style_manager = adw::StyleManager::default();
+    let weak_view = view.downgrade();
+    let handler = style_manager.connect_dark_notify(move |_| {
+        if let Some(view) = weak_view.upgrade() {
+            update_style_scheme(&view);
+        }
+    });
+    let handler = Cell::new(Some(handler));
+    view.connect_destroy(move |_| {
+        if let Some(handler) = handler.take() {
+            style_manager.disconnect(handler);
+        }
+    });
+}
+
+/// Applies the configured color scheme, and adds the `app.preferences` action
+/// opening the preferences window.
+pub(crate) fn setup(application: &adw::Application) {
+    if let Some(settings) = settings() {
+        let style_manager = adw::StyleManager::default();
+        style_manager.set_color_scheme(color_scheme(&settings));
+        settings.connect_changed(Some("color-scheme"), move |settings, _| {
+            style_manager.set_color_scheme(color_scheme(settings));
+        });
+    }
+
+    let action = gio::SimpleAction::new("preferences", None);
+    action.set_enabled(settings().is_some());
+    action.connect_activate({
+        let application = application.downgrade();
+        move |_, _| {
+            let Some(application) = application.upgrade() else {
+                return;
+            };
+            if let Some(settings) = settings() {
+                present(&settings, application.active_window().as_ref());
+            }
+        }
+    });
+    application.add_action(&action);
+    application.set_accels_for_action("app.preferences", &["<Control>comma"]);
+}
+
+fn present(settings: &gio::Settings, parent: Option<&gtk::Window>) {
+    let window = adw::PreferencesWindow::new();
+    window.set_title(Some("Preferences"));
+    window.set_modal(true);
+    window.set_transient_for(parent);
+    window.set_search_enabled(false);
+
+    let page = adw::PreferencesPage::new();
+    page.add(&appearance_group(settings));
+    page.add(&editing_group(settings));
+    window.add(&page);
+    window.present();
+}
+
+fn appearance_group(settings: &gio::Settings) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::new();
+    group.set_title("Appearance");
+
+    let color_row = adw::ComboRow::builder()
+        .title("Style")
+        .model(&gtk::StringList::new(
+            &COLOR_SCHEMES.map(|(_, label, _)| label),
+        ))
+        .build();
+    let current_color_scheme = color_scheme(settings);
+    if let Some(index) = COLOR_SCHEMES
+        .iter()
+        .position(|(_, _, scheme)| *scheme == current_color_scheme)
+    {
+        color_row.set_selected(index as u32);
+    }
+    color_row.connect_selected_notify({
+        let settings = settings.clone();
+        move |row| {
+            let Some((nick, _, _)) = COLOR_SCHEMES.get(row.selected() as usize) else {
+                return;
+            };
+            if let Err(e) = settings.set_string("color-scheme", nick) {
+                tracing::warn!(%e, "failed to save color scheme");
+            }
+        }
+    });
+    group.add(&color_row);
+
+    let font_button = gtk::FontDialogButton::new(Some(gtk::FontDialog::new()));
+    font_button.set_valign(gtk::Align::Center);
+    font_button.set_use_font(true);
+    font_button.set_font_desc(&pango::FontDescription::from_string(
+        &settings.string("font"),
+    ));
+    font_button.connect_font_desc_notify({
+        let settings = settings.clone();
+        move |button| {
+            let font = button
+                .font_desc()
+                .map_or_else(|| DEFAULT_FONT.to_string(), |font| font.to_string());
+            if let Err(e) = settings.set_string("font", &font) {
+                tracing::warn!(%e, "failed to save font");
+            }
+        }
+    });
+    let font_row = adw::ActionRow::builder().title("Font").build();
+    font_row.add_suffix(&font_button);
+    font_row.set_activatable_widget(Some(&font_button));
+    group.add(&font_row);
+
+    let manager = sourceview5::StyleSchemeManager::default();
+    // Dark variants are picked automatically, so only offer the schemes they belong to
+    let (scheme_ids, scheme_names): (Vec<String>, Vec<String>) = manager
+        .scheme_ids()
+        .iter()
+        .filter_map(|id| manager.scheme(id))
+        .filter(|scheme| scheme.metadata("light-variant").is_none())
+        .map(|scheme| (scheme.id().to_string(), scheme.name().to_string()))
+        .unzip();
+    let scheme_row = adw::ComboRow::builder()
+        .title("Style Scheme")
+        .subtitle("Its dark variant is used in dark mode")
+        .model(&gtk::StringList::new(
+            &scheme_names.iter().map(String::as_str).collect::<Vec<_>>(),
+        ))
+        .build();
+    let current_scheme = settings.string("style-scheme");
+    if let Some(index) = scheme_ids
+        .iter()
+        .position(|id| *id == current_scheme.as_str())
+    {
+        scheme_row.set_selected(index as u32);
+    }
+    scheme_row.connect_selected_notify({
+        let settings = settings.clone();
+        move |row| {
+            let Some(id) = scheme_ids.get(row.selected() as usize) else {
+                return;
+            };
+            if let Err(e) = settings.set_string("style-scheme", id) {
+                tracing::warn!(%e, "failed to save style scheme");
+            }
+        }
+    });
+    group.add(&scheme_row);
+
+    for (key, title) in [
+        ("show-line-numbers", "Show Line Numbers"),
+        ("highlight-current-line", "Highlight Current Line"),
+        ("show-grid", "Show Grid"),
+    ] {
+        let row = adw::SwitchRow::builder().title(title).build();
+        settings.bind(key, &row, "active").build();
+        group.add(&row);
+    }
+    group
+}
+
+fn editing_group(settings: &gio::Settings) -> adw::PreferencesGroup {
+    let group = adw::PreferencesGroup::new();
+    group.set_title("Editing");
+
+    let wrap_row = adw::ComboRow::builder()
+        .title("Wrap Lines")
+        .model(&gtk::StringList::new(
+            &WRAP_MODES.map(|(_, label, _)| label),
+        ))
+        .build();
+    let current_mode = wrap_mode(settings);
+    if let Some(index) = WRAP_MODES
+        .iter()
+        .position(|(_, _, mode)| *mode == current_mode)
+    {
+        wrap_row.set_selected(index as u32);
+    }
+    wrap_row.connect_selected_notify({
+        let settings = settings.clone();
+        move |row| {
+            let Some((nick, _, _)) = WRAP_MODES.get(row.selected() as usize) else {
+                return;
+            };
+            if let Err(e) = settings.set_string("wrap-mode", nick) {
+                tracing::warn!(%e, "failed to save wrap mode");
+            }
+        }
+    });
+    group.add(&wrap_row);
+
+    // GSettings can't bind the unsigned key to the spin row's double value
+    let tab_row = adw::SpinRow::with_range(1.0, 16.0, 1.0);
+    tab_row.set_title("Tab Width");
+    tab_row.set_value(f64::from(settings.uint("tab-width")));
+    tab_row.connect_value_notify({
+        let settings = settings.clone();
+        move |row| {
+            if let Err(e) = settings.set_uint("tab-width", row.value() as u32) {
+                tracing::warn!(%e, "failed to save tab width");
+            }
+        }
+    });
+    group.add(&tab_row);
+
+    let spaces_row = adw::SwitchRow::builder()
+        .title("Insert Spaces Instead of Tabs")
+        .build();
+    settings
+        .bind("insert-spaces", &spaces_row, "active")
+        .build();
+    group.add(&spaces_row);
+    group
+}
@@ -0,0 +1,598 @@
+//! A rendered preview of the edited markdown, next to the source.
+//!
+//! The preview is a plain text view with tags for the markdown styles. It is
+//! split into the top level blocks of the markdown, and on every edit only the
+//! blocks whose source changed are rendered again, so typing in a long README
+//! neither flickers nor loses the scroll position.
+
+use std::{
+    cell::{Cell, RefCell},
+    ops::Range,
+    rc::Rc,
+    time::Duration,
+};
+
+use gtk::{gdk, glib, prelude::*};
+use pulldown_cmark::{Event, Parser, Tag, TagEnd};
+
+use crate::export::markdown_options;
+
+/// How long to wait for more edits before rendering, so bursts of remote
+/// changes are rendered once.
+const RENDER_DELAY: Duration = Duration::from_millis(150);
+
+/// Pixels of indentation per level of lists and block quotes.
+const INDENT: i32 = 20;
+
+/// A top level block of the markdown and where its rendering starts.
+struct RenderedBlock {
+    source: String,
+    source_line: i32,
+    /// Has right gravity, so text inserted for earlier blocks moves it along.
+    start: gtk::TextMark,
+}
+
+#[derive(Clone)]
+pub(crate) struct MarkdownPreview {
+    pub(crate) container: gtk::ScrolledWindow,
+    view: gtk::TextView,
+    source: sourceview5::View,
+    blocks: Rc<RefCell<Vec<RenderedBlock>>>,
+    render_pending: Rc<Cell<bool>>,
+    /// Set while one side is scrolled to follow the other, to not loop.
+    syncing_scroll: Rc<Cell<bool>>,
+    /// Whether the pointer is over the preview, which then leads scrolling.
+    /// Otherwise layout changes after rendering would scroll the source.
+    preview_hovered: Rc<Cell<bool>>,
+}
+
+impl MarkdownPreview {
+    pub(crate) fn new(source: &sourceview5::View) -> Self {
+        let view = gtk::TextView::new();
+        view.set_editable(false);
+        view.set_cursor_visible(false);
+        view.set_wrap_mode(gtk::WrapMode::WordChar);
+        view.set_left_margin(16);
+        view.set_right_margin(16);
+        view.set_top_margin(12);
+        view.set_bottom_margin(12);
+        create_tags(&view.buffer());
+
+        let container = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .hexpand(true)
+            .vexpand(true)
+            .child(&view)
+            .build();
+
+        let preview = Self {
+            container,
+            view,
+            source: source.clone(),
+            blocks: Rc::new(RefCell::new(Vec::new())),
+            render_pending: Rc::new(Cell::new(false)),
+            syncing_scroll: Rc::new(Cell::new(false)),
+            preview_hovered: Rc::new(Cell::new(false)),
+        };
+
+        source.buffer().connect_changed({
+            let preview = preview.clone();
+            move |_| preview.schedule_render()
+        });
+        // Catch up on edits made while hidden
+        preview.container.connect_map({
+            let preview = preview.clone();
+            move |_| preview.render()
+        });
+
+        if let Some(adjustment) = source.vadjustment() {
+            adjustment.connect_value_changed({
+                let preview = preview.clone();
+                move |_| preview.follow_source()
+            });
+        }
+        let motion = gtk::EventControllerMotion::new();
+        motion.connect_enter({
+            let preview_hovered = preview.preview_hovered.clone();
+            move |_, _, _| preview_hovered.set(true)
+        });
+        motion.connect_leave({
+            let preview_hovered = preview.preview_hovered.clone();
+            move |_| preview_hovered.set(false)
+        });
+        preview.container.add_controller(motion);
+        preview.container.vadjustment().connect_value_changed({
+            let preview = preview.clone();
+            move |_| preview.follow_preview()
+        });
+
+        preview
+    }
+
+    fn schedule_render(&self) {
+        if self.render_pending.replace(true) {
+            return;
+        }
+        let preview = self.clone();
+        glib::timeout_add_local_once(RENDER_DELAY, move || {
+            preview.render_pending.set(false);
+            if preview.container.is_mapped() {
+                preview.render();
+            }
+        });
+    }
+
+    /// Renders the blocks that changed since the last render.
+    fn render(&self) {
+        let source_buffer = self.source.buffer();
+        let (start, end) = source_buffer.bounds();
+        let markdown = source_buffer.text(&start, &end, false);
+        let markdown = markdown.as_str();
+        let parsed = split_blocks(markdown);
+
+        let buffer = self.view.buffer();
+        let mut blocks = self.blocks.borrow_mut();
+        let unchanged = |old: &RenderedBlock, (range, _): &(Range<usize>, Vec<Event>)| {
+            old.source == markdown[range.clone()]
+        };
+        let prefix = blocks
+            .iter()
+            .zip(&parsed)
+            .take_while(|(old, new)| unchanged(old, new))
+            .count();
+        let suffix = blocks[prefix..]
+            .iter()
+            .rev()
+            .zip(parsed[prefix..].iter().rev())
+            .take_while(|(old, new)| unchanged(old, new))
+            .count();
+        let changed_end = blocks.len() - suffix;
+
+        // Drop the rendering of changed blocks
+        let mut delete_start = match blocks.get(prefix) {
+            Some(block) => buffer.iter_at_mark(&block.start),
+            None => buffer.end_iter(),
+        };
+        let mut delete_end = match blocks.get(changed_end) {
+            Some(block) => buffer.iter_at_mark(&block.start),
+            None => buffer.end_iter(),
+        };
+        buffer.delete(&mut delete_start, &mut delete_end);
+        for block in blocks.drain(prefix..changed_end) {
+            buffer.delete_mark(&block.start);
+        }
+
+        // and render their replacements in the same place
+        let mut iter = match blocks.get(prefix) {
+            Some(block) => buffer.iter_at_mark(&block.start),
+            None => buffer.end_iter(),
+        };
+        let mut rendered = Vec::new();
+        for (range, events) in &parsed[prefix..parsed.len() - suffix] {
+            let offset = iter.offset();
+            Renderer::new(&buffer, &mut iter).render(events);
+            rendered.push(RenderedBlock {
+                source: markdown[range.clone()].to_string(),
+                source_line: 0,
+                start: buffer.create_mark(None, &buffer.iter_at_offset(offset), false),
+            });
+        }
+        blocks.splice(prefix..prefix, rendered);
+
+        // Blocks after an edit may have moved to other lines
+        let mut line = 0;
+        let mut counted = 0;
+        for (block, (range, _)) in blocks.iter_mut().zip(&parsed) {
+            line += markdown[counted..range.start].matches('\n').count() as i32;
+            counted = range.start;
+            block.source_line = line;
+        }
+        drop(blocks);
+
+        self.follow_source();
+    }
+
+    /// Pairs of vertical positions of the same block in the source and preview.
+    fn anchors(&self) -> Vec<(f64, f64)> {
+        let source_buffer = self.source.buffer();
+        let buffer = self.view.buffer();
+        let mut anchors = vec![(0.0, 0.0)];
+        for block in self.blocks.borrow().iter() {
+            let Some(source_iter) = source_buffer.iter_at_line(block.source_line) else {
+                continue;
+            };
+            let (source_y, _) = self.source.line_yrange(&source_iter);
+            let (preview_y, _) = self.view.line_yrange(&buffer.iter_at_mark(&block.start));
+            anchors.push((f64::from(source_y), f64::from(preview_y)));
+        }
+        anchors
+    }
+
+    fn follow_source(&self) {
+        if self.preview_hovered.get() {
+            return;
+        }
+        let Some(source) = self.source.vadjustment() else {
+            return;
+        };
+        self.sync_scroll(&source, &self.container.vadjustment(), false);
+    }
+
+    fn follow_preview(&self) {
+        if !self.preview_hovered.get() {
+            return;
+        }
+        let Some(source) = self.source.vadjustment() else {
+            return;
+        };
+        self.sync_scroll(&self.container.vadjustment(), &source, true);
+    }
+
+    /// Scrolls `to` so it shows the same block as `from`, interpolating within
+    /// blocks.
+    fn sync_scroll(&self, from: &gtk::Adjustment, to: &gtk::Adjustment, from_preview: bool) {
+        if self.syncing_scroll.get() || !self.container.is_mapped() {
+            return;
+        }
+        let max = |adjustment: &gtk::Adjustment| {
+            (adjustment.upper() - adjustment.page_size()).max(adjustment.lower())
+        };
+        let value = from.value();
+        let target = if value > from.lower() && value >= max(from) {
+            // Keep the ends of the documents aligned, even if the last block
+            // is much longer on one side
+            max(to)
+        } else {
+            let anchors: Vec<(f64, f64)> = self
+                .anchors()
+                .into_iter()
+                .map(|(source, preview)| {
+                    if from_preview {
+                        (preview, source)
+                    } else {
+                        (source, preview)
+                    }
+                })
+                .collect();
+            let next = anchors.partition_point(|&(from_y, _)| from_y <= value);
+            let (from_start, to_start) = anchors[next.saturating_sub(1)];
+            match anchors.get(next) {
+                Some(&(from_end, to_end)) if from_end > from_start => {
+                    to_start + (value - from_start) / (from_end - from_start) * (to_end - to_start)
+                }
+                _ => to_start + (value - from_start),
+            }
+        };
+        self.syncing_scroll.set(true);
+        to.set_value(target.clamp(to.lower(), max(to)));
+        self.syncing_scroll.set(false);
+    }
+}
+
+/// Renders all of the markdown into an empty `buffer`, e.g. for printing.
+pub(crate) fn render_into(buffer: &gtk::TextBuffer, markdown: &str) {
+    create_tags(buffer);
+    let mut iter = buffer.end_iter();
+    for (_, events) in split_blocks(markdown) {
+        Renderer::new(buffer, &mut iter).render(&events);
+    }
+}
+
+/// Splits the markdown into its top level blocks, with their source ranges.
+fn split_blocks(markdown: &str) -> Vec<(Range<usize>, Vec<Event<'_>>)> {
+    let mut blocks = Vec::new();
+    let mut events = Vec::new();
+    let mut depth = 0;
+    for (event, range) in Parser::new_ext(markdown, markdown_options()).into_offset_iter() {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        events.push(event);
+        if depth == 0 {
+            blocks.push((range, std::mem::take(&mut events)));
+        }
+    }
+    blocks
+}
+
+fn create_tags(buffer: &gtk::TextBuffer) {
+    let dim = gdk::RGBA::new(0.5, 0.5, 0.5, 1.0);
+    let shade = gdk::RGBA::new(0.5, 0.5, 0.5, 0.15);
+    let table = buffer.tag_table();
+    for (level, scale) in [(1, 2.0), (2, 1.6), (3, 1.3), (4, 1.15), (5, 1.0), (6, 0.9)] {
+        table.add(
+            &gtk::TextTag::builder()
+                .name(format!("h{level}"))
+                .weight(700)
+                .scale(scale)
+                .pixels_above_lines(8)
+                .build(),
+        );
+    }
+    for tag in [
+        gtk::TextTag::builder().name("strong").weight(700).build(),
+        gtk::TextTag::builder()
+            .name("emphasis")
+            .style(gtk::pango::Style::Italic)
+            .build(),
+        gtk::TextTag::builder()
+            .name("strikethrough")
+            .strikethrough(true)
+            .build(),
+        gtk::TextTag::builder()
+            .name("code")
+            .family("monospace")
+            .background_rgba(&shade)
+            .build(),
+        gtk::TextTag::builder()
+            .name("code-block")
+            .family("monospace")
+            .paragraph_background_rgba(&shade)
+            .wrap_mode(gtk::WrapMode::None)
+            .build(),
+        gtk::TextTag::builder()
+            .name("quote")
+            .style(gtk::pango::Style::Italic)
+            .foreground_rgba(&dim)
+            .build(),
+        gtk::TextTag::builder()
+            .name("link")
+            .foreground_rgba(&gdk::RGBA::new(0.21, 0.52, 0.89, 1.0))
+            .underline(gtk::pango::Underline::Single)
+            .build(),
+        gtk::TextTag::builder()
+            .name("dim")
+            .foreground_rgba(&dim)
+            .build(),
+        gtk::TextTag::builder()
+            .name("rule")
+            .foreground_rgba(&dim)
+            .justification(gtk::Justification::Center)
+            .build(),
+        gtk::TextTag::builder()
+            .name("footnote")
+            .scale(0.8)
+            .rise(4 * gtk::pango::SCALE)
+            .build(),
+    ] {
+        table.add(&tag);
+    }
+}
+
+/// Inserts the rendering of one top level block at an iterator.
+struct Renderer<'a> {
+    buffer: &'a gtk::TextBuffer,
+    iter: &'a mut gtk::TextIter,
+    /// The tag of every open markdown element, if it has one.
+    tags: Vec<Option<String>>,
+    /// The next number of every open list, `None` for bullet lists.
+    lists: Vec<Option<u64>>,
+    /// Block quotes and list items open, for indentation.
+    indent: i32,
+    at_line_start: bool,
+    in_table_cell: bool,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(buffer: &'a gtk::TextBuffer, iter: &'a mut gtk::TextIter) -> Self {
+        Self {
+            buffer,
+            iter,
+            tags: Vec::new(),
+            lists: Vec::new(),
+            indent: 0,
+            at_line_start: true,
+            in_table_cell: false,
+        }
+    }
+
+    fn indent_tag(&self) -> Option<String> {
+        if self.indent == 0 {
+            return None;
+        }
+        let name = format!("indent-{}", self.indent);
+        let table = self.buffer.tag_table();
+        if table.lookup(&name).is_none() {
+            table.add(
+                &gtk::TextTag::builder()
+                    .name(name.as_str())
+                    .left_margin(INDENT * self.indent)
+                    .build(),
+            );
+        }
+        Some(name)
+    }
+
+    fn insert(&mut self, text: &str, extra_tag: Option<&str>) {
+        if text.is_empty() {
+            return;
+        }
+        let indent_tag = self.indent_tag();
+        let tags: Vec<&str> = self
+            .tags
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .chain(indent_tag.as_deref())
+            .chain(extra_tag)
+            .collect();
+        self.buffer.insert_with_tags_by_name(self.iter, text, &tags);
+        self.at_line_start = text.ends_with('\n');
+    }
+
+    fn ensure_line_start(&mut self) {
+        if !self.at_line_start {
+            self.insert("\n", None);
+        }
+    }
+
+    fn render(mut self, events: &[Event]) {
+        for event in events {
+            match event {
+                Event::Start(tag) => self.start(tag),
+                Event::End(tag) => self.end(*tag),
+                Event::Text(text) => self.insert(text, None),
+                Event::Code(code) => self.insert(code, Some("code")),
+                Event::Html(html) | Event::InlineHtml(html) => self.insert(html, Some("dim")),
+                Event::SoftBreak => self.insert(" ", None),
+                Event::HardBreak => self.insert("\n", None),
+                Event::Rule => {
+                    self.ensure_line_start();
+                    self.insert("⸻\n", Some("rule"));
+                }
+                Event::TaskListMarker(checked) => {
+                    self.insert(if *checked { "☑ " } else { "☐ " }, None);
+                }
+                Event::FootnoteReference(name) => {
+                    self.insert(&format!("[{name}]"), Some("footnote"));
+                }
+                _ => {}
+            }
+        }
+        // Separate this block from the next
+        self.ensure_line_start();
+        self.insert("\n", None);
+    }
+
+    fn start(&mut self, tag: &Tag) {
+        let name = match tag {
+            Tag::Heading { level, .. } => {
+                self.ensure_line_start();
+                Some(format!("h{}", *level as u8))
+            }
+            Tag::Paragraph => {
+                self.ensure_line_start();
+                None
+            }
+            Tag::BlockQuote(_) => {
+                self.ensure_line_start();
+                self.indent += 1;
+                Some("quote".to_string())
+            }
+            Tag::CodeBlock(_) => {
+                self.ensure_line_start();
+                Some("code-block".to_string())
+            }
+            Tag::List(start) => {
+                self.ensure_line_start();
+                self.lists.push(*start);
+                None
+            }
+            Tag::Item => {
+                self.ensure_line_start();
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                self.indent += 1;
+                self.insert(&marker, None);
+                // Loose list items wrap their text in paragraphs, which belong
+                // on the marker's line
+                self.at_line_start = true;
+                None
+            }
+            Tag::FootnoteDefinition(name) => {
+                self.ensure_line_start();
+                self.insert(&format!("[{name}]: "), Some("footnote"));
+                None
+            }
+            Tag::TableHead => {
+                self.ensure_line_start();
+                Some("strong".to_string())
+            }
+            Tag::TableRow => {
+                self.ensure_line_start();
+                None
+            }
+            Tag::TableCell => {
+                if self.in_table_cell {
+                    self.insert(" │ ", Some("dim"));
+                }
+                self.in_table_cell = true;
+                None
+            }
+            Tag::Emphasis => Some("emphasis".to_string()),
+            Tag::Strong => Some("strong".to_string()),
+            Tag::Strikethrough => Some("strikethrough".to_string()),
+            Tag::Link { .. } => Some("link".to_string()),
+            Tag::Image { .. } => {
+                self.insert("🖼 ", None);
+                Some("dim".to_string())
+            }
+            _ => None,
+        };
+        self.tags.push(name);
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        self.tags.pop();
+        match tag {
+            TagEnd::Heading(_) | TagEnd::Paragraph | TagEnd::CodeBlock => self.ensure_line_start(),
+            TagEnd::BlockQuote(_) | TagEnd::Item => {
+                self.ensure_line_start();
+                self.indent -= 1;
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+            }
+            TagEnd::TableRow | TagEnd::TableHead => {
+                self.ensure_line_start();
+                self.in_table_cell = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The source of each top level block, without trailing newlines.
+    fn block_sources(markdown: &str) -> Vec<&str> {
+        split_blocks(markdown)
+            .into_iter()
+            .map(|(range, _)| markdown[range].trim_end())
+            .collect()
+    }
+
+    #[test]
+    fn splits_top_level_blocks() {
+        let markdown = "# Title\n\nSome *text*\nacross lines\n\n---\n\n> Quote\n";
+        assert_eq!(
+            block_sources(markdown),
+            ["# Title", "Some *text*\nacross lines", "---", "> Quote"]
+        );
+    }
+
+    #[test]
+    fn keeps_nested_blocks_together() {
+        let markdown = "- One\n  - Nested\n\n    More\n- Two\n\n```\ncode\n```\n";
+        let blocks = split_blocks(markdown);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            markdown[blocks[0].0.clone()].trim_end(),
+            "- One\n  - Nested\n\n    More\n- Two"
+        );
+        assert!(matches!(
+            blocks[0].1.first(),
+            Some(Event::Start(Tag::List(None)))
+        ));
+        assert!(matches!(blocks[0].1.last(), Some(Event::End(_))));
+        assert!(matches!(
+            blocks[1].1.first(),
+            Some(Event::Start(Tag::CodeBlock(_)))
+        ));
+    }
+
+    #[test]
+    fn splits_empty_markdown_into_nothing() {
+        assert!(split_blocks("").is_empty());
+        assert!(split_blocks("\n\n").is_empty());
+    }
+}
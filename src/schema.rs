@@ -0,0 +1,319 @@
+//! The document schema shared with other automerge-repo tooling.
+//!
+//! See `docs/document-schema.md` for the TypeScript description of the schema.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use automerge::{
+    Automerge, AutomergeError, ObjId, ObjType, Prop, ROOT, ReadDoc, ScalarValue, Value,
+    transaction::{CommitOptions, Transactable},
+};
+
+use crate::authors::AUTHORS_KEY;
+use crate::comments::COMMENTS_KEY;
+use crate::snapshots::SNAPSHOTS_KEY;
+use crate::text_path::CONTENT_KEY;
+
+/// The schema version written by this version of glyphcaster.
+pub(crate) const SCHEMA_VERSION: u64 = 2;
+
+/// Maps that peers add entries to. They're created with the document, since
+/// peers creating them independently would end up with conflicting maps, of
+/// which automerge only keeps one.
+const SHARED_MAP_KEYS: [&str; 3] = [AUTHORS_KEY, COMMENTS_KEY, SNAPSHOTS_KEY];
+
+pub(crate) const SCHEMA_VERSION_KEY: &str = "schemaVersion";
+pub(crate) const TITLE_KEY: &str = "title";
+pub(crate) const CREATED_AT_KEY: &str = "createdAt";
+pub(crate) const CREATED_BY_KEY: &str = "createdBy";
+pub(crate) const TAGS_KEY: &str = "tags";
+
+/// The optional metadata stored next to a document's content.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Metadata {
+    /// Documents written before versioning was introduced count as version 0.
+    pub(crate) schema_version: u64,
+    pub(crate) title: Option<String>,
+    /// Milliseconds since the unix epoch, like a JavaScript `Date`.
+    pub(crate) created_at: Option<i64>,
+    /// The iroh node ID of the peer that created the document.
+    pub(crate) created_by: Option<String>,
+    pub(crate) tags: Vec<String>,
+}
+
+impl Metadata {
+    pub(crate) fn read<R: ReadDoc>(doc: &R) -> Self {
+        let schema_version = match doc.get(ROOT, SCHEMA_VERSION_KEY) {
+            Ok(Some((value, _))) => value.to_u64().unwrap_or_default(),
+            _ => 0,
+        };
+        let created_at = match doc.get(ROOT, CREATED_AT_KEY) {
+            Ok(Some((value, _))) => value.to_i64(),
+            _ => None,
+        };
+        let tags = match doc.get(ROOT, TAGS_KEY) {
+            Ok(Some((Value::Object(ObjType::List), tags_id))) => (0..doc.length(&tags_id))
+                .filter_map(|i| read_string(doc, &tags_id, i))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            schema_version,
+            title: read_string(doc, &ROOT, TITLE_KEY).filter(|title| !title.trim().is_empty()),
+            created_at,
+            created_by: read_string(doc, &ROOT, CREATED_BY_KEY),
+            tags,
+        }
+    }
+
+    /// A one-line description of the remaining metadata, e.g. for tooltips.
+    pub(crate) fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(created_at) = self.created_at {
+            let created_at = glib::DateTime::from_unix_local(created_at / 1000)
+                .and_then(|date| date.format("%x %X"))
+                .map(|date| date.to_string())
+                .unwrap_or_else(|_| created_at.to_string());
+            parts.push(format!("Created {created_at}"));
+        }
+        if let Some(ref created_by) = self.created_by {
+            parts.push(format!(
+                "by {}...",
+                created_by.chars().take(12).collect::<String>()
+            ));
+        }
+        if !self.tags.is_empty() {
+            parts.push(format!("Tags: {}", self.tags.join(", ")));
+        }
+        parts.push(format!("Schema version {}", self.schema_version));
+        parts.join(" · ")
+    }
+
+    /// The title to show for the document, falling back to its first line.
+    pub(crate) fn display_title(&self, content: &str) -> String {
+        if let Some(ref title) = self.title {
+            return title.clone();
+        }
+        content
+            .lines()
+            .map(|line| line.trim_start_matches('#').trim())
+            .find(|line| !line.is_empty())
+            .unwrap_or("Untitled")
+            .to_string()
+    }
+}
+
+/// Reads a string that's either stored as a text object or as a scalar string,
+/// since JavaScript clients may write either.
+pub(crate) fn read_string<R: ReadDoc, P: Into<Prop>>(
+    doc: &R,
+    obj: &ObjId,
+    prop: P,
+) -> Option<String> {
+    match doc.get(obj, prop).ok()?? {
+        (Value::Object(ObjType::Text), id) => doc.text(id).ok(),
+        (Value::Scalar(scalar), _) => scalar.to_str().map(String::from),
+        _ => None,
+    }
+}
+
+/// The current time in milliseconds since the unix epoch, which is what all
+/// timestamps in the document use.
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Options for committing a local change, which the history shows. Automerge
+/// stores change times in seconds, the only times not in milliseconds.
+pub(crate) fn commit_options(message: impl Into<String>) -> CommitOptions {
+    CommitOptions::default()
+        .with_message(message)
+        .with_time(now_millis() / 1000)
+}
+
+/// Creates the content and metadata of a new document.
+pub(crate) fn initialize<T: Transactable>(
+    tx: &mut T,
+    title: &str,
+    content: &str,
+    created_by: iroh::NodeId,
+) -> Result<(), AutomergeError> {
+    tx.put(ROOT, SCHEMA_VERSION_KEY, ScalarValue::Uint(SCHEMA_VERSION))?;
+
+    let title_id = tx.put_object(ROOT, TITLE_KEY, ObjType::Text)?;
+    tx.splice_text(&title_id, 0, 0, title)?;
+
+    tx.put(ROOT, CREATED_AT_KEY, ScalarValue::Timestamp(now_millis()))?;
+    tx.put(ROOT, CREATED_BY_KEY, created_by.to_string())?;
+    tx.put_object(ROOT, TAGS_KEY, ObjType::List)?;
+    for key in SHARED_MAP_KEYS {
+        tx.put_object(ROOT, key, ObjType::Map)?;
+    }
+
+    let text_id = tx.put_object(ROOT, CONTENT_KEY, ObjType::Text)?;
+    tx.splice_text(&text_id, 0, 0, content)?;
+    Ok(())
+}
+
+/// Creates a new document with the given title and content.
+pub(crate) fn new_document(
+    title: &str,
+    content: &str,
+    created_by: iroh::NodeId,
+) -> Result<Automerge, AutomergeError> {
+    let mut doc = Automerge::new();
+    doc.transact::<_, _, AutomergeError>(|tx| initialize(tx, title, content, created_by))
+        .map_err(|failure| failure.error)?;
+    Ok(doc)
+}
+
+/// Brings documents written with an older schema up to [`SCHEMA_VERSION`].
+///
+/// Returns whether the document was changed. Documents from newer versions of
+/// glyphcaster are left untouched.
+pub(crate) fn migrate(doc: &mut Automerge) -> Result<bool, AutomergeError> {
+    let version = Metadata::read(doc).schema_version;
+    if version >= SCHEMA_VERSION {
+        if version > SCHEMA_VERSION {
+            tracing::warn!(
+                version,
+                supported = SCHEMA_VERSION,
+                "document uses a newer schema version"
+            );
+        }
+        return Ok(false);
+    }
+
+    // Don't touch documents that aren't glyphcaster documents in the first place
+    if !matches!(
+        doc.get(ROOT, CONTENT_KEY)?,
+        Some((Value::Object(ObjType::Text), _))
+    ) {
+        return Ok(false);
+    }
+
+    tracing::info!(from = version, to = SCHEMA_VERSION, "migrating document");
+    doc.transact_with::<_, _, AutomergeError, _>(
+        |_| commit_options(format!("Migrate schema to version {SCHEMA_VERSION}")),
+        |tx| {
+            // Version 0 -> 1: metadata fields are optional, so only the version is recorded
+            // Version 1 -> 2: adds the shared maps that aren't there yet, since earlier
+            // versions only created them once they were needed
+            for key in SHARED_MAP_KEYS {
+                if !matches!(tx.get(ROOT, key)?, Some((Value::Object(ObjType::Map), _))) {
+                    tx.put_object(ROOT, key, ObjType::Map)?;
+                }
+            }
+            tx.put(ROOT, SCHEMA_VERSION_KEY, ScalarValue::Uint(SCHEMA_VERSION))?;
+            Ok(())
+        },
+    )
+    .map_err(|failure| failure.error)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A document as written before versioning, with nothing but the text.
+    fn v0_document() -> Automerge {
+        let mut doc = Automerge::new();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let text_id = tx.put_object(ROOT, CONTENT_KEY, ObjType::Text)?;
+            tx.splice_text(&text_id, 0, 0, "# Notes")?;
+            Ok(())
+        })
+        .unwrap();
+        doc
+    }
+
+    fn has_shared_maps(doc: &Automerge) -> bool {
+        SHARED_MAP_KEYS.iter().all(|key| {
+            matches!(
+                doc.get(ROOT, *key),
+                Ok(Some((Value::Object(ObjType::Map), _)))
+            )
+        })
+    }
+
+    #[test]
+    fn migrates_v0_documents_once() {
+        let mut doc = v0_document();
+        assert!(migrate(&mut doc).unwrap());
+        assert_eq!(Metadata::read(&doc).schema_version, SCHEMA_VERSION);
+        assert!(has_shared_maps(&doc));
+
+        // Reopening the migrated document doesn't add another change
+        let mut reopened = Automerge::load(&doc.save()).unwrap();
+        let heads = reopened.get_heads();
+        assert!(!migrate(&mut reopened).unwrap());
+        assert_eq!(reopened.get_heads(), heads);
+    }
+
+    #[test]
+    fn keeps_existing_shared_maps() {
+        let mut doc = v0_document();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            tx.put(ROOT, SCHEMA_VERSION_KEY, ScalarValue::Uint(1))?;
+            let comments_id = tx.put_object(ROOT, COMMENTS_KEY, ObjType::Map)?;
+            tx.put_object(&comments_id, "thread", ObjType::Map)?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(migrate(&mut doc).unwrap());
+        assert!(has_shared_maps(&doc));
+        let Some((_, comments_id)) = doc.get(ROOT, COMMENTS_KEY).unwrap() else {
+            panic!("comments are missing");
+        };
+        assert_eq!(doc.keys(&comments_id).collect::<Vec<_>>(), ["thread"]);
+    }
+
+    #[test]
+    fn leaves_current_documents_alone() {
+        let node_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        let mut doc = Automerge::new();
+        doc.transact(|tx| initialize(tx, "Notes", "# Notes", node_id))
+            .unwrap();
+        assert!(has_shared_maps(&doc));
+        let heads = doc.get_heads();
+        assert!(!migrate(&mut doc).unwrap());
+        assert_eq!(doc.get_heads(), heads);
+    }
+
+    #[test]
+    fn leaves_newer_and_foreign_documents_alone() {
+        let mut newer = v0_document();
+        newer
+            .transact::<_, _, AutomergeError>(|tx| {
+                tx.put(
+                    ROOT,
+                    SCHEMA_VERSION_KEY,
+                    ScalarValue::Uint(SCHEMA_VERSION + 1),
+                )
+            })
+            .unwrap();
+        let heads = newer.get_heads();
+        assert!(!migrate(&mut newer).unwrap());
+        assert_eq!(newer.get_heads(), heads);
+
+        let mut foreign = Automerge::new();
+        assert!(!migrate(&mut foreign).unwrap());
+        assert!(foreign.get_heads().is_empty());
+    }
+
+    #[test]
+    fn reads_schema_versions_written_by_javascript() {
+        // JavaScript numbers without a fraction are stored as signed integers
+        let mut doc = v0_document();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            tx.put(ROOT, SCHEMA_VERSION_KEY, ScalarValue::Int(1))
+        })
+        .unwrap();
+        assert!(!migrate(&mut doc).unwrap());
+    }
+}
@@ -0,0 +1,406 @@
+//! Find and replace in the edited text.
+//!
+//! Searching and replacing single matches goes through the buffer like any
+//! other edit. Replacing all matches instead computes the new text up front
+//! and applies it to the document as a single change, so peers receive one
+//! small diff rather than a burst of splices interleaving with their edits.
+
+use automerge::transaction::Transactable;
+use gtk::{gio, glib, prelude::*};
+use sourceview5::prelude::*;
+
+use crate::sync::TextSynchronizer;
+
+#[derive(Clone)]
+pub(crate) struct FindBar {
+    pub(crate) bar: gtk::SearchBar,
+    search_entry: gtk::SearchEntry,
+    replace_entry: gtk::Entry,
+    matches_label: gtk::Label,
+    settings: sourceview5::SearchSettings,
+    context: sourceview5::SearchContext,
+    sync: TextSynchronizer,
+    view: sourceview5::View,
+}
+
+impl FindBar {
+    pub(crate) fn new(sync: &TextSynchronizer, view: &sourceview5::View) -> Self {
+        let settings = sourceview5::SearchSettings::new();
+        settings.set_wrap_around(true);
+        let context = sourceview5::SearchContext::new(sync.buffer(), Some(&settings));
+
+        let search_entry = gtk::SearchEntry::new();
+        search_entry.set_placeholder_text(Some("Find"));
+        search_entry.set_hexpand(true);
+        let previous_button = gtk::Button::from_icon_name("go-up-symbolic");
+        previous_button.set_tooltip_text(Some("Previous Match"));
+        let next_button = gtk::Button::from_icon_name("go-down-symbolic");
+        next_button.set_tooltip_text(Some("Next Match"));
+        let case_button = gtk::ToggleButton::with_label("Aa");
+        case_button.set_tooltip_text(Some("Match Case"));
+        case_button
+            .bind_property("active", &settings, "case-sensitive")
+            .bidirectional()
+            .build();
+        let regex_button = gtk::ToggleButton::with_label(".*");
+        regex_button.set_tooltip_text(Some("Regular Expression"));
+        regex_button
+            .bind_property("active", &settings, "regex-enabled")
+            .bidirectional()
+            .build();
+        let replace_toggle = gtk::ToggleButton::new();
+        replace_toggle.set_icon_name("edit-find-replace-symbolic");
+        replace_toggle.set_tooltip_text(Some("Replace"));
+        let matches_label = gtk::Label::new(None);
+        matches_label.add_css_class("caption");
+        matches_label.add_css_class("dim-label");
+        matches_label.set_width_chars(12);
+
+        let search_row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        search_row.append(&search_entry);
+        search_row.append(&matches_label);
+        search_row.append(&previous_button);
+        search_row.append(&next_button);
+        search_row.append(&case_button);
+        search_row.append(&regex_button);
+        search_row.append(&replace_toggle);
+
+        let replace_entry = gtk::Entry::new();
+        replace_entry.set_placeholder_text(Some("Replace"));
+        replace_entry.set_hexpand(true);
+        let replace_button = gtk::Button::with_label("Replace");
+        let replace_all_button = gtk::Button::with_label("Replace All");
+        let replace_row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        replace_row.append(&replace_entry);
+        replace_row.append(&replace_button);
+        replace_row.append(&replace_all_button);
+        replace_toggle
+            .bind_property("active", &replace_row, "visible")
+            .sync_create()
+            .build();
+
+        let rows = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        rows.set_width_request(500);
+        rows.append(&search_row);
+        rows.append(&replace_row);
+
+        let bar = gtk::SearchBar::new();
+        bar.set_child(Some(&rows));
+        bar.connect_entry(&search_entry);
+        bar.set_show_close_button(true);
+
+        let find = Self {
+            bar,
+            search_entry,
+            replace_entry,
+            matches_label,
+            settings,
+            context,
+            sync: sync.clone(),
+            view: view.clone(),
+        };
+
+        find.search_entry.connect_search_changed({
+            let find = find.clone();
+            move |entry| {
+                let text = entry.text();
+                find.settings
+                    .set_search_text((!text.is_empty()).then_some(text.as_str()));
+            }
+        });
+        find.search_entry.connect_activate({
+            let find = find.clone();
+            move |_| find.next()
+        });
+        find.search_entry.connect_next_match({
+            let find = find.clone();
+            move |_| find.next()
+        });
+        find.search_entry.connect_previous_match({
+            let find = find.clone();
+            move |_| find.previous()
+        });
+        find.search_entry.connect_stop_search({
+            let find = find.clone();
+            move |_| find.close()
+        });
+        next_button.connect_clicked({
+            let find = find.clone();
+            move |_| find.next()
+        });
+        previous_button.connect_clicked({
+            let find = find.clone();
+            move |_| find.previous()
+        });
+        find.replace_entry.connect_activate({
+            let find = find.clone();
+            move |_| find.replace()
+        });
+        replace_button.connect_clicked({
+            let find = find.clone();
+            move |_| find.replace()
+        });
+        replace_all_button.connect_clicked({
+            let find = find.clone();
+            move |_| find.replace_all()
+        });
+
+        // Only search while the bar is open, highlighting is costly on long texts
+        find.bar.connect_search_mode_enabled_notify({
+            let find = find.clone();
+            move |bar| {
+                find.context.set_highlight(bar.is_search_mode());
+                if !bar.is_search_mode() {
+                    find.view.grab_focus();
+                }
+            }
+        });
+        find.context.set_highlight(false);
+
+        for property in ["occurrences-count", "regex-error"] {
+            find.context.connect_notify_local(Some(property), {
+                let find = find.clone();
+                move |_, _| find.update_matches_label()
+            });
+        }
+        find.sync.buffer().connect_mark_set({
+            let find = find.clone();
+            move |_, _, mark| {
+                if mark.name().as_deref() == Some("insert") {
+                    find.update_matches_label();
+                }
+            }
+        });
+
+        find.install_actions(&replace_toggle);
+        find
+    }
+
+    fn install_actions(&self, replace_toggle: &gtk::ToggleButton) {
+        let actions = gio::SimpleActionGroup::new();
+        for (name, replace) in [("show", false), ("replace", true)] {
+            let action = gio::SimpleAction::new(name, None);
+            let find = self.clone();
+            let replace_toggle = replace_toggle.clone();
+            action.connect_activate(move |_, _| {
+                // Start out searching for the selected text
+                let buffer = find.sync.buffer();
+                let selection = buffer
+                    .selection_bounds()
+                    .filter(|(start, end)| start.line() == end.line());
+                if let Some((start, end)) = selection {
+                    find.search_entry
+                        .set_text(&buffer.text(&start, &end, false));
+                }
+                if replace {
+                    replace_toggle.set_active(true);
+                }
+                find.bar.set_search_mode(true);
+                find.search_entry.grab_focus();
+                find.search_entry.select_region(0, -1);
+            });
+            actions.add_action(&action);
+        }
+        self.view.insert_action_group("find", Some(&actions));
+
+        let shortcuts = gtk::ShortcutController::new();
+        for (trigger, action) in [("<Control>f", "find.show"), ("<Control>h", "find.replace")] {
+            shortcuts.add_shortcut(gtk::Shortcut::new(
+                gtk::ShortcutTrigger::parse_string(trigger),
+                Some(gtk::NamedAction::new(action)),
+            ));
+        }
+        self.view.add_controller(shortcuts);
+    }
+
+    fn close(&self) {
+        self.bar.set_search_mode(false);
+    }
+
+    /// Shows which match is selected, or why the search doesn't work.
+    fn update_matches_label(&self) {
+        self.matches_label.remove_css_class("error");
+        if let Some(error) = self.context.regex_error() {
+            self.matches_label.add_css_class("error");
+            self.matches_label.set_text("Invalid pattern");
+            self.matches_label.set_tooltip_text(Some(error.message()));
+            return;
+        }
+        self.matches_label.set_tooltip_text(None);
+
+        let count = self.context.occurrences_count();
+        let text = if self.settings.search_text().is_none() {
+            String::new()
+        } else if count == 0 {
+            "No matches".to_string()
+        } else if count < 0 {
+            // Still scanning the buffer
+            String::new()
+        } else {
+            let position = self
+                .sync
+                .buffer()
+                .selection_bounds()
+                .map(|(start, end)| self.context.occurrence_position(&start, &end))
+                .unwrap_or_default();
+            if position > 0 {
+                format!("{position} of {count}")
+            } else {
+                format!("{count} matches")
+            }
+        };
+        self.matches_label.set_text(&text);
+    }
+
+    fn select(&self, start: &gtk::TextIter, end: &gtk::TextIter) {
+        let buffer = self.sync.buffer();
+        buffer.select_range(start, end);
+        self.view
+            .scroll_to_mark(&buffer.get_insert(), 0.1, false, 0.0, 0.0);
+    }
+
+    fn next(&self) {
+        let buffer = self.sync.buffer();
+        let from = match buffer.selection_bounds() {
+            Some((_, end)) => end,
+            None => buffer.iter_at_mark(&buffer.get_insert()),
+        };
+        if let Some((start, end, _)) = self.context.forward(&from) {
+            self.select(&start, &end);
+        }
+    }
+
+    fn previous(&self) {
+        let buffer = self.sync.buffer();
+        let from = match buffer.selection_bounds() {
+            Some((start, _)) => start,
+            None => buffer.iter_at_mark(&buffer.get_insert()),
+        };
+        if let Some((start, end, _)) = self.context.backward(&from) {
+            self.select(&start, &end);
+        }
+    }
+
+    /// Replaces the selected match and moves on to the next one.
+    fn replace(&self) {
+        if !self.view.is_editable() {
+            return;
+        }
+        let buffer = self.sync.buffer();
+        let selected_match = buffer
+            .selection_bounds()
+            .filter(|(start, end)| self.context.occurrence_position(start, end) > 0);
+        let Some((mut start, mut end)) = selected_match else {
+            self.next();
+            return;
+        };
+        if let Err(e) = self
+            .context
+            .replace(&mut start, &mut end, &self.replace_entry.text())
+        {
+            tracing::warn!(%e, "failed to replace match");
+            return;
+        }
+        buffer.place_cursor(&end);
+        self.next();
+    }
+
+    /// Replaces every match in a single change.
+    fn replace_all(&self) {
+        if !self.view.is_editable() {
+            return;
+        }
+        let Some(search) = self.settings.search_text() else {
+            return;
+        };
+        let buffer = self.sync.buffer();
+        let (start, end) = buffer.bounds();
+        let text = buffer.text(&start, &end, false);
+
+        let replaced = replace_matches(
+            &text,
+            &search,
+            &self.replace_entry.text(),
+            self.settings.is_case_sensitive(),
+            self.settings.is_regex_enabled(),
+        );
+        let replaced = match replaced {
+            Ok(Some(replaced)) => replaced,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!(%e, "failed to replace all matches");
+                return;
+            }
+        };
+
+        let result = self.sync.change_text("Replace all", |tx, text_obj_id| {
+            tx.update_text(text_obj_id, &replaced)
+        });
+        if let Err(e) = result {
+            tracing::warn!(%e, "failed to replace all matches");
+        }
+    }
+}
+
+/// Replaces every match of `search` in `text` the way sourceview replaces
+/// single matches: with `\0` to `\9` referring to groups in `replacement` if
+/// `regex` is set, and ignoring case unless `case_sensitive` is set. Returns
+/// `None` if nothing changed.
+fn replace_matches(
+    text: &str,
+    search: &str,
+    replacement: &str,
+    case_sensitive: bool,
+    regex: bool,
+) -> Result<Option<String>, glib::Error> {
+    let mut flags = glib::RegexCompileFlags::MULTILINE;
+    if !case_sensitive {
+        flags |= glib::RegexCompileFlags::CASELESS;
+    }
+    let pattern = if regex {
+        glib::GString::from(search)
+    } else {
+        glib::Regex::escape_string(search)
+    };
+    let Some(pattern) = glib::Regex::new(&pattern, flags, glib::RegexMatchFlags::empty())? else {
+        return Ok(None);
+    };
+    let replaced = if regex {
+        pattern.replace(text, 0, replacement, glib::RegexMatchFlags::empty())?
+    } else {
+        pattern.replace_literal(text, 0, replacement, glib::RegexMatchFlags::empty())?
+    };
+    Ok((replaced.as_str() != text).then(|| replaced.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_plain_text() {
+        assert_eq!(
+            replace_matches("a.b A.B a.b", "a.b", "x", false, false).unwrap(),
+            Some("x x x".to_string())
+        );
+        assert_eq!(
+            replace_matches("a.b A.B axb", "a.b", "\\0", true, false).unwrap(),
+            Some("\\0 A.B axb".to_string())
+        );
+    }
+
+    #[test]
+    fn replaces_regex_references() {
+        assert_eq!(
+            replace_matches("# One\n## Two", "^(#+) (\\w+)$", "\\2 \\1", true, true).unwrap(),
+            Some("One #\nTwo ##".to_string())
+        );
+        assert!(replace_matches("text", "(", "", true, true).is_err());
+    }
+
+    #[test]
+    fn returns_nothing_when_nothing_changes() {
+        assert_eq!(replace_matches("abc", "x", "y", true, false).unwrap(), None);
+        assert_eq!(replace_matches("abc", "b", "b", true, false).unwrap(), None);
+    }
+}
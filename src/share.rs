@@ -0,0 +1,193 @@
+//! Sharing the document with others, as a connection string and a QR code.
+//!
+//! A connection string is the command line for opening the document:
+//!
+//! ```text
+//! automerge:<document ID> <node ID> [--addr <ip:port>]... [--read-only]
+//! ```
+//!
+//! Direct addresses let peers on the same network connect without going
+//! through discovery and the relay. Read-only links open the editor without
+//! editing; that's a courtesy, automerge can't stop a peer from making changes.
+
+use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+
+use adw::prelude::*;
+use gtk::{gdk, glib};
+use iroh::Watcher;
+use samod::DocumentId;
+
+use crate::notifications::{Notification, Notifier};
+
+/// Blank modules around the code, as required for scanning.
+const QUIET_ZONE: usize = 4;
+/// Pixels per module, so the code stays sharp without scaling.
+const MODULE_SIZE: usize = 8;
+
+/// The peer to sync with and how to open the document, from a connection string.
+#[derive(Debug, Clone)]
+pub(crate) struct Invite {
+    pub(crate) node_id: iroh::NodeId,
+    pub(crate) direct_addresses: Vec<SocketAddr>,
+    pub(crate) read_only: bool,
+}
+
+impl Invite {
+    pub(crate) fn connection_string(&self, doc_id: &DocumentId) -> String {
+        let mut link = format!("automerge:{doc_id} {}", self.node_id);
+        for addr in &self.direct_addresses {
+            link.push_str(&format!(" --addr {addr}"));
+        }
+        if self.read_only {
+            link.push_str(" --read-only");
+        }
+        link
+    }
+}
+
+/// Renders `text` as a black on white QR code.
+fn qr_texture(text: &str) -> anyhow::Result<gdk::Texture> {
+    let code = qrcode::QrCode::new(text.as_bytes())?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let modules = QUIET_ZONE..QUIET_ZONE + width;
+    let size = (width + 2 * QUIET_ZONE) * MODULE_SIZE;
+    let pixels: Vec<u8> = (0..size * size)
+        .map(|i| {
+            let (x, y) = (i % size / MODULE_SIZE, i / size / MODULE_SIZE);
+            let dark = modules.contains(&x)
+                && modules.contains(&y)
+                && colors[(y - QUIET_ZONE) * width + x - QUIET_ZONE] == qrcode::Color::Dark;
+            if dark { 0x00 } else { 0xff }
+        })
+        .collect();
+    let texture = gdk::MemoryTexture::new(
+        size as i32,
+        size as i32,
+        gdk::MemoryFormat::G8,
+        &glib::Bytes::from_owned(pixels),
+        size,
+    );
+    Ok(texture.upcast())
+}
+
+/// Shows the connection string for the document, with a QR code to scan it
+/// from another device.
+pub(crate) fn present(
+    parent: &impl IsA<gtk::Widget>,
+    doc_id: &DocumentId,
+    endpoint: &iroh::Endpoint,
+    read_only: bool,
+    notifier: &Notifier,
+) {
+    let picture = gtk::Picture::new();
+    picture.set_size_request(240, 240);
+    picture.set_content_fit(gtk::ContentFit::Contain);
+    picture.set_alternative_text(Some("QR code of the connection string"));
+
+    let link_label = gtk::Label::new(None);
+    link_label.set_selectable(true);
+    link_label.set_wrap(true);
+    link_label.set_wrap_mode(gtk::pango::WrapMode::Char);
+    link_label.set_justify(gtk::Justification::Center);
+    link_label.add_css_class("monospace");
+
+    let read_only_row = adw::SwitchRow::builder()
+        .title("Read-Only")
+        .subtitle("Opens the editor without editing, as a courtesy rather than a lock")
+        .active(read_only)
+        .build();
+    let addresses_row = adw::SwitchRow::builder()
+        .title("Include Direct Addresses")
+        .subtitle("Connects on the same network without the relay")
+        .sensitive(false)
+        .build();
+    let options = adw::PreferencesGroup::new();
+    options.add(&read_only_row);
+    options.add(&addresses_row);
+
+    let copy_button = gtk::Button::with_label("Copy");
+    copy_button.set_halign(gtk::Align::Center);
+    copy_button.add_css_class("pill");
+    copy_button.add_css_class("suggested-action");
+
+    let content = gtk::Box::new(gtk::Orientation::Vertical, 18);
+    content.set_margin_top(12);
+    content.set_margin_bottom(24);
+    content.set_margin_start(24);
+    content.set_margin_end(24);
+    content.append(&picture);
+    content.append(&link_label);
+    content.append(&options);
+    content.append(&copy_button);
+
+    let toolbar_view = adw::ToolbarView::new();
+    toolbar_view.add_top_bar(&adw::HeaderBar::new());
+    toolbar_view.set_content(Some(&content));
+
+    let dialog = adw::Dialog::builder()
+        .title("Share Document")
+        .content_width(420)
+        .child(&toolbar_view)
+        .build();
+
+    // Direct addresses are only known once the endpoint has found them
+    let direct_addresses = Rc::new(RefCell::new(Vec::new()));
+    let update = {
+        let doc_id = doc_id.clone();
+        let node_id = endpoint.node_id();
+        let direct_addresses = direct_addresses.clone();
+        let read_only_row = read_only_row.clone();
+        let addresses_row = addresses_row.clone();
+        let picture = picture.clone();
+        let link_label = link_label.clone();
+        Rc::new(move || {
+            let invite = Invite {
+                node_id,
+                direct_addresses: if addresses_row.is_active() {
+                    direct_addresses.borrow().clone()
+                } else {
+                    Vec::new()
+                },
+                read_only: read_only_row.is_active(),
+            };
+            let link = invite.connection_string(&doc_id);
+            match qr_texture(&link) {
+                Ok(texture) => picture.set_paintable(Some(&texture)),
+                Err(e) => {
+                    tracing::warn!(%e, "failed to render QR code");
+                    picture.set_paintable(None::<&gdk::Paintable>);
+                }
+            }
+            link_label.set_text(&link);
+        })
+    };
+    update();
+    for row in [&read_only_row, &addresses_row] {
+        let update = update.clone();
+        row.connect_active_notify(move |_| update());
+    }
+
+    glib::spawn_future_local({
+        let endpoint = endpoint.clone();
+        let update = update.clone();
+        async move {
+            let node_addr = endpoint.node_addr().initialized().await;
+            *direct_addresses.borrow_mut() = node_addr.direct_addresses.into_iter().collect();
+            addresses_row.set_sensitive(!direct_addresses.borrow().is_empty());
+            update();
+        }
+    });
+
+    copy_button.connect_clicked({
+        let dialog = dialog.clone();
+        let notifier = notifier.clone();
+        move |button| {
+            button.clipboard().set_text(&link_label.text());
+            notifier.notify(Notification::LinkCopied);
+            dialog.close();
+        }
+    });
+
+    dialog.present(Some(parent));
+}
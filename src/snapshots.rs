@@ -0,0 +1,406 @@
+//! Named snapshots: bookmarks on a set of document heads.
+//!
+//! Snapshots live in the document under the `snapshots` map, keyed by a random
+//! ID, so every peer sees the same reference points:
+//!
+//! ```typescript
+//! type Snapshots = {
+//!   [id: string]: {
+//!     name: string // e.g. "v1 sent to client"
+//!     heads: string[] // Hex change hashes
+//!     createdAt: Date
+//!     createdBy: string // iroh node ID
+//!   }
+//! }
+//! ```
+
+use std::{cell::RefCell, rc::Rc, str::FromStr};
+
+use adw::prelude::*;
+use automerge::{
+    AutomergeError, ChangeHash, ObjType, ROOT, ReadDoc, ScalarValue, Value,
+    transaction::Transactable,
+};
+use gtk::gio;
+use sourceview5::prelude::*;
+
+use crate::error::DocumentError;
+use crate::fs::write_atomically;
+use crate::history::format_time;
+use crate::schema::{now_millis, read_string};
+use crate::sync::TextSynchronizer;
+
+pub(crate) const SNAPSHOTS_KEY: &str = "snapshots";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snapshot {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) heads: Vec<ChangeHash>,
+    /// Milliseconds since the unix epoch.
+    pub(crate) created_at: i64,
+    pub(crate) created_by: String,
+}
+
+impl Snapshot {
+    /// Reads the edited text as it was when the snapshot was taken.
+    pub(crate) fn read_text(&self, sync: &TextSynchronizer) -> Result<String, DocumentError> {
+        let path = sync.path();
+        sync.handle()
+            .with_document(|doc| path.read_at(doc, &self.heads))
+    }
+}
+
+/// Reads all snapshots, oldest first.
+///
+/// Snapshots with heads that can't be parsed are skipped.
+pub(crate) fn read_snapshots<R: ReadDoc>(doc: &R) -> Vec<Snapshot> {
+    let Ok(Some((Value::Object(ObjType::Map), snapshots_id))) = doc.get(ROOT, SNAPSHOTS_KEY) else {
+        return Vec::new();
+    };
+    let mut snapshots: Vec<Snapshot> = doc
+        .keys(&snapshots_id)
+        .filter_map(|id| {
+            let Ok(Some((Value::Object(ObjType::Map), snapshot_id))) = doc.get(&snapshots_id, &id)
+            else {
+                return None;
+            };
+            let Ok(Some((Value::Object(ObjType::List), heads_id))) = doc.get(&snapshot_id, "heads")
+            else {
+                return None;
+            };
+            let heads = (0..doc.length(&heads_id))
+                .map(|i| ChangeHash::from_str(&read_string(doc, &heads_id, i)?).ok())
+                .collect::<Option<Vec<_>>>()?;
+            let created_at = match doc.get(&snapshot_id, "createdAt") {
+                Ok(Some((value, _))) => value.to_i64().unwrap_or_default(),
+                _ => 0,
+            };
+            Some(Snapshot {
+                name: read_string(doc, &snapshot_id, "name").unwrap_or_default(),
+                heads,
+                created_at,
+                created_by: read_string(doc, &snapshot_id, "createdBy").unwrap_or_default(),
+                id,
+            })
+        })
+        .collect();
+    snapshots.sort_by_key(|snapshot| snapshot.created_at);
+    snapshots
+}
+
+pub(crate) fn create_snapshot<T: Transactable>(
+    tx: &mut T,
+    name: &str,
+    heads: &[ChangeHash],
+    author: &str,
+) -> Result<String, AutomergeError> {
+    // Created with the document, see `crate::schema`
+    let snapshots_id = match tx.get(ROOT, SNAPSHOTS_KEY)? {
+        Some((Value::Object(ObjType::Map), id)) => id,
+        _ => return Err(AutomergeError::InvalidObjId(SNAPSHOTS_KEY.to_string())),
+    };
+    let id = format!("{:016x}", rand::random::<u64>());
+    let snapshot_id = tx.put_object(&snapshots_id, &id, ObjType::Map)?;
+    tx.put(&snapshot_id, "name", name)?;
+    let heads_id = tx.put_object(&snapshot_id, "heads", ObjType::List)?;
+    for (i, head) in heads.iter().enumerate() {
+        tx.insert(&heads_id, i, head.to_string())?;
+    }
+    tx.put(
+        &snapshot_id,
+        "createdAt",
+        ScalarValue::Timestamp(now_millis()),
+    )?;
+    tx.put(&snapshot_id, "createdBy", author)?;
+    Ok(id)
+}
+
+pub(crate) fn delete_snapshot<T: Transactable>(tx: &mut T, id: &str) -> Result<(), AutomergeError> {
+    match tx.get(ROOT, SNAPSHOTS_KEY)? {
+        Some((Value::Object(ObjType::Map), snapshots_id)) => tx.delete(&snapshots_id, id),
+        _ => Ok(()),
+    }
+}
+
+/// The snapshots page of the side pane.
+#[derive(Clone)]
+pub(crate) struct SnapshotsPanel {
+    pub(crate) container: gtk::Box,
+    list: gtk::Box,
+    sync: TextSynchronizer,
+    author: String,
+    snapshots: Rc<RefCell<Vec<Snapshot>>>,
+}
+
+impl SnapshotsPanel {
+    pub(crate) fn new(sync: &TextSynchronizer, author: String) -> Self {
+        let container = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        container.set_margin_top(8);
+        container.set_margin_bottom(8);
+        container.set_margin_start(8);
+        container.set_margin_end(8);
+
+        let save_button = gtk::Button::with_label("Save Snapshot…");
+        save_button.set_tooltip_text(Some("Bookmark the current version under a name"));
+        save_button.set_sensitive(!sync.is_read_only());
+        container.append(&save_button);
+
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 8);
+        let scroll = gtk::ScrolledWindow::builder()
+            .hscrollbar_policy(gtk::PolicyType::Never)
+            .vexpand(true)
+            .child(&list)
+            .build();
+        container.append(&scroll);
+
+        let panel = Self {
+            container,
+            list,
+            sync: sync.clone(),
+            author,
+            snapshots: Rc::new(RefCell::new(Vec::new())),
+        };
+
+        save_button.connect_clicked({
+            let panel = panel.clone();
+            move |_| panel.prompt_new_snapshot()
+        });
+        sync.connect_reconciled({
+            let panel = panel.clone();
+            move || panel.refresh()
+        });
+        panel.refresh();
+        panel
+    }
+
+    fn window(&self) -> Option<gtk::Window> {
+        self.container.root().and_downcast::<gtk::Window>()
+    }
+
+    fn prompt_new_snapshot(&self) {
+        let entry = gtk::Entry::new();
+        entry.set_placeholder_text(Some("v1 sent to client"));
+        entry.set_activates_default(true);
+
+        let dialog = adw::AlertDialog::new(
+            Some("Save Snapshot"),
+            Some("Give the current version a name to come back to it later."),
+        );
+        dialog.add_responses(&[("cancel", "Cancel"), ("save", "Save")]);
+        dialog.set_response_appearance("save", adw::ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("save"));
+        dialog.set_close_response("cancel");
+        dialog.set_extra_child(Some(&entry));
+
+        let panel = self.clone();
+        dialog.connect_response(None, move |_, response| {
+            let name = entry.text();
+            if response != "save" || name.trim().is_empty() {
+                return;
+            }
+            // Snapshots include everything that was typed so far
+            panel.sync.flush();
+            let heads = panel.sync.view_heads();
+            panel.change_snapshots(&format!("Save snapshot \"{}\"", name.trim()), |tx| {
+                create_snapshot(tx, name.trim(), &heads, &panel.author).map(|_| ())
+            });
+        });
+        dialog.present(Some(&self.container));
+    }
+
+    /// Makes a change to the snapshots, which doesn't touch the text itself.
+    fn change_snapshots(
+        &self,
+        message: &str,
+        f: impl FnOnce(&mut automerge::transaction::Transaction<'_>) -> Result<(), AutomergeError>,
+    ) {
+        if let Err(e) = self.sync.change_document(message, f) {
+            tracing::warn!(%e, "failed to update snapshots");
+        }
+    }
+
+    pub(crate) fn refresh(&self) {
+        let snapshots = self.sync.handle().with_document(|doc| read_snapshots(doc));
+        if *self.snapshots.borrow() == snapshots {
+            return;
+        }
+
+        while let Some(child) = self.list.first_child() {
+            self.list.remove(&child);
+        }
+        if snapshots.is_empty() {
+            let placeholder = gtk::Label::new(Some("No Snapshots"));
+            placeholder.set_margin_top(16);
+            placeholder.add_css_class("dim-label");
+            self.list.append(&placeholder);
+        }
+        // Newest first
+        for snapshot in snapshots.iter().rev() {
+            self.list.append(&self.snapshot_card(snapshot));
+        }
+        *self.snapshots.borrow_mut() = snapshots;
+    }
+
+    fn snapshot_card(&self, snapshot: &Snapshot) -> gtk::Box {
+        let card = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        card.add_css_class("card");
+
+        let name = gtk::Label::new(Some(&snapshot.name));
+        name.set_halign(gtk::Align::Start);
+        name.set_wrap(true);
+        name.set_xalign(0.0);
+        name.add_css_class("heading");
+        card.append(&name);
+
+        let details = gtk::Label::new(Some(&format!(
+            "{} · {}...",
+            format_time(snapshot.created_at / 1000),
+            snapshot.created_by.chars().take(8).collect::<String>()
+        )));
+        details.set_halign(gtk::Align::Start);
+        details.add_css_class("caption");
+        details.add_css_class("dim-label");
+        card.append(&details);
+
+        let actions = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+        let open_button = gtk::Button::with_label("Open");
+        open_button.connect_clicked({
+            let panel = self.clone();
+            let snapshot = snapshot.clone();
+            move |_| panel.open(&snapshot)
+        });
+        actions.append(&open_button);
+
+        let export_button = gtk::Button::with_label("Export…");
+        export_button.connect_clicked({
+            let panel = self.clone();
+            let snapshot = snapshot.clone();
+            move |_| panel.export(&snapshot)
+        });
+        actions.append(&export_button);
+
+        let delete_button = gtk::Button::from_icon_name("user-trash-symbolic");
+        delete_button.set_tooltip_text(Some("Delete Snapshot"));
+        delete_button.set_hexpand(true);
+        delete_button.set_halign(gtk::Align::End);
+        delete_button.set_sensitive(!self.sync.is_read_only());
+        delete_button.connect_clicked({
+            let panel = self.clone();
+            let snapshot = snapshot.clone();
+            move |_| {
+                panel.change_snapshots(&format!("Delete snapshot \"{}\"", snapshot.name), |tx| {
+                    delete_snapshot(tx, &snapshot.id)
+                });
+            }
+        });
+        actions.append(&delete_button);
+
+        card.append(&actions);
+        card
+    }
+
+    /// Shows the text at the snapshot in a read-only window.
+    fn open(&self, snapshot: &Snapshot) {
+        let text = match snapshot.read_text(&self.sync) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!(%e, name = snapshot.name, "failed to read snapshot");
+                format!("This snapshot isn't available: {e}")
+            }
+        };
+
+        let window = gtk::Window::builder()
+            .title(format!("{} ({})", snapshot.name, self.sync.path()))
+            .default_width(700)
+            .default_height(500)
+            .build();
+        window.set_transient_for(self.window().as_ref());
+
+        let buffer = sourceview5::Buffer::new(None);
+        buffer.set_language(self.sync.buffer().language().as_ref());
+        buffer.set_style_scheme(self.sync.buffer().style_scheme().as_ref());
+        buffer.set_text(&text);
+        let view = sourceview5::View::with_buffer(&buffer);
+        view.set_editable(false);
+        view.set_monospace(true);
+        view.set_show_line_numbers(true);
+        view.set_wrap_mode(gtk::WrapMode::WordChar);
+
+        let scroll = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&view)
+            .build();
+        window.set_child(Some(&scroll));
+        window.present();
+    }
+
+    /// Writes the text at the snapshot to a file of the user's choice.
+    fn export(&self, snapshot: &Snapshot) {
+        let text = match snapshot.read_text(&self.sync) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::warn!(%e, name = snapshot.name, "failed to read snapshot");
+                return;
+            }
+        };
+
+        let dialog = gtk::FileDialog::builder()
+            .title("Export Snapshot")
+            .initial_name(format!("{}.md", snapshot.name))
+            .build();
+        dialog.save(
+            self.window().as_ref(),
+            gio::Cancellable::NONE,
+            move |result| {
+                let Some(path) = result.ok().and_then(|file| file.path()) else {
+                    return;
+                };
+                if let Err(e) = write_atomically(&path, &text) {
+                    tracing::error!(e = format!("{e:#}"), "failed to export snapshot");
+                }
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::Automerge;
+
+    use super::*;
+    use crate::schema;
+
+    #[test]
+    fn creates_reads_and_deletes_snapshots() {
+        let node_id = iroh::SecretKey::from_bytes(&[1; 32]).public();
+        let mut doc = Automerge::new();
+        doc.transact(|tx| schema::initialize(tx, "Notes", "# Notes", node_id))
+            .unwrap();
+        let heads = doc.get_heads();
+        let id = doc
+            .transact(|tx| create_snapshot(tx, "v1 sent to client", &heads, "alice"))
+            .unwrap()
+            .result;
+
+        let snapshots = read_snapshots(&doc);
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, id);
+        assert_eq!(snapshots[0].name, "v1 sent to client");
+        assert_eq!(snapshots[0].heads, heads);
+        assert_eq!(snapshots[0].created_by, "alice");
+
+        doc.transact(|tx| delete_snapshot(tx, &id)).unwrap();
+        assert!(read_snapshots(&doc).is_empty());
+    }
+
+    #[test]
+    fn doesnt_create_the_snapshots_map() {
+        let mut doc = Automerge::new();
+        let result = doc.transact(|tx| create_snapshot(tx, "v1", &[], "alice"));
+        assert!(result.is_err());
+        assert!(doc.get(ROOT, SNAPSHOTS_KEY).unwrap().is_none());
+    }
+}
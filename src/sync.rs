@@ -1,72 +1,373 @@
-use std::sync::{
-    Arc, Mutex,
-    atomic::{AtomicBool, Ordering},
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use automerge::{
-    ChangeHash, PatchLog, ROOT, ReadDoc, TextEncoding, patches::TextRepresentation,
-    transaction::Transactable,
+    Automerge, AutomergeError, ChangeHash, ObjId, PatchLog, ReadDoc, ScalarValue, TextEncoding,
+    marks::Mark,
+    patches::TextRepresentation,
+    transaction::{Transactable, Transaction},
 };
 use futures::StreamExt;
 use glib::spawn_future_local;
 use gtk::prelude::{TextBufferExt, TextBufferExtManual};
 use sourceview5::Buffer;
 
+use crate::error::DocumentError;
+use crate::marks;
+use crate::schema::commit_options;
+use crate::text_path::TextPath;
+
+/// How long the buffer has to be idle before pending local edits get committed.
+const IDLE_COMMIT_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound for how long local edits are held back while typing continuously.
+const MAX_COMMIT_DELAY: Duration = Duration::from_secs(3);
+
+/// A local edit that hasn't been committed to the document yet.
+struct Splice {
+    index: usize,
+    delete: usize,
+    text: String,
+}
+
+/// Local edits are collected here and committed as a single change once the
+/// user stops typing, instead of creating one change per keystroke.
+///
+/// The splice indices are relative to the buffer contents, which is the
+/// document at `view_heads` with all previous pending splices applied.
+#[derive(Default)]
+struct PendingEdits {
+    splices: Vec<Splice>,
+    first_edit: Option<Instant>,
+    commit_timer: Option<glib::SourceId>,
+}
+
+/// Applies local edits to the text at `path` as it was at `heads`, all in one
+/// change. Returns the hash of that change.
+fn commit_splices(
+    doc: &mut Automerge,
+    path: &TextPath,
+    heads: &[ChangeHash],
+    splices: &[Splice],
+) -> Result<Option<ChangeHash>, DocumentError> {
+    let message = match splices.len() {
+        1 => "Edit text".to_string(),
+        n => format!("Edit text ({n} edits)"),
+    };
+
+    // Dropping the transaction on error rolls it back
+    let mut tx = doc.transaction_at(
+        PatchLog::inactive(TextRepresentation::String(TextEncoding::GraphemeCluster)),
+        heads,
+    );
+    let text_obj_id = path.resolve(&tx)?;
+    for splice in splices {
+        tx.splice_text(
+            &text_obj_id,
+            splice.index,
+            splice.delete as isize,
+            &splice.text,
+        )?;
+    }
+    let (new_head, _) = tx.commit_with(commit_options(message));
+    Ok(new_head)
+}
+
 #[derive(Clone)]
 pub(crate) struct TextSynchronizer {
     handle: samod::DocHandle,
     editor_buffer: Buffer,
+    path: Rc<RefCell<TextPath>>,
     reconciling: Arc<AtomicBool>,
     view_heads: Arc<Mutex<Vec<ChangeHash>>>,
+    pending: Rc<RefCell<PendingEdits>>,
+    failed: Rc<Cell<bool>>,
+    /// Set for read-only links, which must not change the document at all.
+    read_only: Rc<Cell<bool>>,
+    marks_stale: Rc<Cell<bool>>,
+    error_handlers: Rc<RefCell<Vec<Box<dyn Fn(&DocumentError)>>>>,
+    reconciled_handlers: Rc<RefCell<Vec<Box<dyn Fn()>>>>,
 }
 
 impl TextSynchronizer {
-    pub(crate) fn new(handle: samod::DocHandle, buffer: Buffer) -> Self {
+    pub(crate) fn new(handle: samod::DocHandle, buffer: Buffer, path: TextPath) -> Self {
         let view_heads = handle.with_document(|doc| doc.get_heads());
         Self {
             handle,
             editor_buffer: buffer,
+            path: Rc::new(RefCell::new(path)),
             reconciling: Arc::new(AtomicBool::new(false)),
             view_heads: Arc::new(Mutex::new(view_heads)),
+            pending: Rc::new(RefCell::new(PendingEdits::default())),
+            failed: Rc::new(Cell::new(false)),
+            read_only: Rc::new(Cell::new(false)),
+            marks_stale: Rc::new(Cell::new(true)),
+            error_handlers: Rc::new(RefCell::new(Vec::new())),
+            reconciled_handlers: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Registers a callback that gets invoked when synchronization had to stop
+    /// because the document no longer has the expected structure.
+    pub(crate) fn connect_error(&self, handler: impl Fn(&DocumentError) + 'static) {
+        self.error_handlers.borrow_mut().push(Box::new(handler));
+    }
+
+    /// Registers a callback that gets invoked whenever the buffer caught up
+    /// with the document, so positions in the document match the buffer.
+    pub(crate) fn connect_reconciled(&self, handler: impl Fn() + 'static) {
+        self.reconciled_handlers
+            .borrow_mut()
+            .push(Box::new(handler));
+    }
+
+    pub(crate) fn handle(&self) -> &samod::DocHandle {
+        &self.handle
+    }
+
+    pub(crate) fn buffer(&self) -> &Buffer {
+        &self.editor_buffer
+    }
+
+    pub(crate) fn path(&self) -> TextPath {
+        self.path.borrow().clone()
+    }
+
+    /// Makes [`Self::change_text`] and [`Self::change_document`] fail with
+    /// [`DocumentError::ReadOnly`], for read-only links. The editor has to be
+    /// made non-editable separately.
+    pub(crate) fn set_read_only(&self, read_only: bool) {
+        self.read_only.set(read_only);
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only.get()
+    }
+
+    /// The heads of the document version the buffer currently shows, not
+    /// counting local edits that are still pending.
+    pub(crate) fn view_heads(&self) -> Vec<ChangeHash> {
+        self.view_heads.lock().unwrap().clone()
+    }
+
+    /// Points the editor at a different text object of the same document.
+    ///
+    /// Pending edits to the previous text object are committed first. This
+    /// also resumes synchronization if it was stopped due to an error.
+    pub(crate) fn switch_to(&self, path: TextPath) -> Result<(), DocumentError> {
+        self.flush();
+        // Whatever couldn't be committed belongs to the previous text object
+        let stale = std::mem::take(&mut *self.pending.borrow_mut());
+        if let Some(timer) = stale.commit_timer {
+            timer.remove();
+        }
+
+        let (text, heads) = self
+            .handle
+            .with_document(|doc| Ok::<_, DocumentError>((path.read(doc)?, doc.get_heads())))?;
+
+        self.reconciling.store(true, Ordering::Release);
+        self.editor_buffer.set_text(&text);
+        self.reconciling.store(false, Ordering::Release);
+
+        *self.view_heads.lock().unwrap() = heads;
+        *self.path.borrow_mut() = path;
+        self.failed.set(false);
+        self.marks_stale.set(true);
+        self.reconcile();
+        Ok(())
+    }
+
+    /// Makes a change to the edited text object that isn't a plain keystroke,
+    /// like formatting. Positions passed to `f` are buffer offsets.
+    ///
+    /// The change is applied to the buffer via the regular patch path, so it
+    /// interleaves correctly with concurrent remote edits.
+    pub(crate) fn change_text<F, E>(&self, message: &str, f: F) -> Result<(), DocumentError>
+    where
+        F: FnOnce(&mut Transaction<'_>, &ObjId) -> Result<(), E>,
+        DocumentError: From<E>,
+    {
+        if self.read_only.get() {
+            return Err(DocumentError::ReadOnly);
+        }
+        if self.failed.get() {
+            return Ok(());
+        }
+        {
+            let mut view_heads = self.view_heads.lock().unwrap();
+            self.commit_pending(&mut view_heads)?;
+            self.handle.with_document(|doc| {
+                let mut tx = doc.transaction_at(
+                    PatchLog::inactive(TextRepresentation::String(TextEncoding::GraphemeCluster)),
+                    view_heads.as_slice(),
+                );
+                let text_obj_id = self.path.borrow().resolve(&tx)?;
+                f(&mut tx, &text_obj_id)?;
+                tx.commit_with(commit_options(message));
+                Ok::<_, DocumentError>(())
+            })?;
+        }
+        self.reconcile();
+        Ok(())
+    }
+
+    /// Makes a change to the document outside the edited text, like to the
+    /// comments or snapshots. Pending edits are committed first, so the
+    /// history shows both in the order they were made.
+    pub(crate) fn change_document<F>(&self, message: &str, f: F) -> Result<(), DocumentError>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<(), AutomergeError>,
+    {
+        if self.read_only.get() {
+            return Err(DocumentError::ReadOnly);
+        }
+        if !self.failed.get() {
+            self.commit_pending(&mut self.view_heads.lock().unwrap())?;
+        }
+        self.handle
+            .with_document(|doc| {
+                doc.transact_with::<_, _, AutomergeError, _>(|_| commit_options(message), f)
+            })
+            .map_err(|failure| failure.error)?;
+        Ok(())
+    }
+
+    pub(crate) fn fail(&self, error: DocumentError) {
+        tracing::error!(%error, "stopping text synchronization");
+        self.failed.set(true);
+        for handler in self.error_handlers.borrow().iter() {
+            handler(&error);
         }
     }
 
     fn handle_splice(&self, insert: usize, delete: usize, text: &str) {
-        if self.reconciling.load(std::sync::atomic::Ordering::Acquire) {
+        if self.reconciling.load(std::sync::atomic::Ordering::Acquire) || self.failed.get() {
             return;
         }
-        let mut view_heads = self.view_heads.lock().unwrap();
-        self.handle.with_document(|doc| {
-            let mut tx = doc.transaction_at(
-                PatchLog::inactive(TextRepresentation::String(TextEncoding::GraphemeCluster)),
-                &view_heads.as_ref(),
-            );
-            let (_, text_obj_id) = tx
-                .get(ROOT, "content")
-                .expect("failed to get content object")
-                .expect("no content key found");
-            tx.splice_text(text_obj_id, insert, delete as isize, text)
-                .unwrap();
-            let (new_head, _) = tx.commit();
-            if let Some(new_head) = new_head {
-                *view_heads = vec![new_head];
-            }
+        let mut pending = self.pending.borrow_mut();
+        pending.splices.push(Splice {
+            index: insert,
+            delete,
+            text: text.to_string(),
         });
-        drop(view_heads);
+
+        // Restart the idle timer, but never wait past the deadline of the first pending edit
+        let deadline = *pending.first_edit.get_or_insert_with(Instant::now) + MAX_COMMIT_DELAY;
+        let delay = IDLE_COMMIT_DELAY.min(deadline.saturating_duration_since(Instant::now()));
+        if let Some(timer) = pending.commit_timer.take() {
+            timer.remove();
+        }
+        let this = self.clone();
+        pending.commit_timer = Some(glib::timeout_add_local_once(delay, move || {
+            this.pending.borrow_mut().commit_timer = None;
+            this.reconcile();
+        }));
+    }
+
+    /// Adds the mark `name` to the buffer range from `start` to `end`, or
+    /// removes it if `value` is `None`.
+    pub(crate) fn set_mark(
+        &self,
+        name: &str,
+        value: Option<ScalarValue>,
+        start: usize,
+        end: usize,
+    ) -> Result<(), DocumentError> {
+        let expand = marks::expand_for(name);
+        match value {
+            Some(value) => self.change_text(&format!("Add {name}"), |tx, text_obj_id| {
+                tx.mark(
+                    text_obj_id,
+                    Mark::new(name.to_string(), value, start, end),
+                    expand,
+                )
+            }),
+            None => self.change_text(&format!("Remove {name}"), |tx, text_obj_id| {
+                tx.unmark(text_obj_id, name, start, end, expand)
+            }),
+        }
+    }
+
+    /// Toggles a boolean mark like bold on the current selection.
+    pub(crate) fn toggle_mark(&self, name: &str) -> Result<(), DocumentError> {
+        let Some((start, end)) = self.editor_buffer.selection_bounds() else {
+            return Ok(());
+        };
+        let (start, end) = (start.offset(), end.offset());
+        let value = if marks::range_has_mark(&self.editor_buffer, name, start, end) {
+            None
+        } else {
+            Some(ScalarValue::Boolean(true))
+        };
+        self.set_mark(name, value, start as usize, end as usize)
+    }
+
+    /// Commits all pending local edits as one change on top of `view_heads`.
+    fn commit_pending(&self, view_heads: &mut Vec<ChangeHash>) -> Result<(), DocumentError> {
+        // Text typed at the edge of a mark may have picked it up in the document
+        self.marks_stale.set(true);
+
+        let splices = {
+            let mut pending = self.pending.borrow_mut();
+            if let Some(timer) = pending.commit_timer.take() {
+                timer.remove();
+            }
+            pending.first_edit = None;
+            std::mem::take(&mut pending.splices)
+        };
+        if splices.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.path.borrow();
+        let new_head = self
+            .handle
+            .with_document(|doc| commit_splices(doc, &path, view_heads, &splices))?;
+        if let Some(new_head) = new_head {
+            *view_heads = vec![new_head];
+        }
+        Ok(())
+    }
+
+    /// Commits any pending local edits right away, e.g. before the window closes.
+    pub(crate) fn flush(&self) {
         self.reconcile();
     }
 
     fn reconcile(&self) {
+        if self.failed.get() {
+            return;
+        }
         self.reconciling.store(true, Ordering::Release);
+        let result = self.apply_changes();
+        self.reconciling.store(false, Ordering::Release);
+        match result {
+            Ok(()) => {
+                for handler in self.reconciled_handlers.borrow().iter() {
+                    handler();
+                }
+            }
+            Err(error) => self.fail(error),
+        }
+    }
 
+    fn apply_changes(&self) -> Result<(), DocumentError> {
         let mut view_heads = self.view_heads.lock().unwrap();
 
-        let text_obj_id = self.handle.with_document(|doc| {
-            doc.get(ROOT, "content")
-                .expect("failed to get content object")
-                .expect("no content key found")
-                .1
-        });
+        // Local edits have to land in the document before remote patches get applied
+        // to the buffer, otherwise the patch indices wouldn't line up with the buffer
+        self.commit_pending(&mut view_heads)?;
+
+        let text_obj_id = self
+            .handle
+            .with_document(|doc| self.path.borrow().resolve(doc))?;
 
         let (diff, new_heads) = self.handle.with_document(|doc| {
             let heads = doc.get_heads();
@@ -87,8 +388,11 @@ impl TextSynchronizer {
                 automerge::PatchAction::SpliceText {
                     index,
                     value,
-                    marks: _,
+                    marks,
                 } => {
+                    if marks.is_some_and(|marks| !marks.is_empty()) {
+                        self.marks_stale.set(true);
+                    }
                     let index = index + index_adjustment;
                     let mut pos = self.editor_buffer.iter_at_offset(index as i32);
                     let as_text = value.make_string();
@@ -101,11 +405,25 @@ impl TextSynchronizer {
                     let mut end = self.editor_buffer.iter_at_offset((index + length) as i32);
                     self.editor_buffer.delete(&mut start, &mut end);
                 }
+                automerge::PatchAction::Mark { .. } => self.marks_stale.set(true),
                 _ => {}
             }
         }
         *view_heads = new_heads;
-        self.reconciling.store(false, Ordering::Release);
+
+        if self.marks_stale.replace(false) {
+            let marks = self.handle.with_document(|doc| {
+                doc.marks_at(&text_obj_id, view_heads.as_slice())
+                    .map(|marks| {
+                        marks
+                            .into_iter()
+                            .map(|mark| mark.into_owned())
+                            .collect::<Vec<_>>()
+                    })
+            })?;
+            marks::apply(&self.editor_buffer, &marks);
+        }
+        Ok(())
     }
 
     pub(crate) fn start(&self) {
@@ -130,6 +448,9 @@ impl TextSynchronizer {
                 });
         }
 
+        // Show the marks that are already in the document
+        self.reconcile();
+
         // Now, whenever the document changes, update the text buffer
         {
             let this = self.clone();
@@ -142,3 +463,103 @@ impl TextSynchronizer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use automerge::{ROOT, transaction::Transactable};
+
+    use super::*;
+
+    fn splice(index: usize, delete: usize, text: &str) -> Splice {
+        Splice {
+            index,
+            delete,
+            text: text.to_string(),
+        }
+    }
+
+    fn document(text: &str) -> (Automerge, TextPath) {
+        let mut doc = Automerge::new();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let text_id = tx.put_object(ROOT, "content", automerge::ObjType::Text)?;
+            tx.splice_text(&text_id, 0, 0, text)?;
+            Ok(())
+        })
+        .unwrap();
+        (doc, "content".parse().unwrap())
+    }
+
+    #[test]
+    fn coalesces_splices_into_one_change() {
+        let (mut doc, path) = document("Hello");
+        let heads = doc.get_heads();
+        // Each splice is relative to the text after the previous ones
+        let splices = [
+            splice(5, 0, " world"),
+            splice(0, 1, "J"),
+            splice(11, 0, "!"),
+        ];
+
+        let new_head = commit_splices(&mut doc, &path, &heads, &splices)
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.get_heads(), vec![new_head]);
+        assert_eq!(doc.get_changes(&heads).len(), 1);
+        let change = doc.get_change_by_hash(&new_head).unwrap();
+        assert_eq!(
+            change.message().map(String::as_str),
+            Some("Edit text (3 edits)")
+        );
+        assert_eq!(path.read(&doc).unwrap(), "Jello world!");
+    }
+
+    #[test]
+    fn names_single_edits() {
+        let (mut doc, path) = document("Hello");
+        let heads = doc.get_heads();
+        let new_head = commit_splices(&mut doc, &path, &heads, &[splice(0, 5, "Bye")])
+            .unwrap()
+            .unwrap();
+        let change = doc.get_change_by_hash(&new_head).unwrap();
+        assert_eq!(change.message().map(String::as_str), Some("Edit text"));
+        assert_eq!(path.read(&doc).unwrap(), "Bye");
+    }
+
+    #[test]
+    fn applies_splices_on_top_of_older_heads() {
+        let (mut doc, path) = document("Hello");
+        let view_heads = doc.get_heads();
+
+        // A peer's change arrives while the local edits are still pending
+        let text_id = path.resolve(&doc).unwrap();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            tx.splice_text(&text_id, 0, 0, "> ")?;
+            Ok(())
+        })
+        .unwrap();
+        let remote_heads = doc.get_heads();
+
+        // The indices refer to the text the user saw, without the peer's change
+        let new_head = commit_splices(&mut doc, &path, &view_heads, &[splice(5, 0, "!")])
+            .unwrap()
+            .unwrap();
+        let change = doc.get_change_by_hash(&new_head).unwrap();
+        assert_eq!(change.deps(), view_heads.as_slice());
+        let mut heads = doc.get_heads();
+        heads.sort();
+        let mut expected = vec![remote_heads[0], new_head];
+        expected.sort();
+        assert_eq!(heads, expected);
+        assert_eq!(path.read(&doc).unwrap(), "> Hello!");
+    }
+
+    #[test]
+    fn rolls_back_failed_edits() {
+        let (mut doc, path) = document("Hello");
+        let heads = doc.get_heads();
+        let splices = [splice(0, 0, "Oh, "), splice(100, 0, "!")];
+        assert!(commit_splices(&mut doc, &path, &heads, &splices).is_err());
+        assert_eq!(doc.get_heads(), heads);
+        assert_eq!(path.read(&doc).unwrap(), "Hello");
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    fmt::{self, Write as _},
+    str::FromStr,
+};
+
+use automerge::{ChangeHash, ObjId, ObjType, Prop, ROOT, ReadDoc, Value};
+
+use crate::error::DocumentError;
+
+/// The key of the text object in the document root that gets edited by default.
+pub(crate) const CONTENT_KEY: &str = "content";
+
+/// The location of a text object inside a document, e.g. `content` or `notes[3].body`.
+///
+/// Keys escape `.`, `[` and `\` with a backslash, so `a\.b` is the single key `a.b`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TextPath(Vec<Prop>);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum TextPathParseError {
+    #[error("path is empty")]
+    Empty,
+    #[error("empty key at position {0}")]
+    EmptyKey(usize),
+    #[error("unclosed '[' at position {0}")]
+    UnclosedIndex(usize),
+    #[error("invalid list index '{0}'")]
+    InvalidIndex(String),
+    #[error("path starts with a list index, but the document root is a map")]
+    StartsWithIndex,
+    #[error("'\\' at the end of the path doesn't escape anything")]
+    DanglingEscape,
+}
+
+impl Default for TextPath {
+    fn default() -> Self {
+        Self(vec![Prop::Map(CONTENT_KEY.to_string())])
+    }
+}
+
+impl TextPath {
+    /// Walks the path from the document root and returns the text object at its end.
+    pub(crate) fn resolve<R: ReadDoc>(&self, doc: &R) -> Result<ObjId, DocumentError> {
+        self.resolve_inner(doc, None)
+    }
+
+    /// Like [`TextPath::resolve`], but walks the document as it was at `heads`.
+    pub(crate) fn resolve_at<R: ReadDoc>(
+        &self,
+        doc: &R,
+        heads: &[ChangeHash],
+    ) -> Result<ObjId, DocumentError> {
+        self.resolve_inner(doc, Some(heads))
+    }
+
+    fn resolve_inner<R: ReadDoc>(
+        &self,
+        doc: &R,
+        heads: Option<&[ChangeHash]>,
+    ) -> Result<ObjId, DocumentError> {
+        let mut obj = ROOT;
+        for (i, prop) in self.0.iter().enumerate() {
+            let is_last = i == self.0.len() - 1;
+            let value = match heads {
+                Some(heads) => doc.get_at(&obj, prop.clone(), heads)?,
+                None => doc.get(&obj, prop.clone())?,
+            };
+            match value {
+                Some((Value::Object(ObjType::Text), id)) if is_last => return Ok(id),
+                Some((value, _)) if is_last => {
+                    return Err(DocumentError::not_text(self.to_string(), &value));
+                }
+                Some((Value::Object(_), id)) => obj = id,
+                Some(_) | None => {
+                    return Err(DocumentError::MissingText {
+                        path: self.to_string(),
+                    });
+                }
+            }
+        }
+        Err(DocumentError::MissingText {
+            path: self.to_string(),
+        })
+    }
+
+    /// Reads the text object at this path as a string.
+    pub(crate) fn read<R: ReadDoc>(&self, doc: &R) -> Result<String, DocumentError> {
+        let text_obj_id = self.resolve(doc)?;
+        Ok(doc.text(text_obj_id)?)
+    }
+
+    /// Reads the text object at this path as it was at `heads`.
+    pub(crate) fn read_at<R: ReadDoc>(
+        &self,
+        doc: &R,
+        heads: &[ChangeHash],
+    ) -> Result<String, DocumentError> {
+        let text_obj_id = self.resolve_at(doc, heads)?;
+        Ok(doc.text_at(text_obj_id, heads)?)
+    }
+
+    /// Lists the paths of all text objects in the document, in document order.
+    pub(crate) fn find_all<R: ReadDoc>(doc: &R) -> Vec<TextPath> {
+        let mut found = Vec::new();
+        collect_text_paths(doc, &ROOT, ObjType::Map, &mut Vec::new(), &mut found);
+        found
+    }
+}
+
+fn collect_text_paths<R: ReadDoc>(
+    doc: &R,
+    obj: &ObjId,
+    obj_type: ObjType,
+    path: &mut Vec<Prop>,
+    found: &mut Vec<TextPath>,
+) {
+    let props: Vec<Prop> = match obj_type {
+        ObjType::Map | ObjType::Table => doc.keys(obj).map(Prop::Map).collect(),
+        ObjType::List => (0..doc.length(obj)).map(Prop::Seq).collect(),
+        ObjType::Text => return,
+    };
+    for prop in props {
+        let Ok(Some((Value::Object(child_type), child))) = doc.get(obj, prop.clone()) else {
+            continue;
+        };
+        path.push(prop);
+        if child_type == ObjType::Text {
+            found.push(TextPath(path.clone()));
+        } else {
+            collect_text_paths(doc, &child, child_type, path, found);
+        }
+        path.pop();
+    }
+}
+
+impl fmt::Display for TextPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, prop) in self.0.iter().enumerate() {
+            match prop {
+                Prop::Map(key) => {
+                    if i > 0 {
+                        f.write_char('.')?;
+                    }
+                    for c in key.chars() {
+                        if matches!(c, '.' | '[' | '\\') {
+                            f.write_char('\\')?;
+                        }
+                        f.write_char(c)?;
+                    }
+                }
+                Prop::Seq(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TextPath {
+    type Err = TextPathParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut props = Vec::new();
+        let mut rest = s.trim();
+        let mut position = 0;
+        let mut expect_key = true;
+
+        while !rest.is_empty() {
+            if let Some(after_bracket) = rest.strip_prefix('[') {
+                if props.is_empty() {
+                    return Err(TextPathParseError::StartsWithIndex);
+                }
+                let Some(end) = after_bracket.find(']') else {
+                    return Err(TextPathParseError::UnclosedIndex(position));
+                };
+                let index = &after_bracket[..end];
+                let index = index
+                    .parse()
+                    .map_err(|_| TextPathParseError::InvalidIndex(index.to_string()))?;
+                props.push(Prop::Seq(index));
+                position += end + 2;
+                rest = &after_bracket[end + 1..];
+                expect_key = false;
+                continue;
+            }
+
+            if !expect_key {
+                let Some(after_dot) = rest.strip_prefix('.') else {
+                    return Err(TextPathParseError::EmptyKey(position));
+                };
+                position += 1;
+                rest = after_dot;
+            }
+
+            let mut key = String::new();
+            let mut end = rest.len();
+            let mut chars = rest.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '.' | '[' => {
+                        end = i;
+                        break;
+                    }
+                    '\\' => match chars.next() {
+                        Some((_, escaped)) => key.push(escaped),
+                        None => return Err(TextPathParseError::DanglingEscape),
+                    },
+                    c => key.push(c),
+                }
+            }
+            if key.is_empty() {
+                return Err(TextPathParseError::EmptyKey(position));
+            }
+            props.push(Prop::Map(key));
+            position += end;
+            rest = &rest[end..];
+            expect_key = false;
+        }
+
+        if props.is_empty() {
+            return Err(TextPathParseError::Empty);
+        }
+        if expect_key {
+            return Err(TextPathParseError::EmptyKey(position));
+        }
+        Ok(Self(props))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{Automerge, AutomergeError, transaction::Transactable};
+
+    use super::*;
+
+    fn parse(path: &str) -> Result<TextPath, TextPathParseError> {
+        path.parse()
+    }
+
+    #[test]
+    fn parses_keys_and_indices() {
+        assert_eq!(
+            parse("content").unwrap(),
+            TextPath(vec![Prop::Map("content".to_string())])
+        );
+        assert_eq!(
+            parse(" notes[3].body ").unwrap(),
+            TextPath(vec![
+                Prop::Map("notes".to_string()),
+                Prop::Seq(3),
+                Prop::Map("body".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse("grid[0][1]").unwrap(),
+            TextPath(vec![
+                Prop::Map("grid".to_string()),
+                Prop::Seq(0),
+                Prop::Seq(1),
+            ])
+        );
+        assert_eq!(
+            parse(r"v1\.2.notes\[draft\]").unwrap(),
+            TextPath(vec![
+                Prop::Map("v1.2".to_string()),
+                Prop::Map("notes[draft]".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn displays_as_parsed() {
+        for path in [
+            "content",
+            "notes[3].body",
+            "a.b.c",
+            "grid[0][1]",
+            r"a\.b\[0]\\",
+        ] {
+            assert_eq!(parse(path).unwrap().to_string(), path);
+        }
+    }
+
+    #[test]
+    fn round_trips_keys_with_special_characters() {
+        let path = TextPath(vec![
+            Prop::Map("v1.2".to_string()),
+            Prop::Seq(0),
+            Prop::Map("notes[draft]".to_string()),
+            Prop::Map(r"C:\notes".to_string()),
+        ]);
+        assert_eq!(path.to_string(), r"v1\.2[0].notes\[draft].C:\\notes");
+        assert_eq!(parse(&path.to_string()).unwrap(), path);
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert!(matches!(parse(""), Err(TextPathParseError::Empty)));
+        assert!(matches!(parse("   "), Err(TextPathParseError::Empty)));
+        assert!(matches!(
+            parse(".content"),
+            Err(TextPathParseError::EmptyKey(0))
+        ));
+        assert!(matches!(
+            parse("notes."),
+            Err(TextPathParseError::EmptyKey(6))
+        ));
+        assert!(matches!(
+            parse("notes..body"),
+            Err(TextPathParseError::EmptyKey(6))
+        ));
+        assert!(matches!(
+            parse("notes[3]body"),
+            Err(TextPathParseError::EmptyKey(8))
+        ));
+        assert!(matches!(
+            parse("notes[3"),
+            Err(TextPathParseError::UnclosedIndex(5))
+        ));
+        assert!(matches!(
+            parse("notes[x]"),
+            Err(TextPathParseError::InvalidIndex(index)) if index == "x"
+        ));
+        assert!(matches!(
+            parse("notes[-1]"),
+            Err(TextPathParseError::InvalidIndex(_))
+        ));
+        assert!(matches!(
+            parse("[0].body"),
+            Err(TextPathParseError::StartsWithIndex)
+        ));
+        assert!(matches!(
+            parse(r"notes\"),
+            Err(TextPathParseError::DanglingEscape)
+        ));
+    }
+
+    #[test]
+    fn resolves_nested_text() {
+        let mut doc = Automerge::new();
+        doc.transact::<_, _, AutomergeError>(|tx| {
+            let notes = tx.put_object(ROOT, "notes", ObjType::List)?;
+            let note = tx.insert_object(&notes, 0, ObjType::Map)?;
+            let body = tx.put_object(&note, "body", ObjType::Text)?;
+            tx.splice_text(&body, 0, 0, "Hello")?;
+            tx.put(&note, "title", "Not text")?;
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(parse("notes[0].body").unwrap().read(&doc).unwrap(), "Hello");
+        assert!(matches!(
+            parse("notes[0].title").unwrap().read(&doc),
+            Err(DocumentError::NotText { .. })
+        ));
+        assert!(matches!(
+            parse("notes[1].body").unwrap().read(&doc),
+            Err(DocumentError::MissingText { .. })
+        ));
+        assert_eq!(
+            TextPath::find_all(&doc),
+            vec![parse("notes[0].body").unwrap()]
+        );
+    }
+}